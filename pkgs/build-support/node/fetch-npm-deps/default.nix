@@ -1,4 +1,4 @@
-{ lib, stdenvNoCC, rustPlatform, makeWrapper, pkg-config, curl, gnutar, gzip, nix, testers, fetchurl, cacert, prefetch-npm-deps, fetchNpmDeps }:
+{ lib, stdenvNoCC, rustPlatform, makeWrapper, pkg-config, curl, gnutar, git, gzip, nghttp2, openssl, testers, fetchurl, cacert, prefetch-npm-deps, fetchNpmDeps }:
 
 {
   prefetch-npm-deps = rustPlatform.buildRustPackage {
@@ -18,9 +18,12 @@
 
     nativeBuildInputs = [ makeWrapper pkg-config ];
     buildInputs = [ curl ];
+    nativeCheckInputs = [ git nghttp2 openssl ];
+    # Runs the HTTP/2 test too, which needs nghttpd and a curl with HTTP/2 support.
+    checkFlags = [ "--include-ignored" ];
 
     postInstall = ''
-      wrapProgram "$out/bin/prefetch-npm-deps" --prefix PATH : ${lib.makeBinPath [ gnutar gzip nix ]}
+      wrapProgram "$out/bin/prefetch-npm-deps" --prefix PATH : ${lib.makeBinPath [ git gnutar gzip openssl ]}
     '';
 
     passthru.tests =
@@ -1,17 +1,25 @@
+use anyhow::anyhow;
 use base64::prelude::{Engine, BASE64_STANDARD};
 use digest::{Digest, Update};
 use serde::{Deserialize, Serialize};
 use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 use std::{
+    collections::HashSet,
     fmt::Write as FmtWrite,
     fs::{self, File},
-    io::Write,
-    path::PathBuf,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    time::SystemTime,
 };
+use tempfile::NamedTempFile;
 use url::Url;
+use walkdir::WalkDir;
 
 #[derive(Serialize, Deserialize)]
+#[allow(clippy::struct_field_names)]
 pub(super) struct Key {
     pub(super) key: String,
     pub(super) integrity: String,
@@ -23,6 +31,9 @@ pub(super) struct Key {
 #[derive(Serialize, Deserialize)]
 pub(super) struct Metadata {
     pub(super) url: Url,
+    /// The lockfile's `resolved` URL, if `url` was rewritten from it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) resolved: Option<Url>,
     pub(super) options: Options,
 }
 
@@ -31,7 +42,60 @@ pub(super) struct Options {
     pub(super) compress: bool,
 }
 
-pub struct Cache(PathBuf);
+pub struct Cache {
+    path: PathBuf,
+    algorithm: Option<ContentAlgorithm>,
+    reproducible: bool,
+    npm_index: bool,
+}
+
+/// A hash algorithm to address content in the cache by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl ContentAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            ContentAlgorithm::Sha1 => "sha1",
+            ContentAlgorithm::Sha256 => "sha256",
+            ContentAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ContentAlgorithm::Sha1 => Sha1::new().chain(data).finalize().to_vec(),
+            ContentAlgorithm::Sha256 => Sha256::new().chain(data).finalize().to_vec(),
+            ContentAlgorithm::Sha512 => Sha512::new().chain(data).finalize().to_vec(),
+        }
+    }
+
+    /// Returns the SRI hash of `data`.
+    pub fn sri(self, data: &[u8]) -> String {
+        format!(
+            "{}-{}",
+            self.name(),
+            BASE64_STANDARD.encode(self.digest(data))
+        )
+    }
+}
+
+impl FromStr for ContentAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<ContentAlgorithm> {
+        match s {
+            "sha1" => Ok(ContentAlgorithm::Sha1),
+            "sha256" => Ok(ContentAlgorithm::Sha256),
+            "sha512" => Ok(ContentAlgorithm::Sha512),
+            _ => Err(anyhow!("unknown content algorithm {s:?}")),
+        }
+    }
+}
 
 fn push_hash_segments(path: &mut PathBuf, hash: &str) {
     path.push(&hash[0..2]);
@@ -39,69 +103,97 @@ fn push_hash_segments(path: &mut PathBuf, hash: &str) {
     path.push(&hash[4..]);
 }
 
+pub(super) fn hex(hash: &[u8]) -> String {
+    hash.iter().fold(String::new(), |mut out, n| {
+        let _ = write!(out, "{n:02x}");
+        out
+    })
+}
+
 impl Cache {
     pub fn new(path: PathBuf) -> Cache {
-        Cache(path)
+        Cache {
+            path,
+            algorithm: None,
+            reproducible: false,
+            npm_index: false,
+        }
+    }
+
+    /// Addresses content by `algorithm`, rather than by the algorithm of each entry's integrity (or sha512, for entries
+    /// without one).
+    ///
+    /// As npm finds content by its index entry's integrity, entries are then indexed with their `algorithm` hash alone.
+    pub fn with_content_algorithm(self, algorithm: ContentAlgorithm) -> Cache {
+        Cache {
+            algorithm: Some(algorithm),
+            ..self
+        }
+    }
+
+    /// Writes the cache so that putting the same entries always leaves it the same, down to its files' modification times:
+    /// entries that are already indexed aren't indexed again, and written files are dated to the Unix epoch.
+    pub fn reproducible(self) -> Cache {
+        Cache {
+            reproducible: true,
+            ..self
+        }
+    }
+
+    /// Writes index entries exactly as npm's own cache does, each on its own line, so that the cache can be merged into
+    /// `~/.npm/_cacache`. Otherwise, entries are written without separators (which changing would change existing
+    /// caches' hashes), and so a bucket is only readable while it has a single entry.
+    pub fn with_npm_index(self) -> Cache {
+        Cache {
+            npm_index: true,
+            ..self
+        }
     }
 
     pub fn init(&self) -> anyhow::Result<()> {
-        fs::create_dir_all(self.0.join("content-v2"))?;
-        fs::create_dir_all(self.0.join("index-v5"))?;
+        fs::create_dir_all(self.path.join("content-v2"))?;
+        fs::create_dir_all(self.path.join("index-v5"))?;
 
         Ok(())
     }
 
+    /// Stores `data` under `key`, as fetched from `url`, which was rewritten from the lockfile's `resolved` URL if that's
+    /// given.
     pub fn put(
         &self,
         key: String,
         url: Url,
         data: &[u8],
         integrity: Option<String>,
+        resolved: Option<Url>,
     ) -> anyhow::Result<()> {
-        let (algo, hash, integrity) = if let Some(integrity) = integrity {
-            let (algo, hash) = integrity.split_once('-').unwrap();
-
-            (algo.to_string(), BASE64_STANDARD.decode(hash)?, integrity)
-        } else {
-            let hash = Sha512::new().chain(data).finalize();
-
-            (
-                String::from("sha512"),
-                hash.to_vec(),
-                format!("sha512-{}", BASE64_STANDARD.encode(hash)),
-            )
-        };
-
-        let content_path = {
-            let mut p = self.0.join("content-v2");
+        let (algo, hash, integrity) = match (self.algorithm, integrity) {
+            (None, Some(integrity)) => {
+                let (algo, hash) = integrity.split_once('-').unwrap();
 
-            p.push(algo);
+                (algo.to_string(), BASE64_STANDARD.decode(hash)?, integrity)
+            }
+            (algorithm, _) => {
+                let algorithm = algorithm.unwrap_or(ContentAlgorithm::Sha512);
+                let hash = algorithm.digest(data);
 
-            push_hash_segments(
-                &mut p,
-                &hash.into_iter().fold(String::new(), |mut out, n| {
-                    let _ = write!(out, "{n:02x}");
-                    out
-                }),
-            );
-
-            p
+                (
+                    algorithm.name().to_string(),
+                    hash.clone(),
+                    format!("{}-{}", algorithm.name(), BASE64_STANDARD.encode(hash)),
+                )
+            }
         };
 
-        fs::create_dir_all(content_path.parent().unwrap())?;
-
-        fs::write(content_path, data)?;
+        let content_path = self.content_path(&algo, &hash);
 
-        let index_path = {
-            let mut p = self.0.join("index-v5");
+        fs::create_dir_all(content_path.parent().unwrap())?;
 
-            push_hash_segments(
-                &mut p,
-                &format!("{:x}", Sha256::new().chain(&key).finalize()),
-            );
+        // Renamed into place only once fully written, so that an interrupted write never leaves partial content where
+        // it'd be taken as complete.
+        stage(&content_path, data)?.persist(&content_path)?;
 
-            p
-        };
+        let index_path = self.index_path(&key);
 
         fs::create_dir_all(index_path.parent().unwrap())?;
 
@@ -112,13 +204,502 @@ impl Cache {
             size: data.len(),
             metadata: Metadata {
                 url,
+                resolved,
                 options: Options { compress: true },
             },
         })?;
 
-        let mut file = File::options().append(true).create(true).open(index_path)?;
+        let line = format!(
+            "{}{:x}\t{data}",
+            if self.npm_index { "\n" } else { "" },
+            Sha1::new().chain(&data).finalize()
+        );
+
+        if self.reproducible && is_indexed(&index_path, &line)? {
+            return Ok(());
+        }
+
+        let mut file = File::options()
+            .append(true)
+            .create(true)
+            .open(&index_path)?;
+
+        write!(file, "{line}")?;
+
+        if self.reproducible {
+            file.set_modified(SystemTime::UNIX_EPOCH)?;
+            File::options()
+                .append(true)
+                .open(&content_path)?
+                .set_modified(SystemTime::UNIX_EPOCH)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the index entries for `key`, leaving its content to [`Cache::gc`] if nothing else refers to it.
+    pub fn forget(&self, key: &str) -> anyhow::Result<()> {
+        match fs::remove_file(self.index_path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn index_path(&self, key: &str) -> PathBuf {
+        let mut p = self.path.join("index-v5");
+
+        push_hash_segments(
+            &mut p,
+            &format!("{:x}", Sha256::new().chain(key).finalize()),
+        );
+
+        p
+    }
+
+    fn content_path(&self, algo: &str, hash: &[u8]) -> PathBuf {
+        let mut p = self.path.join("content-v2");
+
+        p.push(algo);
+
+        push_hash_segments(&mut p, &hex(hash));
+
+        p
+    }
+
+    /// Returns the path that content with the SRI hash `integrity` is stored at.
+    fn integrity_path(&self, integrity: &str) -> anyhow::Result<PathBuf> {
+        let (algo, hash) = integrity
+            .split_once('-')
+            .ok_or_else(|| anyhow!("malformed integrity {integrity:?}"))?;
+
+        Ok(self.content_path(algo, &BASE64_STANDARD.decode(hash)?))
+    }
+
+    /// Looks up the most recent index entry for `key`.
+    pub(super) fn get(&self, key: &str) -> anyhow::Result<Option<Key>> {
+        let index = match fs::read_to_string(self.index_path(key)) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut found = None;
+
+        for line in index.lines() {
+            if let Some((_, data)) = line.split_once('\t') {
+                let entry: Key = serde_json::from_str(data)?;
+
+                if entry.key == key {
+                    found = Some(entry);
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Reads the content with the SRI hash `integrity`, if it's in the cache.
+    pub fn content(&self, integrity: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(self.integrity_path(integrity)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads every entry from the index.
+    pub(super) fn entries(&self) -> anyhow::Result<Vec<Key>> {
+        let mut entries = Vec::new();
+
+        for entry in WalkDir::new(self.path.join("index-v5")) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                for line in fs::read_to_string(entry.path())?.lines() {
+                    if let Some((_, data)) = line.split_once('\t') {
+                        entries.push(serde_json::from_str(data)?);
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes content that isn't referenced by any index entry, returning the amount of bytes reclaimed.
+    pub fn gc(&self) -> anyhow::Result<u64> {
+        let referenced = self
+            .entries()?
+            .into_iter()
+            .map(|key| self.integrity_path(&key.integrity))
+            .collect::<anyhow::Result<HashSet<_>>>()?;
+
+        let content = self.path.join("content-v2");
+        let mut reclaimed = 0;
+
+        for entry in WalkDir::new(&content).contents_first(true) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                if !referenced.contains(entry.path()) {
+                    reclaimed += entry.metadata()?.len();
+
+                    fs::remove_file(entry.path())?;
+                }
+            } else if entry.file_type().is_dir() && entry.path() != content {
+                remove_dir_if_empty(entry.path())?;
+            }
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Packs the cache into an uncompressed tar archive at `path`, with its directory as the only top-level entry.
+    ///
+    /// Entries are sorted and their metadata is normalized, so that archives of the same cache are byte-identical.
+    pub fn export_tar(&self, path: &Path) -> anyhow::Result<()> {
+        let (Some(parent), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Err(anyhow!("can't export the cache at {:?}", self.path));
+        };
+
+        let output = Command::new("tar")
+            .args([
+                "--sort=name",
+                "--mtime=@0",
+                "--owner=0",
+                "--group=0",
+                "--numeric-owner",
+                "--mode=u=rwX,go=rX",
+                "--format=gnu",
+                "--create",
+                "--file",
+            ])
+            .arg(path)
+            .arg("-C")
+            .arg(parent)
+            .arg(name)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "failed to export the cache: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether the index bucket at `path` already has `line` in it.
+fn is_indexed(path: &Path, line: &str) -> anyhow::Result<bool> {
+    match fs::read_to_string(path) {
+        Ok(bucket) => Ok(bucket.contains(line)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `data` to a temporary file next to `path`, to be renamed to it.
+fn stage(path: &Path, data: &[u8]) -> anyhow::Result<NamedTempFile> {
+    let mut file = tempfile::Builder::new()
+        .prefix(".tmp-")
+        .tempfile_in(path.parent().unwrap())?;
+
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+
+    Ok(file)
+}
+
+fn remove_dir_if_empty(path: &Path) -> anyhow::Result<()> {
+    if fs::read_dir(path)?.next().is_none() {
+        fs::remove_dir(path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex, stage, Cache, ContentAlgorithm};
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use digest::{Digest, Update};
+    use sha1::Sha1;
+    use sha2::{Sha256, Sha512};
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        time::{Duration, SystemTime},
+    };
+    use tempfile::tempdir;
+    use url::Url;
+
+    #[test]
+    fn gc_removes_orphans() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+
+        cache.init()?;
+        cache.put(String::from("foo"), url, b"referenced", None, None)?;
+
+        let orphan = cache.content_path("sha512", &[0xab; 64]);
+
+        fs::create_dir_all(orphan.parent().unwrap())?;
+        fs::write(&orphan, b"orphan")?;
+
+        assert_eq!(cache.gc()?, 6);
+        assert!(!orphan.exists());
+        assert!(!orphan.parent().unwrap().exists());
+
+        let referenced =
+            cache.content_path("sha512", &Sha512::new().chain(b"referenced").finalize());
+
+        assert_eq!(fs::read(referenced)?, b"referenced");
+        assert_eq!(cache.gc()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_algorithm() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"))
+            .with_content_algorithm("sha256".parse::<ContentAlgorithm>()?);
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+        let sha512 = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(b"foo").finalize())
+        );
+
+        cache.init()?;
+        cache.put(String::from("foo"), url, b"foo", Some(sha512), None)?;
+
+        let hash = format!("{:x}", Sha256::new().chain(b"foo").finalize());
+        let path = dir
+            .path()
+            .join("_cacache/content-v2/sha256")
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(&hash[4..]);
+
+        assert_eq!(fs::read(path)?, b"foo");
+        assert!(!dir.path().join("_cacache/content-v2/sha512").exists());
+        assert!(cache.get("foo")?.unwrap().integrity.starts_with("sha256-"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_tar_is_deterministic() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+
+        cache.init()?;
+
+        for name in ["foo", "bar"] {
+            let url = Url::parse(&format!(
+                "https://registry.npmjs.org/{name}/-/{name}-1.0.0.tgz"
+            ))?;
+
+            cache.put(format!("key-{name}"), url, name.as_bytes(), None, None)?;
+        }
+
+        cache.export_tar(&dir.path().join("first.tar"))?;
+
+        // Only the metadata that the export should normalize changes in between.
+        for entry in walkdir::WalkDir::new(dir.path().join("_cacache")) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o600))?;
+                fs::File::options()
+                    .append(true)
+                    .open(entry.path())?
+                    .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000))?;
+            }
+        }
+
+        cache.export_tar(&dir.path().join("second.tar"))?;
+
+        let first = fs::read(dir.path().join("first.tar"))?;
+
+        assert_eq!(first, fs::read(dir.path().join("second.tar"))?);
+        assert!(first.starts_with(b"_cacache/"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reproducible_index() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+        let run = |name: &str, reproducible: bool| -> anyhow::Result<std::path::PathBuf> {
+            let mut cache = Cache::new(dir.path().join(name));
+
+            if reproducible {
+                cache = cache.reproducible();
+            }
+
+            cache.init()?;
+
+            // As when a cache is fetched into again.
+            for _ in 0..2 {
+                cache.put(String::from("key"), url.clone(), b"foo", None, None)?;
+            }
+
+            Ok(cache.index_path("key"))
+        };
+
+        let (first, second) = (run("first", true)?, run("second", true)?);
+
+        let index = fs::read(&first)?;
+
+        assert_eq!(index, fs::read(&second)?);
+        assert_eq!(fs::metadata(&first)?.modified()?, SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            fs::read(run("plain", false)?)?.len(),
+            index.len() * 2,
+            "without it, entries should be indexed every time they're put"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn original_resolved_url() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let resolved = Url::parse("git+ssh://git@github.com/foo/bar.git#0a1b2c3d")?;
+        let fetched = Url::parse("https://codeload.github.com/foo/bar/tar.gz/0a1b2c3d")?;
+        let registry = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+
+        cache.init()?;
+        cache.put(
+            String::from("git"),
+            fetched.clone(),
+            b"git",
+            None,
+            Some(resolved.clone()),
+        )?;
+        cache.put(
+            String::from("registry"),
+            registry.clone(),
+            b"foo",
+            None,
+            None,
+        )?;
+
+        let git = cache.get("git")?.unwrap();
+
+        assert_eq!(git.metadata.url, fetched);
+        assert_eq!(git.metadata.resolved, Some(resolved));
+
+        let registry_entry = cache.get("registry")?.unwrap();
+
+        assert_eq!(registry_entry.metadata.url, registry);
+        assert_eq!(registry_entry.metadata.resolved, None);
+        assert!(!fs::read_to_string(cache.index_path("registry"))?.contains("\"resolved\""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_index_unchanged() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let url = Url::parse("https://codeload.github.com/foo/bar/tar.gz/0a1b2c3d")?;
+
+        cache.init()?;
+        cache.put(String::from("git"), url, b"git", None, None)?;
+
+        // As indexed before the original `resolved` URL could be recorded.
+        let data = format!(
+            r#"{{"key":"git","integrity":"sha512-{}","time":0,"size":3,"metadata":{{"url":"https://codeload.github.com/foo/bar/tar.gz/0a1b2c3d","options":{{"compress":true}}}}}}"#,
+            BASE64_STANDARD.encode(Sha512::digest(b"git"))
+        );
+
+        assert_eq!(
+            fs::read_to_string(cache.index_path("git"))?,
+            format!("{:x}\t{data}", Sha1::new().chain(&data).finalize())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_put() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+        let data = b"complete tarball";
+        let integrity = format!("sha512-{}", BASE64_STANDARD.encode(Sha512::digest(data)));
+        let content_path = cache.integrity_path(&integrity)?;
+
+        cache.init()?;
+        fs::create_dir_all(content_path.parent().unwrap())?;
+
+        // Killed after writing part of the content, but before renaming it into place.
+        stage(&content_path, &data[..8])?.into_temp_path().keep()?;
+
+        assert_eq!(cache.content(&integrity)?, None);
+        assert!(cache.get("key")?.is_none());
+
+        cache.put(
+            String::from("key"),
+            url,
+            data,
+            Some(integrity.clone()),
+            None,
+        )?;
+
+        assert_eq!(cache.content(&integrity)?.as_deref(), Some(&data[..]));
+        assert_eq!(
+            cache.gc()?,
+            8,
+            "the leftover partial write should be collected"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npm_index() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache")).with_npm_index();
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+        let key = format!("make-fetch-happen:request-cache:{url}");
+
+        cache.init()?;
+        cache.put(key.clone(), url.clone(), b"old", None, None)?;
+        cache.put(key.clone(), url.clone(), b"new", None, None)?;
+
+        let hash = hex(&Sha256::new().chain(&key).finalize());
+        let bucket = dir
+            .path()
+            .join("_cacache/index-v5")
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(&hash[4..]);
+        let index = fs::read_to_string(bucket)?;
+        let lines = index.split('\n').collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3, "{index:?}");
+        assert!(lines[0].is_empty(), "each entry should start a new line");
+
+        for line in &lines[1..] {
+            let (digest, entry) = line.split_once('\t').unwrap();
+
+            assert_eq!(digest, hex(&Sha1::new().chain(entry).finalize()));
+        }
+
+        let latest = cache.get(&key)?.unwrap();
 
-        write!(file, "{:x}\t{data}", Sha1::new().chain(&data).finalize())?;
+        assert_eq!(
+            cache.content(&latest.integrity)?.as_deref(),
+            Some(&b"new"[..])
+        );
 
         Ok(())
     }
@@ -0,0 +1,326 @@
+use log::warn;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{env, fs, path::Path, sync::OnceLock, time::Duration};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Network configuration, read from an optional TOML file (passed with `--config`), where each setting can be overridden
+/// by its corresponding environment variable.
+///
+/// ```toml
+/// # FETCH_NPM_DEPS_PROXY
+/// proxy = "http://proxy.example.com:3128"
+/// # FETCH_NPM_DEPS_TIMEOUT, in seconds
+/// timeout = 60
+/// # FETCH_NPM_DEPS_TIMEOUT_RATE, in bytes per second, to give downloads `timeout` plus the time their size takes at it
+/// timeout-rate = 1048576
+/// # FETCH_NPM_DEPS_CONNECT_RETRIES, for failures to connect
+/// connect-retries = 10
+/// # FETCH_NPM_DEPS_RETRIES, for other transient failures
+/// retries = 3
+/// # FETCH_NPM_DEPS_TOTAL_RETRIES, shared by all requests in the run
+/// total-retries = 50
+/// # FETCH_NPM_DEPS_PER_HOST_JOBS, for how many requests may be sent to one host at once
+/// per-host-jobs = 4
+/// # FETCH_NPM_DEPS_RANGE_THRESHOLD, in bytes, over which downloads are split into ranges fetched in parallel
+/// range-threshold = 104857600
+/// # FETCH_NPM_DEPS_MAX_SIZE, in bytes, for any one download
+/// max-size = 104857600
+/// # FETCH_NPM_DEPS_ALLOWED_HOSTS, comma-separated
+/// allowed-hosts = ["registry.npmjs.org", "mirror.example.com"]
+/// # FETCH_NPM_DEPS_HTTP2_HOSTS, comma-separated
+/// http2-hosts = ["registry.npmjs.org"]
+/// # FETCH_NPM_DEPS_CACHING_PROXY_HOSTS, comma-separated, for registry proxies (e.g. Verdaccio) whose 404s are retried
+/// caching-proxy-hosts = ["verdaccio.example.com"]
+/// # FETCH_NPM_DEPS_GITLAB_HOSTS, comma-separated, for self-hosted GitLab instances whose Git dependencies are fetched as
+/// # archives
+/// gitlab-hosts = ["gitlab.example.com"]
+/// # FETCH_NPM_DEPS_DECOMPRESSED_HASH_HOSTS, comma-separated, for registries whose integrity covers tarballs' decompressed
+/// # contents, as they recompress them
+/// decompressed-hash-hosts = ["registry.example.com"]
+/// # FETCH_NPM_DEPS_UNVERIFIED_HOSTS, comma-separated, replacing the default hosts whose archives' integrity isn't verified
+/// unverified-hosts = ["codeload.github.com"]
+/// # FETCH_NPM_DEPS_S3_ENDPOINT, the HTTPS URL that `s3://{bucket}/{key}` URLs are fetched from
+/// s3-endpoint = "https://{bucket}.s3.eu-west-1.amazonaws.com/{key}"
+///
+/// # GITHUB_TOKEN, only ever sent to GitHub
+/// github-token = "example-github-token"
+///
+/// # NIX_NPM_TOKENS, as JSON
+/// [tokens]
+/// "registry.example.com" = "example-registry-bearer-token"
+///
+/// # FETCH_NPM_DEPS_REGISTRY_REWRITES, as JSON
+/// [registry-rewrites]
+/// "registry.npmjs.org" = "registry.example.com"
+/// "registry.yarnpkg.com" = "artifacts.example.com/api/npm/v1"
+///
+/// # FETCH_NPM_DEPS_MIRRORS, as JSON
+/// [mirrors]
+/// "registry.npmjs.org" = ["mirror.example.com"]
+///
+/// # FETCH_NPM_DEPS_STATUS_RETRIES, as JSON, for whether responses with each status are retried (otherwise, only 429s and
+/// # 5xx are)
+/// [status-retries]
+/// "520" = true
+/// "503" = false
+///
+/// # FETCH_NPM_DEPS_HOST_OVERRIDES, as JSON, for the Host header and TLS SNI name sent to an IP endpoint
+/// [host-overrides]
+/// "10.0.0.5:8443" = "mirror.example.com"
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub proxy: Option<String>,
+    pub timeout: Option<u64>,
+    pub timeout_rate: Option<u64>,
+    pub connect_retries: Option<usize>,
+    pub retries: Option<usize>,
+    pub total_retries: Option<usize>,
+    pub per_host_jobs: Option<usize>,
+    pub max_size: Option<u64>,
+    pub range_threshold: Option<u64>,
+    pub allowed_hosts: Option<Vec<String>>,
+    pub http2_hosts: Vec<String>,
+    pub caching_proxy_hosts: Vec<String>,
+    pub gitlab_hosts: Vec<String>,
+    pub decompressed_hash_hosts: Vec<String>,
+    pub unverified_hosts: Option<Vec<String>>,
+    pub s3_endpoint: Option<String>,
+    pub github_token: Option<String>,
+    pub tokens: Map<String, Value>,
+    pub registry_rewrites: Map<String, Value>,
+    pub mirrors: Map<String, Value>,
+    pub host_overrides: Map<String, Value>,
+    pub status_retries: Map<String, Value>,
+}
+
+impl Config {
+    /// Loads the configuration from `path` (if any), and then the environment.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+        let config = match path {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+            None => Config::default(),
+        };
+
+        Ok(config.with_env(|name| env::var(name).ok()))
+    }
+
+    fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Config {
+        if let Some(proxy) = var("FETCH_NPM_DEPS_PROXY") {
+            self.proxy = Some(proxy);
+        }
+
+        if let Some(timeout) = var("FETCH_NPM_DEPS_TIMEOUT") {
+            match timeout.parse() {
+                Ok(timeout) => self.timeout = Some(timeout),
+                Err(e) => warn!("ignoring invalid FETCH_NPM_DEPS_TIMEOUT: {e}"),
+            }
+        }
+
+        for (name, setting) in [
+            ("FETCH_NPM_DEPS_TIMEOUT_RATE", &mut self.timeout_rate),
+            ("FETCH_NPM_DEPS_MAX_SIZE", &mut self.max_size),
+            ("FETCH_NPM_DEPS_RANGE_THRESHOLD", &mut self.range_threshold),
+        ] {
+            if let Some(value) = var(name) {
+                match value.parse() {
+                    Ok(value) => *setting = Some(value),
+                    Err(e) => warn!("ignoring invalid {name}: {e}"),
+                }
+            }
+        }
+
+        for (name, setting) in [
+            ("FETCH_NPM_DEPS_CONNECT_RETRIES", &mut self.connect_retries),
+            ("FETCH_NPM_DEPS_RETRIES", &mut self.retries),
+            ("FETCH_NPM_DEPS_TOTAL_RETRIES", &mut self.total_retries),
+            ("FETCH_NPM_DEPS_PER_HOST_JOBS", &mut self.per_host_jobs),
+        ] {
+            if let Some(value) = var(name) {
+                match value.parse() {
+                    Ok(value) => *setting = Some(value),
+                    Err(e) => warn!("ignoring invalid {name}: {e}"),
+                }
+            }
+        }
+
+        if let Some(hosts) = var("FETCH_NPM_DEPS_ALLOWED_HOSTS") {
+            self.allowed_hosts = Some(hosts.split(',').map(|h| h.trim().to_string()).collect());
+        }
+
+        for (name, setting) in [
+            ("FETCH_NPM_DEPS_HTTP2_HOSTS", &mut self.http2_hosts),
+            (
+                "FETCH_NPM_DEPS_CACHING_PROXY_HOSTS",
+                &mut self.caching_proxy_hosts,
+            ),
+            ("FETCH_NPM_DEPS_GITLAB_HOSTS", &mut self.gitlab_hosts),
+            (
+                "FETCH_NPM_DEPS_DECOMPRESSED_HASH_HOSTS",
+                &mut self.decompressed_hash_hosts,
+            ),
+        ] {
+            if let Some(hosts) = var(name) {
+                *setting = host_list(&hosts);
+            }
+        }
+
+        if let Some(hosts) = var("FETCH_NPM_DEPS_UNVERIFIED_HOSTS") {
+            self.unverified_hosts = Some(host_list(&hosts));
+        }
+
+        if let Some(endpoint) = var("FETCH_NPM_DEPS_S3_ENDPOINT") {
+            self.s3_endpoint = Some(endpoint);
+        }
+
+        if let Some(token) = var("GITHUB_TOKEN").filter(|t| !t.is_empty()) {
+            self.github_token = Some(token);
+        }
+
+        for (name, map) in [
+            ("NIX_NPM_TOKENS", &mut self.tokens),
+            (
+                "FETCH_NPM_DEPS_REGISTRY_REWRITES",
+                &mut self.registry_rewrites,
+            ),
+            ("FETCH_NPM_DEPS_MIRRORS", &mut self.mirrors),
+            ("FETCH_NPM_DEPS_HOST_OVERRIDES", &mut self.host_overrides),
+            ("FETCH_NPM_DEPS_STATUS_RETRIES", &mut self.status_retries),
+        ] {
+            if let Some(value) = var(name) {
+                match serde_json::from_str(&value) {
+                    Ok(value) => *map = value,
+                    Err(e) => warn!("ignoring invalid {name}: {e}"),
+                }
+            }
+        }
+
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+}
+
+/// Splits a comma-separated list of hosts.
+fn host_list(hosts: &str) -> Vec<String> {
+    hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Sets the configuration for this run. Has no effect if the configuration has already been set or used.
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// Returns the configuration for this run, loading it from the environment alone if it wasn't set with [`init`].
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| Config::default().with_env(|name| env::var(name).ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use serde_json::json;
+    use std::{collections::HashMap, time::Duration};
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            proxy = "http://file-proxy:3128"
+            timeout = 30
+            retries = 3
+            allowed-hosts = ["registry.npmjs.org"]
+
+            [tokens]
+            "registry.example.com" = "file-token"
+
+            [registry-rewrites]
+            "registry.npmjs.org" = "registry.example.com"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn file_only() {
+        let config = config().with_env(|_| None);
+
+        assert_eq!(config.proxy.as_deref(), Some("http://file-proxy:3128"));
+        assert_eq!(config.timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(
+            config.allowed_hosts,
+            Some(vec![String::from("registry.npmjs.org")])
+        );
+        assert_eq!(config.tokens["registry.example.com"], "file-token");
+        assert_eq!(
+            config.registry_rewrites["registry.npmjs.org"],
+            "registry.example.com"
+        );
+        assert!(config.mirrors.is_empty());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let env = HashMap::from([
+            ("FETCH_NPM_DEPS_PROXY", "http://env-proxy:3128"),
+            ("FETCH_NPM_DEPS_TIMEOUT", "not a number"),
+            ("FETCH_NPM_DEPS_CONNECT_RETRIES", "10"),
+            ("FETCH_NPM_DEPS_UNVERIFIED_HOSTS", ""),
+            (
+                "FETCH_NPM_DEPS_ALLOWED_HOSTS",
+                "a.example.com, b.example.com",
+            ),
+            (
+                "NIX_NPM_TOKENS",
+                r#"{ "registry.example.com": "env-token" }"#,
+            ),
+        ]);
+
+        let config = config().with_env(|name| env.get(name).map(ToString::to_string));
+
+        assert_eq!(config.proxy.as_deref(), Some("http://env-proxy:3128"));
+        assert_eq!(
+            config.timeout(),
+            Some(Duration::from_secs(30)),
+            "invalid environment values should be ignored"
+        );
+        assert_eq!(config.connect_retries, Some(10));
+        assert_eq!(config.retries, Some(3));
+        assert_eq!(
+            config.allowed_hosts,
+            Some(vec![
+                String::from("a.example.com"),
+                String::from("b.example.com")
+            ])
+        );
+        assert_eq!(
+            config.unverified_hosts,
+            Some(vec![]),
+            "an empty list should verify every host"
+        );
+        assert_eq!(
+            config.tokens,
+            *json!({ "registry.example.com": "env-token" })
+                .as_object()
+                .unwrap()
+        );
+        assert_eq!(
+            config.registry_rewrites["registry.npmjs.org"], "registry.example.com",
+            "settings without an environment override should come from the file"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("registry = \"foo\"").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::anyhow;
+use log::info;
+use serde::Serialize;
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// A progress event, written as a line of JSON for wrappers to consume.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Started {
+        name: &'a str,
+        url: &'a Url,
+    },
+    Fetched {
+        name: &'a str,
+        url: &'a Url,
+        bytes: usize,
+        seconds: f64,
+    },
+    Failed {
+        name: &'a str,
+        url: &'a Url,
+        error: String,
+        seconds: f64,
+    },
+    Done {
+        packages: usize,
+        seconds: f64,
+    },
+}
+
+/// A stream of NDJSON progress events (passed with `--events`), which may be written to from multiple threads.
+///
+/// To write to an already open file descriptor, pass its `/dev/fd/<n>` path.
+pub struct Events {
+    file: Option<Mutex<File>>,
+    start: Instant,
+}
+
+impl Events {
+    /// Opens `path` for appending events to, or discards events if there's no `path`.
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Events> {
+        let file = path
+            .map(|path| File::options().append(true).create(true).open(path))
+            .transpose()?;
+
+        Ok(Events {
+            file: file.map(Mutex::new),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn emit(&self, event: &Event) -> anyhow::Result<()> {
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_vec(event)?;
+
+            line.push(b'\n');
+
+            // Written in one go, so that lines from different threads don't interleave.
+            file.lock().unwrap().write_all(&line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the time since the events were opened.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Logs the name of each package as it's fetched, or (with `--sorted-logs`) buffers them, so that they can be logged in a
+/// deterministic order once fetching is done.
+///
+/// With a CI format (passed with `--ci-format`), each package's fetch is also reported in that CI system's markup once it
+/// finishes.
+///
+/// With `--timings`, how long each package took to fetch is also collected, to report the slowest ones.
+pub struct FetchLog {
+    buffered: Option<Mutex<Vec<String>>>,
+    ci_format: Option<CiFormat>,
+    timings: Option<Mutex<Vec<(String, Duration)>>>,
+}
+
+/// A CI system to format fetch logs for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CiFormat {
+    /// GitHub Actions, with a collapsible group per package, and an error annotation for each failure.
+    Github,
+}
+
+impl FromStr for CiFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<CiFormat> {
+        match s {
+            "github" => Ok(CiFormat::Github),
+            _ => Err(anyhow!("unknown CI format {s:?}, expected \"github\"")),
+        }
+    }
+}
+
+impl FetchLog {
+    pub fn new(sorted: bool) -> FetchLog {
+        FetchLog {
+            buffered: sorted.then(|| Mutex::new(Vec::new())),
+            ci_format: None,
+            timings: None,
+        }
+    }
+
+    pub fn with_timings(self, timings: bool) -> FetchLog {
+        FetchLog {
+            timings: timings.then(|| Mutex::new(Vec::new())),
+            ..self
+        }
+    }
+
+    pub fn with_ci_format(self, ci_format: Option<CiFormat>) -> FetchLog {
+        FetchLog { ci_format, ..self }
+    }
+
+    pub fn fetching(&self, name: &str) {
+        if let Some(lines) = &self.buffered {
+            lines.lock().unwrap().push(name.to_string());
+        } else if self.ci_format.is_none() {
+            info!("{name}");
+        }
+    }
+
+    /// Reports that fetching `name` from `url` finished after `elapsed`, with `error` if it failed.
+    pub fn finished(
+        &self,
+        name: &str,
+        url: &Url,
+        elapsed: Duration,
+        error: Option<&anyhow::Error>,
+    ) {
+        if let Some(timings) = &self.timings {
+            timings.lock().unwrap().push((name.to_string(), elapsed));
+        }
+
+        if let Some(CiFormat::Github) = self.ci_format {
+            // Written in one go to stderr (as stdout is for the hash), so that groups from different threads don't
+            // interleave.
+            let _ = io::stderr().write_all(github_group(name, url, error).as_bytes());
+        }
+    }
+
+    /// Returns the `n` packages that took the longest to fetch, slowest first, if timings are being collected.
+    pub fn slowest(&self, n: usize) -> Vec<(String, Duration)> {
+        let mut timings = self
+            .timings
+            .as_ref()
+            .map(|timings| timings.lock().unwrap().clone())
+            .unwrap_or_default();
+
+        timings.sort_by(|(x_name, x), (y_name, y)| y.cmp(x).then_with(|| x_name.cmp(y_name)));
+        timings.truncate(n);
+
+        timings
+    }
+
+    /// Returns the buffered lines, sorted by package name.
+    pub fn into_sorted(self) -> Vec<String> {
+        let mut lines = self
+            .buffered
+            .map(|lines| lines.into_inner().unwrap())
+            .unwrap_or_default();
+
+        lines.sort();
+
+        lines
+    }
+}
+
+/// Formats a package's fetch as a GitHub Actions log group, followed by an error annotation if it failed.
+fn github_group(name: &str, url: &Url, error: Option<&anyhow::Error>) -> String {
+    // Workflow command data ends at the end of the line, so line breaks (and the `%` they're escaped with) are escaped.
+    let escape = |s: &str| {
+        s.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    let mut out = format!("::group::{}\nfetching {url}\n", escape(name));
+
+    match error {
+        None => out.push_str("fetched\n::endgroup::\n"),
+        Some(error) => {
+            let _ = write!(
+                out,
+                "failed\n::endgroup::\n::error::{}\n",
+                escape(&format!("{error:#}"))
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{github_group, FetchLog};
+    use rayon::prelude::*;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn sorted_logs() {
+        let names = (0..100)
+            .rev()
+            .map(|i| format!("node_modules/dep-{i:03}"))
+            .collect::<Vec<_>>();
+
+        let log = FetchLog::new(true);
+
+        names.par_iter().for_each(|name| log.fetching(name));
+
+        let mut expected = names.clone();
+
+        expected.sort();
+
+        assert_eq!(log.into_sorted(), expected);
+        assert!(FetchLog::new(false).into_sorted().is_empty());
+    }
+
+    #[test]
+    fn github_groups() -> anyhow::Result<()> {
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+        let error = anyhow::anyhow!("HTTP 500\nretries exhausted (100%)");
+
+        assert_eq!(
+            github_group("node_modules/foo", &url, None),
+            format!("::group::node_modules/foo\nfetching {url}\nfetched\n::endgroup::\n")
+        );
+        assert_eq!(
+            github_group("node_modules/foo", &url, Some(&error)),
+            format!(
+                "::group::node_modules/foo\nfetching {url}\nfailed\n::endgroup::\n::error::HTTP 500%0Aretries exhausted (100%25)\n"
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn slowest_timings() -> anyhow::Result<()> {
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+        let log = FetchLog::new(false).with_timings(true);
+
+        (0..100u64).into_par_iter().for_each(|i| {
+            log.finished(
+                &format!("node_modules/dep-{i:03}"),
+                &url,
+                Duration::from_millis(i),
+                None,
+            );
+        });
+
+        assert_eq!(
+            log.slowest(3),
+            [
+                (
+                    String::from("node_modules/dep-099"),
+                    Duration::from_millis(99)
+                ),
+                (
+                    String::from("node_modules/dep-098"),
+                    Duration::from_millis(98)
+                ),
+                (
+                    String::from("node_modules/dep-097"),
+                    Duration::from_millis(97)
+                ),
+            ]
+        );
+        assert_eq!(log.slowest(1000).len(), 100);
+        assert!(FetchLog::new(false).slowest(3).is_empty());
+
+        Ok(())
+    }
+}
@@ -1,21 +1,36 @@
 #![warn(clippy::pedantic)]
 
-use crate::cacache::{Cache, Key};
-use anyhow::{anyhow, bail};
+use crate::{
+    cacache::{Cache, ContentAlgorithm, Key},
+    events::{CiFormat, Event, Events, FetchLog},
+    parse::Package,
+};
+use anyhow::{anyhow, bail, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{error, info, warn};
 use rayon::prelude::*;
-use serde_json::{Map, Value};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
-    env, fs,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt, fs,
+    io::Write,
     path::{Path, PathBuf},
     process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
-use tempfile::tempdir;
+use tempfile::NamedTempFile;
 use url::Url;
 use walkdir::WalkDir;
 
 mod cacache;
+mod config;
+mod events;
+mod nar;
+mod netrc;
 mod parse;
+mod sbom;
 mod util;
 
 fn cache_map_path() -> Option<PathBuf> {
@@ -38,7 +53,7 @@ fn cache_map_path() -> Option<PathBuf> {
 /// `dependencies` key in v2 lockfiles designed for backwards compatibility with v1 parsers is removed because of inconsistent data.
 fn fixup_lockfile(
     mut lock: Map<String, Value>,
-    cache: &Option<HashMap<String, String>>,
+    cache: Option<&HashMap<String, String>>,
 ) -> anyhow::Result<Option<Map<String, Value>>> {
     let mut fixed = false;
 
@@ -109,7 +124,7 @@ fn fixup_lockfile(
 // Recursive helper to fixup v1 lockfile deps
 fn fixup_v1_deps(
     dependencies: &mut Map<String, Value>,
-    cache: &Option<HashMap<String, String>>,
+    cache: Option<&HashMap<String, String>>,
     fixed: &mut bool,
 ) {
     for dep in dependencies.values_mut() {
@@ -172,260 +187,2971 @@ fn map_cache() -> anyhow::Result<HashMap<Url, String>> {
     Ok(hashes)
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
+/// Returns the sha512 SRI hash of every file under `dir` (hashed in parallel), as `<sri>\t<path relative to dir>` lines
+/// sorted by path.
+fn hash_dir(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut files = WalkDir::new(dir)
+        .into_iter()
+        .filter(|entry| entry.as_ref().map_or(true, |e| e.file_type().is_file()))
+        .map(|entry| Ok(entry?.into_path()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    files.sort();
+
+    files
+        .par_iter()
+        .map(|path| {
+            let sri = ContentAlgorithm::Sha512.sri(
+                &fs::read(path).with_context(|| format!("failed to read {}", path.display()))?,
+            );
+
+            Ok(format!(
+                "{sri}\t{}",
+                path.strip_prefix(dir).unwrap_or(path).display()
+            ))
+        })
+        .collect()
+}
 
-    let args = env::args().collect::<Vec<_>>();
+/// Creates `path` if necessary and resolves it to its canonical form.
+///
+/// The output directory may be (or live under) a symlink, so we resolve it once and use the real path for both the cache
+/// writes and the final hash step, rather than letting each of them follow the link on their own.
+fn canonical_out_dir(path: &Path) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(path)?;
 
-    if args.len() < 2 {
-        println!("usage: {} <path/to/package-lock.json>", args[0]);
-        println!();
-        println!("Prefetches npm dependencies for usage by fetchNpmDeps.");
+    Ok(fs::canonicalize(path)?)
+}
 
-        process::exit(1);
-    }
+/// Options controlling how each package is fetched and cached.
+#[derive(Default)]
+struct FetchOptions {
+    validate_archives: bool,
+    /// Whether to undo an extra layer of gzip compression added by broken mirrors, if that fixes an integrity mismatch.
+    unwrap_double_gzip: bool,
+    /// A command to run on each fetched tarball, with the tarball's path and the package's name as arguments.
+    on_fetch: Option<PathBuf>,
+    /// A cache to copy intact entries from, rather than fetching them.
+    offline: Option<Cache>,
+    /// Whether to leave checking tarballs' integrity to a pass over the cache once everything's fetched (see
+    /// [`verify_fetched`]).
+    verify_after: bool,
+}
 
-    if let Ok(jobs) = env::var("NIX_BUILD_CORES") {
-        if !jobs.is_empty() {
-            rayon::ThreadPoolBuilder::new()
-                .num_threads(
-                    jobs.parse()
-                        .expect("NIX_BUILD_CORES must be a whole number"),
-                )
-                .build_global()
-                .unwrap();
+/// Fetches `package` and inserts it into `cache`, returning the size of its tarball.
+fn fetch_package(cache: &Cache, package: Package, options: &FetchOptions) -> anyhow::Result<usize> {
+    if let Some(offline) = &options.offline {
+        if let Some(size) = copy_cached(offline, cache, &package)? {
+            return Ok(size);
         }
     }
 
-    if args[1] == "--fixup-lockfile" {
-        let lock = serde_json::from_str(&fs::read_to_string(&args[2])?)?;
+    let mut tarball = package
+        .tarball()
+        .map_err(|e| anyhow!("couldn't fetch {} at {}: {e:?}", package.name, package.url))?;
+    let integrity = if options.verify_after {
+        // Cached under its own digest until it's verified, so that npm never finds it under an integrity it may not match.
+        package
+            .integrity()
+            .filter(|_| !util::should_verify_integrity(&package.url, config::get()))
+            .map(ToString::to_string)
+    } else {
+        checked_integrity(&package, &mut tarball, options, config::get())?
+    };
 
-        let cache = cache_map_path()
-            .map(|map_path| Ok::<_, anyhow::Error>(serde_json::from_slice(&fs::read(map_path)?)?))
-            .transpose()?;
+    if options.validate_archives {
+        util::validate_archive(&tarball).map_err(|e| {
+            anyhow!(
+                "couldn't validate {} at {}: {e:?}",
+                package.name,
+                package.url
+            )
+        })?;
+    }
 
-        if let Some(fixed) = fixup_lockfile(lock, &cache)? {
-            println!("Fixing lockfile");
+    if let Some(hook) = &options.on_fetch {
+        run_fetch_hook(hook, &package.name, &tarball)?;
+    }
 
-            fs::write(&args[2], serde_json::to_string(&fixed)?)?;
-        }
+    cache
+        .put(
+            cache_key(&package.url),
+            package.url,
+            &tarball,
+            integrity,
+            package.original_url,
+        )
+        .map_err(|e| anyhow!("couldn't insert cache entry for {}: {e:?}", package.name))?;
+
+    Ok(tarball.len())
+}
 
-        return Ok(());
-    } else if args[1] == "--map-cache" {
-        let map = map_cache()?;
+/// Checks `tarball` against `package`'s integrity (unless its host is exempt from verification), unwrapping it if the
+/// server compressed it twice, and returns the integrity to cache it with.
+///
+/// A tarball from one of `decompressed-hash-hosts` whose archive matches, but whose recompressed bytes don't, is cached
+/// with its own digest, as npm checks content against the integrity it's cached with; `--fixup-lockfile` then writes that
+/// digest to the lockfile.
+fn checked_integrity(
+    package: &Package,
+    tarball: &mut Vec<u8>,
+    options: &FetchOptions,
+    config: &config::Config,
+) -> anyhow::Result<Option<String>> {
+    let Some(integrity) = package.integrity().map(ToString::to_string) else {
+        return Ok(None);
+    };
 
-        fs::write(
-            cache_map_path().expect("CACHE_MAP_PATH environment variable must be set"),
-            serde_json::to_string(&map)?,
-        )?;
+    if !util::should_verify_integrity(&package.url, config)
+        || util::integrity_matches(&integrity, tarball)?
+    {
+        return Ok(Some(integrity));
+    }
 
-        return Ok(());
+    if util::should_hash_decompressed(&package.url, config)
+        && util::is_gzip(tarball)
+        && util::decompressed_integrity_matches(&integrity, tarball)?
+    {
+        info!(
+            "{} at {} was recompressed by the server, caching it with its own integrity",
+            package.name, package.url
+        );
+
+        return Ok(None);
     }
 
-    let lock_content = fs::read_to_string(&args[1])?;
+    match unwrap_double_gzip(tarball, &integrity, options)? {
+        Some(unwrapped) => {
+            warn!(
+                "{} at {} was compressed twice by the server, using its inner gzip stream instead",
+                package.name, package.url
+            );
 
-    let out_tempdir;
+            *tarball = unwrapped;
 
-    let (out, print_hash) = if let Some(path) = args.get(2) {
-        (Path::new(path), false)
-    } else {
-        out_tempdir = tempdir()?;
+            Ok(Some(integrity))
+        }
+        None => bail!(
+            "integrity mismatch for {} at {}: expected {integrity}",
+            package.name,
+            package.url
+        ),
+    }
+}
 
-        (out_tempdir.path(), true)
-    };
+/// Like [`fetch_package`], but reports its progress to `events` and `log`.
+fn fetch_package_with_events(
+    cache: &Cache,
+    package: Package,
+    options: &FetchOptions,
+    events: &Events,
+    log: &FetchLog,
+) -> anyhow::Result<()> {
+    let (name, url) = (package.name.clone(), package.url.clone());
+    let start = Instant::now();
+
+    log.fetching(&name);
+
+    events.emit(&Event::Started {
+        name: &name,
+        url: &url,
+    })?;
 
-    let packages = parse::lockfile(
-        &lock_content,
-        env::var("FORCE_GIT_DEPS").is_ok(),
-        env::var("FORCE_EMPTY_CACHE").is_ok(),
-    )?;
+    let result = fetch_package(cache, package, options);
+
+    log.finished(&name, &url, start.elapsed(), result.as_ref().err());
+
+    match result {
+        Ok(bytes) => events.emit(&Event::Fetched {
+            name: &name,
+            url: &url,
+            bytes,
+            seconds: start.elapsed().as_secs_f64(),
+        }),
+        Err(err) => {
+            events.emit(&Event::Failed {
+                name: &name,
+                url: &url,
+                error: format!("{err:#}"),
+                seconds: start.elapsed().as_secs_f64(),
+            })?;
+
+            Err(err)
+        }
+    }
+}
 
-    let cache = Cache::new(out.join("_cacache"));
-    cache.init()?;
+/// Runs `fetch` on each of `packages` in parallel, giving up on the rest once `timeout` has passed.
+///
+/// Fetches that are already running when the timeout passes are left to finish.
+fn fetch_all(
+    packages: Vec<Package>,
+    timeout: Option<Duration>,
+    fetch: impl Fn(Package) -> anyhow::Result<()> + Sync + Send,
+) -> anyhow::Result<()> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let total = packages.len();
+    let fetched = AtomicUsize::new(0);
 
     packages.into_par_iter().try_for_each(|package| {
-        eprintln!("{}", package.name);
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            bail!(
+                "exceeded the total timeout of {}s, after fetching {} of {total} packages",
+                timeout.unwrap_or_default().as_secs_f64(),
+                fetched.load(Ordering::Relaxed)
+            );
+        }
 
-        let tarball = package
-            .tarball()
-            .map_err(|e| anyhow!("couldn't fetch {} at {}: {e:?}", package.name, package.url))?;
-        let integrity = package.integrity().map(ToString::to_string);
+        fetch(package)?;
+        fetched.fetch_add(1, Ordering::Relaxed);
 
-        cache
-            .put(
-                format!("make-fetch-happen:request-cache:{}", package.url),
-                package.url,
-                &tarball,
+        Ok(())
+    })
+}
+
+/// Writes a `CycloneDX` SBOM of `packages` (by key, version, and URL) to `path`, with the hashes they were cached with.
+fn write_sbom(
+    path: &Path,
+    cache: &Cache,
+    packages: Vec<(String, Option<String>, Url)>,
+) -> anyhow::Result<()> {
+    let components = packages
+        .into_iter()
+        .map(|(key, version, url)| {
+            let integrity = cache
+                .get(&cache_key(&url))?
+                .ok_or_else(|| anyhow!("{key} is missing from the cache"))?
+                .integrity;
+
+            Ok(sbom::Component {
+                key,
+                version,
+                url,
                 integrity,
-            )
-            .map_err(|e| anyhow!("couldn't insert cache entry for {}: {e:?}", package.name))?;
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-        Ok::<_, anyhow::Error>(())
-    })?;
+    fs::write(
+        path,
+        serde_json::to_string_pretty(&sbom::cyclonedx(&components)?)?,
+    )?;
+
+    Ok(())
+}
 
-    fs::write(out.join("package-lock.json"), lock_content)?;
+/// Writes the cache key of each dependency in `lock_content` to `path`, as a JSON object keyed by `resolved` URL.
+fn dump_keys(path: &Path, lock_content: &str, options: &parse::Options) -> anyhow::Result<()> {
+    let keys = parse::fetch_urls(lock_content, options)?
+        .into_iter()
+        .map(|(resolved, url)| (resolved, Value::String(cache_key(&url))))
+        .collect::<Map<_, _>>();
 
-    if print_hash {
-        Command::new("nix")
-            .args(["--experimental-features", "nix-command", "hash", "path"])
-            .arg(out.as_os_str())
-            .status()?;
-    }
+    fs::write(path, serde_json::to_string_pretty(&keys)? + "\n")?;
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+/// Returns the key that npm looks up the tarball at `url` under.
+fn cache_key(url: &Url) -> String {
+    format!(
+        "make-fetch-happen:request-cache:{}",
+        util::without_fragment(url)
+    )
+}
 
-    use super::fixup_lockfile;
-    use serde_json::json;
+/// Checks that `cache` has an intact entry for each of `packages`, stored with the integrity from the lockfile, returning
+/// a description of each problem found.
+fn verify_cache(cache: &Cache, packages: &[Package]) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
 
-    #[test]
-    fn lockfile_fixup() -> anyhow::Result<()> {
-        let input = json!({
-            "lockfileVersion": 2,
-            "name": "foo",
-            "packages": {
-                "": {
+    for package in packages {
+        if let Some(problem) = cache_problem(cache, package)? {
+            problems.push(format!("{} at {}: {problem}", package.name, package.url));
+        }
+    }
 
-                },
-                "foo": {
-                    "resolved": "https://github.com/NixOS/nixpkgs",
-                    "integrity": "sha1-aaa"
-                },
-                "bar": {
-                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
-                    "integrity": "sha512-aaa"
-                },
-                "foo-bad": {
-                    "resolved": "foo",
-                    "integrity": "sha1-foo"
-                },
-                "foo-good": {
-                    "resolved": "foo",
-                    "integrity": "sha512-foo"
-                },
-            }
-        });
+    Ok(problems)
+}
 
-        let expected = json!({
-            "lockfileVersion": 2,
-            "name": "foo",
-            "packages": {
-                "": {
+/// Returns what's wrong with the entry for `package` in `cache`, if anything. Packages without an integrity (i.e. Git
+/// dependencies) can't be checked, and are assumed to be fine.
+fn cache_problem(cache: &Cache, package: &Package) -> anyhow::Result<Option<String>> {
+    let Some(integrity) = package.integrity() else {
+        return Ok(None);
+    };
 
-                },
-                "foo": {
-                    "resolved": "https://github.com/NixOS/nixpkgs",
-                    "integrity": ""
-                },
-                "bar": {
-                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
-                },
-                "foo-bad": {
-                    "resolved": "foo",
-                    "integrity": "sha512-foo"
-                },
-                "foo-good": {
-                    "resolved": "foo",
-                    "integrity": "sha512-foo"
-                },
+    Ok(cached_content(cache, package, integrity.as_str())?.err())
+}
+
+/// Reads the content of the entry for `package` in `cache` if it's intact, i.e. stored with and matching `integrity`, or
+/// else returns what's wrong with it.
+fn cached_content(
+    cache: &Cache,
+    package: &Package,
+    integrity: &str,
+) -> anyhow::Result<Result<Vec<u8>, String>> {
+    Ok(match cache.get(&cache_key(&package.url))? {
+        None => Err(String::from("missing from the cache")),
+        Some(entry) if entry.integrity != integrity => Err(format!(
+            "cached with integrity {}, but the lockfile has {integrity}",
+            entry.integrity
+        )),
+        Some(entry) => match cache.content(&entry.integrity)? {
+            None => Err(String::from("cache entry has no content")),
+            Some(data) if !util::integrity_matches(integrity, &data)? => {
+                Err(String::from("cached content doesn't match its integrity"))
             }
-        });
+            Some(data) => Ok(data),
+        },
+    })
+}
 
-        let mut hashes = HashMap::new();
+/// Returns the name, URL and integrity of each of `packages` whose integrity is verified.
+fn expected_integrity(packages: &[Package]) -> Vec<(String, Url, String)> {
+    packages
+        .iter()
+        .filter(|package| util::should_verify_integrity(&package.url, config::get()))
+        .filter_map(|package| {
+            Some((
+                package.name.clone(),
+                package.url.clone(),
+                package.integrity()?.to_string(),
+            ))
+        })
+        .collect()
+}
 
-        hashes.insert(
-            String::from("https://github.com/NixOS/nixpkgs"),
-            String::new(),
-        );
+/// Checks the cached content of each of `expected` (as returned by [`expected_integrity`]) against its integrity, in
+/// parallel, failing with every mismatch at once.
+///
+/// Content that matches is moved from the digest it was cached under to its integrity, unless it was recompressed by
+/// the server (see [`checked_integrity`]).
+fn verify_fetched(cache: &Cache, expected: &[(String, Url, String)]) -> anyhow::Result<()> {
+    let matches = |url: &Url, integrity: &str| -> anyhow::Result<bool> {
+        let key = cache_key(url);
+        let Some(entry) = cache.get(&key)? else {
+            return Ok(false);
+        };
+        let Some(data) = cache.content(&entry.integrity)? else {
+            return Ok(false);
+        };
+
+        if util::integrity_matches(integrity, &data)? {
+            cache.forget(&key)?;
+            cache.put(
+                key,
+                entry.metadata.url,
+                &data,
+                Some(integrity.to_string()),
+                entry.metadata.resolved,
+            )?;
+
+            return Ok(true);
+        }
 
-        hashes.insert(
-            String::from("git+ssh://git@github.com/NixOS/nixpkgs.git"),
-            String::new(),
-        );
+        Ok(util::should_hash_decompressed(url, config::get())
+            && util::is_gzip(&data)
+            && util::decompressed_integrity_matches(integrity, &data)?)
+    };
 
-        hashes.insert(String::from("foo"), String::from("sha512-foo"));
+    let mut mismatches = expected
+        .par_iter()
+        .filter_map(|(name, url, integrity)| match matches(url, integrity) {
+            Ok(true) => None,
+            Ok(false) => Some(format!("{name} at {url}: expected {integrity}")),
+            Err(e) => Some(format!("{name} at {url}: {e:?}")),
+        })
+        .collect::<Vec<_>>();
 
-        assert_eq!(
-            fixup_lockfile(input.as_object().unwrap().clone(), &Some(hashes))?,
-            Some(expected.as_object().unwrap().clone())
-        );
+    if !mismatches.is_empty() {
+        mismatches.sort();
 
-        Ok(())
+        bail!("integrity mismatch for:\n{}", mismatches.join("\n"));
     }
 
-    #[test]
-    fn lockfile_v1_fixup() -> anyhow::Result<()> {
-        let input = json!({
-            "lockfileVersion": 1,
-            "name": "foo",
-            "dependencies": {
-                "foo": {
-                    "resolved": "https://github.com/NixOS/nixpkgs",
-                    "integrity": "sha512-aaa"
-                },
-                "foo-good": {
-                    "resolved": "foo",
-                    "integrity": "sha512-foo"
-                },
-                "bar": {
-                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
-                    "integrity": "sha512-bbb",
-                    "dependencies": {
-                        "foo-bad": {
-                            "resolved": "foo",
-                            "integrity": "sha1-foo"
-                        },
-                    },
-                },
-            }
-        });
+    // Drops the content left behind under the digests it was cached with.
+    cache.gc()?;
 
-        let expected = json!({
-            "lockfileVersion": 1,
-            "name": "foo",
-            "dependencies": {
-                "foo": {
-                    "resolved": "https://github.com/NixOS/nixpkgs",
-                    "integrity": ""
-                },
-                "foo-good": {
-                    "resolved": "foo",
-                    "integrity": "sha512-foo"
-                },
-                "bar": {
-                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
-                    "dependencies": {
-                        "foo-bad": {
-                            "resolved": "foo",
-                            "integrity": "sha512-foo"
-                        },
-                    },
-                },
-            }
-        });
+    Ok(())
+}
 
-        let mut hashes = HashMap::new();
+/// Copies the entry for `package` from `from` to `to` if `from` has an intact one, returning the size of its tarball.
+///
+/// Git dependencies are never copied, as they've already been fetched by the time the lockfile is parsed.
+fn copy_cached(from: &Cache, to: &Cache, package: &Package) -> anyhow::Result<Option<usize>> {
+    let Some(integrity) = package.integrity() else {
+        return Ok(None);
+    };
+    let Ok(data) = cached_content(from, package, integrity.as_str())? else {
+        return Ok(None);
+    };
 
-        hashes.insert(
-            String::from("https://github.com/NixOS/nixpkgs"),
-            String::new(),
-        );
+    to.put(
+        cache_key(&package.url),
+        package.url.clone(),
+        &data,
+        Some(integrity.to_string()),
+        package.original_url.clone(),
+    )
+    .map_err(|e| anyhow!("couldn't insert cache entry for {}: {e:?}", package.name))?;
 
-        hashes.insert(
-            String::from("git+ssh://git@github.com/NixOS/nixpkgs.git"),
-            String::new(),
-        );
+    Ok(Some(data.len()))
+}
 
-        hashes.insert(String::from("foo"), String::from("sha512-foo"));
+/// Drops the packages that `base` already has intact entries for, so that only the rest are fetched.
+///
+/// Git dependencies are always kept, as they've already been fetched by the time the lockfile is parsed.
+fn without_cached(base: &Cache, packages: Vec<Package>) -> anyhow::Result<Vec<Package>> {
+    let total = packages.len();
+    let mut missing = Vec::new();
+
+    for package in packages {
+        if package.integrity().is_none() || cache_problem(base, &package)?.is_some() {
+            missing.push(package);
+        }
+    }
 
-        assert_eq!(
-            fixup_lockfile(input.as_object().unwrap().clone(), &Some(hashes))?,
-            Some(expected.as_object().unwrap().clone())
+    info!(
+        "{} of {total} packages are already cached",
+        total - missing.len()
+    );
+
+    Ok(missing)
+}
+
+/// Drops the packages that the cache a previous run left in `out` already has intact entries for, if `incremental`.
+///
+/// The entries of the rest are forgotten, so that fetching them again replaces (rather than adds to) any stale ones.
+/// Without `incremental`, adding to such a cache is refused, as it may be from an unrelated lockfile.
+fn existing_cache(
+    out: &Path,
+    packages: Vec<Package>,
+    incremental: bool,
+) -> anyhow::Result<Vec<Package>> {
+    if !incremental {
+        bail!(
+            "{} already has a cache from a previous run; remove it, or pass --incremental to add to it",
+            out.display()
         );
+    }
+
+    let existing = Cache::new(out.join("_cacache"));
+    let missing = without_cached(&existing, packages)?;
+
+    for package in &missing {
+        existing.forget(&cache_key(&package.url))?;
+    }
+
+    Ok(missing)
+}
+
+/// Re-serializes a lockfile with sorted keys and consistent formatting, so that its contents (and thus the output hash)
+/// don't depend on how the input happened to be formatted.
+fn canonicalize_lockfile(content: &str) -> anyhow::Result<String> {
+    // Without serde_json's `preserve_order` feature, objects are backed by a `BTreeMap`, so keys come out sorted.
+    let lock: Value = serde_json::from_str(content)?;
+
+    Ok(serde_json::to_string_pretty(&lock)? + "\n")
+}
+
+/// Some broken mirrors gzip tarballs a second time. If enabled, and `tarball` is such a doubly-compressed tarball whose
+/// inner layer matches `integrity`, the inner layer is returned.
+fn unwrap_double_gzip(
+    tarball: &[u8],
+    integrity: &str,
+    options: &FetchOptions,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if !options.unwrap_double_gzip || !util::is_gzip(tarball) {
+        return Ok(None);
+    }
+
+    let inner = util::gunzip(tarball)?;
+
+    if util::is_gzip(&inner) && util::integrity_matches(integrity, &inner)? {
+        Ok(Some(inner))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Runs `hook` with the path to a temporary copy of `tarball` and the package's name.
+fn run_fetch_hook(hook: &Path, name: &str, tarball: &[u8]) -> anyhow::Result<()> {
+    let mut file = NamedTempFile::new()?;
+
+    file.write_all(tarball)?;
+    file.flush()?;
+
+    let status = Command::new(hook).arg(file.path()).arg(name).status()?;
+
+    if !status.success() {
+        bail!("fetch hook {hook:?} failed for {name} with {status}");
+    }
+
+    Ok(())
+}
+
+/// Returns the log filter to use, from `FETCH_NPM_DEPS_LOG` or `RUST_LOG` (in that order), defaulting to `info`.
+fn log_filter() -> String {
+    env::var("FETCH_NPM_DEPS_LOG")
+        .or_else(|_| env::var("RUST_LOG"))
+        .unwrap_or_else(|_| String::from("info"))
+}
+
+fn logger(filter: &str) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+
+    builder
+        .parse_filters(filter)
+        .format_timestamp(None)
+        .format_target(false);
+
+    builder
+}
+
+/// Removes `flag` from `args`, returning whether it was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    if let Some(i) = args.iter().position(|a| a == flag) {
+        args.remove(i);
+
+        true
+    } else {
+        false
+    }
+}
+
+/// Removes `option` and its value from `args`, returning the value if it was present.
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == option)?;
+
+    args.remove(i);
+
+    (i < args.len()).then(|| args.remove(i))
+}
+
+/// Options for the default mode, which prefetches the dependencies in a lockfile.
+#[derive(Default)]
+struct Options {
+    parse: parse::Options,
+    fetch: FetchOptions,
+    cache: CacheOptions,
+    lockfile: LockfileOptions,
+    report: ReportOptions,
+    /// How long the whole run may take before no more packages are fetched.
+    timeout_total: Option<Duration>,
+    /// Where to write a `CycloneDX` SBOM of the fetched packages to.
+    sbom: Option<PathBuf>,
+    /// Where to write a deterministic tar archive of the output cache to.
+    export_tar: Option<PathBuf>,
+    /// Where to write the cache key of each dependency to, by `resolved` URL.
+    dump_keys: Option<PathBuf>,
+    /// Where to write the hashes of the output directory and of its lockfile to, as JSON for Nix tooling.
+    emit_nix_json: Option<PathBuf>,
+    /// A `package.json` whose corepack-pinned package manager is fetched along with the lockfile's dependencies.
+    package_json: Option<PathBuf>,
+    /// Whether to warn about `overrides` in `package_json` that the lockfile has no entries for.
+    check_overrides: bool,
+}
+
+/// Options controlling how the output cache is written.
+#[derive(Default)]
+struct CacheOptions {
+    /// The hash algorithm to address cached content by, if not that of each package's integrity.
+    content_algorithm: Option<ContentAlgorithm>,
+    /// A cache whose intact entries aren't fetched again, so that only the missing ones are written to the output cache.
+    base_cache: Option<PathBuf>,
+    /// Whether to write the cache so that fetching into it again leaves it byte-for-byte the same.
+    reproducible: bool,
+    /// Whether to add to a cache that the output directory already has, rather than refusing to touch it.
+    incremental: bool,
+    /// Whether to write the cache's index in npm's own format, so that it can be merged into `~/.npm/_cacache`.
+    npm_index: bool,
+}
+
+/// Options controlling how the lockfile is copied into the output directory.
+struct LockfileOptions {
+    /// Whether to copy the lockfile into the output directory alongside the cache.
+    write: bool,
+    canonical: bool,
+    /// The name to write the lockfile to the output directory as, if not `package-lock.json`.
+    name: Option<String>,
+    /// Whether to check that the lockfile written to the output directory has the same dependencies as the one read.
+    self_check: bool,
+}
+
+impl FetchOptions {
+    fn from_args(args: &mut Vec<String>) -> anyhow::Result<FetchOptions> {
+        let options = FetchOptions {
+            validate_archives: take_flag(args, "--validate-archives"),
+            unwrap_double_gzip: take_flag(args, "--unwrap-double-gzip"),
+            on_fetch: take_option(args, "--on-fetch").map(PathBuf::from),
+            offline: take_option(args, "--prefer-offline").map(|path| Cache::new(path.into())),
+            verify_after: take_flag(args, "--verify-after"),
+        };
+
+        if options.verify_after && options.unwrap_double_gzip {
+            bail!("--verify-after can't be used with --unwrap-double-gzip, as tarballs are cached before they're checked");
+        }
+
+        if options.verify_after && options.on_fetch.is_some() {
+            bail!("--verify-after can't be used with --on-fetch, as hooks would be run on unverified tarballs");
+        }
+
+        Ok(options)
+    }
+}
+
+impl CacheOptions {
+    fn from_args(args: &mut Vec<String>) -> anyhow::Result<CacheOptions> {
+        Ok(CacheOptions {
+            content_algorithm: take_option(args, "--content-algorithm")
+                .map(|algorithm| algorithm.parse())
+                .transpose()?,
+            base_cache: take_option(args, "--base-cache").map(PathBuf::from),
+            reproducible: take_flag(args, "--reproducible"),
+            incremental: take_flag(args, "--incremental"),
+            npm_index: take_flag(args, "--npm-index"),
+        })
+    }
+}
+
+impl Default for LockfileOptions {
+    fn default() -> LockfileOptions {
+        LockfileOptions {
+            write: true,
+            canonical: false,
+            name: None,
+            self_check: false,
+        }
+    }
+}
+
+/// Options controlling what's reported about fetching, besides the packages being fetched.
+#[derive(Default)]
+struct ReportOptions {
+    /// Where to write NDJSON progress events to.
+    events: Option<PathBuf>,
+    /// Whether to log the packages being fetched all at once, sorted, rather than in whatever order they're fetched in.
+    sorted_logs: bool,
+    /// Whether to print how many requests were sent to each host after fetching.
+    list_hosts: bool,
+    /// The CI system to also report each package's fetch in the markup of.
+    ci_format: Option<CiFormat>,
+    /// How many of the slowest packages to fetch to report, with how long each took.
+    timings: Option<usize>,
+}
+
+impl ReportOptions {
+    fn from_args(args: &mut Vec<String>) -> anyhow::Result<ReportOptions> {
+        Ok(ReportOptions {
+            events: take_option(args, "--events").map(PathBuf::from),
+            sorted_logs: take_flag(args, "--sorted-logs"),
+            list_hosts: take_flag(args, "--list-hosts"),
+            ci_format: take_option(args, "--ci-format")
+                .map(|format| format.parse())
+                .transpose()?,
+            timings: take_option(args, "--timings")
+                .map(|n| n.parse())
+                .transpose()
+                .map_err(|e| anyhow!("invalid --timings: {e}"))?,
+        })
+    }
+}
+
+impl Options {
+    /// Takes the options from `args`, leaving only positional arguments behind.
+    fn from_args(args: &mut Vec<String>) -> anyhow::Result<Options> {
+        let platform = if take_flag(args, "--prune-platform") {
+            Some(match take_option(args, "--platform") {
+                Some(platform) => platform.parse()?,
+                None => parse::Platform::host(),
+            })
+        } else {
+            None
+        };
+
+        let legacy_sha1 = take_flag(args, "--legacy-sha1");
+
+        if legacy_sha1 {
+            util::use_minimal_headers();
+        }
+
+        let options = Options {
+            parse: parse::Options {
+                force_git_deps: env::var("FORCE_GIT_DEPS").is_ok(),
+                force_empty_cache: env::var("FORCE_EMPTY_CACHE").is_ok(),
+                platform,
+                require_resolved: take_flag(args, "--require-resolved"),
+                legacy_sha1,
+                integrity_overrides: take_option(args, "--integrity-overrides")
+                    .map(|path| {
+                        serde_json::from_slice(&fs::read(&path)?)
+                            .map_err(|e| anyhow!("invalid integrity overrides in {path}: {e}"))
+                    })
+                    .transpose()?
+                    .unwrap_or_default(),
+                registry: default_registry(|name| env::var(name).ok())?,
+                depth: take_option(args, "--depth")
+                    .map(|depth| depth.parse())
+                    .transpose()
+                    .map_err(|e| anyhow!("invalid --depth: {e}"))?,
+                exclude: exclusions(args)?,
+                strict_exclude: take_flag(args, "--strict-exclude"),
+                scope_registries: scope_registries()?,
+                check_origins: take_option(args, "--check-origins")
+                    .map(|check| check.parse())
+                    .transpose()?,
+                omit: omissions(args)?,
+                min_hash: take_option(args, "--min-hash")
+                    .map(|min| parse::HashPolicy::new(min, take_flag(args, "--min-hash-warn")))
+                    .transpose()
+                    .map_err(|e| anyhow!("invalid --min-hash: {e}"))?,
+                require_https_git: take_flag(args, "--require-https-git"),
+                verify_signatures: take_flag(args, "--verify-signatures"),
+                record_resolved: take_flag(args, "--record-resolved"),
+            },
+            fetch: FetchOptions::from_args(args)?,
+            cache: CacheOptions::from_args(args)?,
+            lockfile: LockfileOptions {
+                write: !take_flag(args, "--no-write-lockfile"),
+                canonical: take_flag(args, "--canonical-lockfile"),
+                name: None,
+                self_check: take_flag(args, "--self-check"),
+            },
+            report: ReportOptions::from_args(args)?,
+            timeout_total: take_option(args, "--timeout-total")
+                .map(|secs| secs.parse().map(Duration::from_secs))
+                .transpose()
+                .map_err(|e| anyhow!("invalid --timeout-total: {e}"))?,
+            sbom: take_option(args, "--sbom").map(PathBuf::from),
+            export_tar: take_option(args, "--export-tar").map(PathBuf::from),
+            dump_keys: take_option(args, "--dump-keys").map(PathBuf::from),
+            emit_nix_json: take_option(args, "--emit-nix-json").map(PathBuf::from),
+            package_json: take_option(args, "--package-json").map(PathBuf::from),
+            check_overrides: take_flag(args, "--check-overrides"),
+        };
+
+        if options.check_overrides && options.package_json.is_none() {
+            bail!("--check-overrides needs the package.json to check, passed with --package-json");
+        }
+
+        Ok(options)
+    }
+}
+
+/// Reads the registry that `npm:` shorthands and bare `resolved` paths are resolved against from the environment (as read
+/// by `var`), from `FETCH_NPM_DEPS_DEFAULT_REGISTRY`, or else npm's own `npm_config_registry`.
+fn default_registry(var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Option<Url>> {
+    ["FETCH_NPM_DEPS_DEFAULT_REGISTRY", "npm_config_registry"]
+        .into_iter()
+        .find_map(|name| {
+            var(name)
+                .filter(|registry| !registry.is_empty())
+                .map(|registry| Url::parse(&registry).map_err(|e| anyhow!("invalid {name}: {e}")))
+        })
+        .transpose()
+}
+
+/// Reads the registries for scoped packages from the environment, as npm would (`npm_config_@scope:registry`).
+fn scope_registries() -> anyhow::Result<HashMap<String, Url>> {
+    env::vars()
+        .filter_map(|(name, value)| {
+            let scope = name
+                .strip_prefix("npm_config_")?
+                .strip_suffix(":registry")?;
+
+            scope.starts_with('@').then(|| {
+                Url::parse(&value)
+                    .map(|registry| (scope.to_string(), registry))
+                    .map_err(|e| anyhow!("invalid {name}: {e}"))
+            })
+        })
+        .collect()
+}
+
+/// Takes the kinds of dependencies passed with `--omit` (which may be repeated).
+fn omissions(args: &mut Vec<String>) -> anyhow::Result<HashSet<parse::Omit>> {
+    let mut omit = HashSet::new();
+
+    while let Some(kind) = take_option(args, "--omit") {
+        omit.insert(kind.parse()?);
+    }
+
+    Ok(omit)
+}
+
+/// Takes the package names passed with `--exclude` (which may be repeated) and listed in `--exclude-file` (one per line).
+fn exclusions(args: &mut Vec<String>) -> anyhow::Result<HashSet<String>> {
+    let mut exclude = HashSet::new();
+
+    while let Some(name) = take_option(args, "--exclude") {
+        exclude.insert(name);
+    }
+
+    if let Some(path) = take_option(args, "--exclude-file") {
+        exclude.extend(
+            fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
+    }
+
+    Ok(exclude)
+}
+
+/// Takes the limits on requests to each host from `args`.
+fn request_settings(args: &mut Vec<String>) -> anyhow::Result<()> {
+    if let Some(max) = take_option(args, "--max-attempts-per-host") {
+        util::set_max_failures_per_host(
+            max.parse()
+                .map_err(|e| anyhow!("invalid --max-attempts-per-host: {e}"))?,
+        );
+    }
+
+    if take_flag(args, "--trace-http") {
+        util::trace_http();
+    }
+
+    let mut rewrites = Vec::new();
+    while let Some(rule) = take_option(args, "--rewrite") {
+        rewrites.push(rule.parse()?);
+    }
+    util::set_url_rewrites(rewrites);
+
+    if let Some(jobs) = take_option(args, "--parallel-hosts") {
+        util::set_per_host_jobs(
+            jobs.parse()
+                .map_err(|e| anyhow!("invalid --parallel-hosts: {e}"))?,
+        );
+    }
+
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    logger(&log_filter()).init();
+
+    let mut args = env::args().collect::<Vec<_>>();
+
+    config::init(config::Config::load(
+        take_option(&mut args, "--config").as_deref().map(Path::new),
+    )?);
+
+    request_settings(&mut args)?;
+
+    let mut options = Options::from_args(&mut args)?;
+    let verify_only = take_option(&mut args, "--verify-only");
+    let refetch_algorithm = take_option(&mut args, "--refetch-where-hash");
+    let from_git = take_option(&mut args, "--from-git");
+    let keep_lockfile_name = take_flag(&mut args, "--keep-lockfile-name");
+    let graph = take_option(&mut args, "--graph");
+
+    if args.len() < 2 && from_git.is_none() {
+        println!("usage: {} <path/to/package-lock.json>", args[0]);
+        println!();
+        println!("Prefetches npm dependencies for usage by fetchNpmDeps.");
+
+        process::exit(1);
+    }
+
+    if let Ok(jobs) = env::var("NIX_BUILD_CORES") {
+        if !jobs.is_empty() {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(
+                    jobs.parse()
+                        .expect("NIX_BUILD_CORES must be a whole number"),
+                )
+                .build_global()
+                .unwrap();
+        }
+    }
+
+    if keep_lockfile_name {
+        options.lockfile.name = lockfile_name(
+            from_git.as_deref(),
+            args.get(1).map(String::as_str).unwrap_or_default(),
+        );
+    }
+
+    if let Some(spec) = from_git {
+        return prefetch(
+            read_git_lockfile(&spec)?,
+            args.get(1).map(Path::new),
+            &options,
+        );
+    }
+
+    if args[1] == "--fixup-lockfile" {
+        let lock = serde_json::from_str(&read_lockfile(Path::new(&args[2]))?)?;
+
+        let cache = cache_map_path()
+            .map(|map_path| Ok::<_, anyhow::Error>(serde_json::from_slice(&fs::read(map_path)?)?))
+            .transpose()?;
+
+        if let Some(fixed) = fixup_lockfile(lock, cache.as_ref())? {
+            info!("Fixing lockfile");
+
+            fs::write(&args[2], serde_json::to_string(&fixed)?)?;
+        }
+
+        return Ok(());
+    } else if args[1] == "--report-unsupported" {
+        return report_unsupported(&read_lockfile(Path::new(&args[2]))?, &options.parse);
+    } else if let Some(format) = graph {
+        print!(
+            "{}",
+            parse::graph(&read_lockfile(Path::new(&args[1]))?, format.parse()?)?
+        );
+
+        return Ok(());
+    } else if args[1] == "--dedupe-report" {
+        return dedupe_report(&read_lockfile(Path::new(&args[2]))?, &options.parse);
+    } else if args[1] == "--hash-dir" {
+        for line in hash_dir(Path::new(&args[2]))? {
+            println!("{line}");
+        }
+
+        return Ok(());
+    } else if args[1] == "--gc" {
+        let reclaimed = Cache::new(PathBuf::from(&args[2])).gc()?;
+
+        info!("Reclaimed {reclaimed} bytes");
+
+        return Ok(());
+    } else if let Some(cache_dir) = verify_only {
+        return verify(Path::new(&cache_dir), &args[1], &options);
+    } else if let Some(algorithm) = refetch_algorithm {
+        let lock_content = read_lockfile(Path::new(&args[1]))?;
+
+        return refetch_where_hash(
+            Path::new(&args[2]),
+            &lock_content,
+            &algorithm,
+            &options.parse,
+        )
+        .map(drop);
+    } else if args[1] == "--map-cache" {
+        let map = map_cache()?;
+
+        fs::write(
+            cache_map_path().expect("CACHE_MAP_PATH environment variable must be set"),
+            serde_json::to_string(&map)?,
+        )?;
+
+        return Ok(());
+    }
+
+    prefetch(
+        read_lockfile(Path::new(&args[1]))?,
+        args.get(2).map(Path::new),
+        &options,
+    )
+}
+
+/// Reads the lockfile at `path`, without the byte order mark that some Windows tools start it with.
+fn read_lockfile(path: &Path) -> anyhow::Result<String> {
+    decode_lockfile(&path.display(), fs::read(path)?)
+}
+
+/// Decodes the lockfile `content` read from `source` (see [`read_lockfile`]).
+fn decode_lockfile(source: &impl fmt::Display, content: Vec<u8>) -> anyhow::Result<String> {
+    let content = String::from_utf8(content).map_err(|e| {
+        anyhow!(
+            "{source} isn't valid UTF-8 (from byte {}), which lockfiles must be",
+            e.utf8_error().valid_up_to()
+        )
+    })?;
+
+    Ok(match content.strip_prefix('\u{feff}') {
+        Some(content) => content.to_string(),
+        None => content,
+    })
+}
+
+/// Prints how many dependencies in `lock_content` are fetched from each scheme and host, and whether they're supported.
+fn report_unsupported(lock_content: &str, options: &parse::Options) -> anyhow::Result<()> {
+    for (source, count) in &parse::sources(lock_content, options)? {
+        println!(
+            "{count}\t{}://{}\t{}",
+            source.scheme,
+            source.host,
+            if source.supported {
+                "supported"
+            } else {
+                "unsupported"
+            }
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints how many dependencies in `lock_content` share each `resolved` URL with others, and how many downloads (and,
+/// when `FETCH_NPM_DEPS_MAX_SIZE` enables `Content-Length` preflights, bytes) fetching each URL only once saves.
+fn dedupe_report(lock_content: &str, options: &parse::Options) -> anyhow::Result<()> {
+    let duplicates = parse::duplicates(lock_content, options)?;
+    let (mut saved, mut saved_bytes) = (0, 0);
+
+    for (url, names) in &duplicates {
+        let redundant = names.len() - 1;
+
+        println!("{}\t{url}\t{}", names.len(), names.join(" "));
+
+        saved += redundant;
+
+        if config::get().max_size.is_some() {
+            if let Some(len) = Url::parse(url)
+                .ok()
+                .and_then(|url| util::content_length(&url).ok().flatten())
+            {
+                saved_bytes += len * redundant as u64;
+            }
+        }
+    }
+
+    let bytes = if config::get().max_size.is_some() {
+        format!(" ({saved_bytes} bytes)")
+    } else {
+        String::new()
+    };
+
+    println!(
+        "{} dependencies share {} URLs, so fetching each URL once saves {saved} downloads{bytes}",
+        duplicates.values().map(Vec::len).sum::<usize>(),
+        duplicates.len()
+    );
+
+    Ok(())
+}
+
+/// Checks that the cache at `cache_dir` has an intact entry for every registry dependency in the lockfile at `lockfile`.
+fn verify(cache_dir: &Path, lockfile: &str, options: &Options) -> anyhow::Result<()> {
+    let packages = parse::registry_lockfile(&read_lockfile(Path::new(lockfile))?, &options.parse)?;
+    let problems = verify_cache(&Cache::new(cache_dir.to_path_buf()), &packages)?;
+
+    for problem in &problems {
+        error!("{problem}");
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "{} of {} dependencies failed verification",
+            problems.len(),
+            packages.len()
+        );
+    }
+
+    info!("Verified {} dependencies", packages.len());
+
+    Ok(())
+}
+
+/// Re-fetches the dependencies in `lock_content` that the cache at `cache_dir` has entries with `algorithm` integrity for,
+/// and caches them again with sha512 integrity (once they're checked against their old integrity), returning how many
+/// were re-fetched.
+///
+/// Entries with other integrity are left as they are, without fetching anything for them.
+fn refetch_where_hash(
+    cache_dir: &Path,
+    lock_content: &str,
+    algorithm: &str,
+    options: &parse::Options,
+) -> anyhow::Result<usize> {
+    let cache = Cache::new(cache_dir.to_path_buf());
+    let mut stale = Vec::new();
+
+    for package in parse::registry_lockfile(lock_content, options)? {
+        if let Some(entry) = cache.get(&cache_key(&package.url))? {
+            if entry.integrity.starts_with(&format!("{algorithm}-")) {
+                stale.push((package, entry.integrity));
+            }
+        }
+    }
+
+    let count = stale.len();
+
+    stale.into_par_iter().try_for_each(|(package, integrity)| {
+        let tarball = package
+            .tarball()
+            .map_err(|e| anyhow!("couldn't fetch {} at {}: {e:?}", package.name, package.url))?;
+
+        if !util::integrity_matches(&integrity, &tarball)? {
+            bail!(
+                "{} at {} no longer matches its cached integrity {integrity}",
+                package.name,
+                package.url
+            );
+        }
+
+        // Indexed afresh, rather than alongside the old entry, as when first fetched.
+        cache.forget(&cache_key(&package.url))?;
+        cache
+            .put(
+                cache_key(&package.url),
+                package.url.clone(),
+                &tarball,
+                None,
+                package.original_url.clone(),
+            )
+            .map_err(|e| anyhow!("couldn't insert cache entry for {}: {e:?}", package.name))
+    })?;
+
+    info!("Re-fetched {count} dependencies cached with {algorithm} integrity");
+
+    Ok(count)
+}
+
+/// Returns the file name of the lockfile being read, either from a `--from-git` spec or the path to it.
+fn lockfile_name(from_git: Option<&str>, path: &str) -> Option<String> {
+    let path = match from_git {
+        Some(spec) => spec.rsplit_once(':').map_or(spec, |(_, path)| path),
+        None => path,
+    };
+
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Reads a lockfile from a local git repository without checking it out, as specified by `<repo>#<ref>:<path>`.
+fn read_git_lockfile(spec: &str) -> anyhow::Result<String> {
+    let (repo, object) = spec
+        .rsplit_once('#')
+        .filter(|(_, object)| object.contains(':'))
+        .ok_or_else(|| anyhow!("expected a lockfile like `<repo>#<ref>:<path>`, got {spec:?}"))?;
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["show", object])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "couldn't read {object} from {repo}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    decode_lockfile(&spec, output.stdout)
+}
+
+/// Adds the package manager pinned by the `package.json` at `path` to `packages`, and (with `--check-overrides`) warns about
+/// its `overrides` that `lock_content` doesn't reflect.
+fn read_package_json(
+    path: &Path,
+    lock_content: &str,
+    options: &Options,
+    packages: &mut Vec<Package>,
+) -> anyhow::Result<()> {
+    let package_json = fs::read_to_string(path)?;
+
+    if options.check_overrides {
+        for warning in parse::missing_overrides(lock_content, &package_json, &options.parse)? {
+            warn!("{warning}");
+        }
+    }
+
+    let package_manager = parse::package_manager(&package_json, &options.parse)
+        .with_context(|| format!("failed to read the package manager from {}", path.display()))?;
+
+    packages.extend(package_manager.filter(|pm| packages.iter().all(|p| p.url != pm.url)));
+
+    Ok(())
+}
+
+/// Prefetches the dependencies in `lock_content` into `out`, or into a temporary directory whose hash is printed if
+/// `out` isn't given.
+fn prefetch(lock_content: String, out: Option<&Path>, options: &Options) -> anyhow::Result<()> {
+    prefetch_in(lock_content, out, &util::temp_root(), options)
+}
+
+/// Like [`prefetch`], but creates the temporary directory (if `out` isn't given) under `temp_root`.
+fn prefetch_in(
+    lock_content: String,
+    out: Option<&Path>,
+    temp_root: &Path,
+    options: &Options,
+) -> anyhow::Result<()> {
+    let out_tempdir;
+
+    let (out, print_hash) = if let Some(path) = out {
+        (path, false)
+    } else {
+        out_tempdir = util::tempdir_in(temp_root)?;
+
+        (out_tempdir.path(), true)
+    };
+
+    let out = canonical_out_dir(out)?;
+
+    // Written before anything is fetched, so that it's available for debugging even if fetching fails.
+    if let Some(path) = &options.dump_keys {
+        dump_keys(path, &lock_content, &options.parse)?;
+    }
+
+    let mut packages = parse::lockfile(&lock_content, &options.parse)?;
+
+    if let Some(path) = &options.package_json {
+        read_package_json(path, &lock_content, options, &mut packages)?;
+    }
+
+    if let Some(base) = &options.cache.base_cache {
+        packages = without_cached(&Cache::new(base.clone()), packages)?;
+    }
+
+    if !print_hash && out.join("_cacache").exists() {
+        packages = existing_cache(&out, packages, options.cache.incremental)?;
+    }
+
+    let mut cache = Cache::new(out.join("_cacache"));
+
+    if let Some(algorithm) = options.cache.content_algorithm {
+        cache = cache.with_content_algorithm(algorithm);
+    }
+
+    if options.cache.reproducible {
+        cache = cache.reproducible();
+    }
+
+    if options.cache.npm_index {
+        cache = cache.with_npm_index();
+    }
+
+    cache.init()?;
+
+    let events = Events::open(options.report.events.as_deref())?;
+    let count = packages.len();
+    let components = options.sbom.as_ref().map(|_| {
+        packages
+            .iter()
+            .map(|p| (p.name.clone(), p.version.clone(), p.url.clone()))
+            .collect::<Vec<_>>()
+    });
+
+    // Taken before fetching, which consumes the packages.
+    let expected = options
+        .fetch
+        .verify_after
+        .then(|| expected_integrity(&packages));
+
+    let log = FetchLog::new(options.report.sorted_logs)
+        .with_ci_format(options.report.ci_format)
+        .with_timings(options.report.timings.is_some());
+    let fetched = fetch_all(packages, options.timeout_total, |package| {
+        fetch_package_with_events(&cache, package, &options.fetch, &events, &log)
+    });
+
+    // Printed even if fetching failed, as the slowest packages may be why it timed out.
+    if let Some(n) = options.report.timings {
+        for (name, elapsed) in log.slowest(n) {
+            info!("{:.3}s\t{name}", elapsed.as_secs_f64());
+        }
+    }
+
+    for line in log.into_sorted() {
+        info!("{line}");
+    }
+
+    // Printed even if fetching failed, as knowing which hosts were contacted helps with allowlisting them.
+    if options.report.list_hosts {
+        for (host, requests) in util::host_requests() {
+            info!("{requests}\t{host}");
+        }
+    }
+
+    fetched?;
+
+    if let Some(expected) = &expected {
+        verify_fetched(&cache, expected)?;
+    }
+
+    events.emit(&Event::Done {
+        packages: count,
+        seconds: events.elapsed().as_secs_f64(),
+    })?;
+
+    if let (Some(path), Some(components)) = (&options.sbom, components) {
+        write_sbom(path, &cache, components)?;
+    }
+
+    if let Some(path) = &options.export_tar {
+        cache.export_tar(path)?;
+    }
+
+    let lock_content = write_lockfile(&out, lock_content, options)?;
+
+    if print_hash || options.emit_nix_json.is_some() {
+        let hash = nar::hash_path(&out)?;
+
+        if let Some(path) = &options.emit_nix_json {
+            write_nix_json(path, &lock_content, &hash)?;
+        }
+
+        if print_hash {
+            println!("{hash}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the lockfile to `out` (unless disabled), canonicalizing it if requested, and returns its written content.
+fn write_lockfile(out: &Path, lock_content: String, options: &Options) -> anyhow::Result<String> {
+    let expected = if options.lockfile.self_check {
+        Some(parse::fetch_urls(&lock_content, &options.parse)?)
+    } else {
+        None
+    };
+
+    let lock_content = if options.lockfile.canonical {
+        canonicalize_lockfile(&lock_content)?
+    } else {
+        lock_content
+    };
+
+    if !options.lockfile.write {
+        info!("Not writing the lockfile to the output directory");
+
+        return Ok(lock_content);
+    }
+
+    let path = out.join(
+        options
+            .lockfile
+            .name
+            .as_deref()
+            .unwrap_or("package-lock.json"),
+    );
+
+    fs::write(&path, &lock_content)?;
+
+    if let Some(expected) = expected {
+        self_check(&path, &expected, &options.parse)?;
+    }
+
+    Ok(lock_content)
+}
+
+/// Checks that the lockfile written to `path` parses back to the `expected` dependencies, i.e. that writing it didn't
+/// corrupt it.
+fn self_check(
+    path: &Path,
+    expected: &BTreeMap<String, Url>,
+    options: &parse::Options,
+) -> anyhow::Result<()> {
+    let written = parse::fetch_urls(&fs::read_to_string(path)?, options)
+        .map_err(|e| anyhow!("the written lockfile {} doesn't parse: {e}", path.display()))?;
+
+    let missing = expected.keys().filter(|url| !written.contains_key(*url));
+    let added = written.keys().filter(|url| !expected.contains_key(*url));
+    let changed = expected
+        .iter()
+        .filter(|(url, fetched)| written.get(*url).is_some_and(|w| w != *fetched))
+        .map(|(url, _)| url);
+    let differences = missing
+        .map(|url| format!("missing {url}"))
+        .chain(added.map(|url| format!("added {url}")))
+        .chain(changed.map(|url| format!("changed {url}")))
+        .collect::<Vec<_>>();
+
+    if !differences.is_empty() {
+        bail!(
+            "the written lockfile {} doesn't match the one that was read: {}",
+            path.display(),
+            differences.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Writes the hash of the output directory, and the SRI sha256 hash of the lockfile, to `path`.
+fn write_nix_json(path: &Path, lock_content: &str, cache_hash: &str) -> anyhow::Result<()> {
+    let lockfile_hash = format!(
+        "sha256-{}",
+        BASE64_STANDARD.encode(Sha256::digest(lock_content.as_bytes()))
+    );
+
+    fs::write(
+        path,
+        serde_json::to_string_pretty(&json!({
+            "lockfileHash": lockfile_hash,
+            "cacheHash": cache_hash,
+        }))?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::Path, thread, time::Duration};
+
+    use super::{
+        cache_key, canonical_out_dir, canonicalize_lockfile, checked_integrity, default_registry,
+        dump_keys, fetch_all, fixup_lockfile, hash_dir, lockfile_name, logger, nar, prefetch,
+        prefetch_in, read_git_lockfile, read_lockfile, refetch_where_hash, run_fetch_hook,
+        self_check, unwrap_double_gzip, verify_cache, CacheOptions, FetchOptions, LockfileOptions,
+        Options, ReportOptions,
+    };
+    use crate::{
+        cacache::{Cache, ContentAlgorithm},
+        config, parse,
+        util::test_server::{response, Server},
+    };
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use digest::{Digest, Update};
+    use log::LevelFilter;
+    use serde_json::{json, Value};
+    use sha2::{Sha256, Sha512};
+    use tempfile::tempdir;
+    use url::Url;
+
+    #[test]
+    fn logger_filters() {
+        assert_eq!(logger("info").build().filter(), LevelFilter::Info);
+        assert_eq!(logger("warn").build().filter(), LevelFilter::Warn);
+        assert_eq!(
+            logger("prefetch_npm_deps=debug").build().filter(),
+            LevelFilter::Debug
+        );
+    }
+
+    #[test]
+    fn lockfile_fixup() -> anyhow::Result<()> {
+        let input = json!({
+            "lockfileVersion": 2,
+            "name": "foo",
+            "packages": {
+                "": {
+
+                },
+                "foo": {
+                    "resolved": "https://github.com/NixOS/nixpkgs",
+                    "integrity": "sha1-aaa"
+                },
+                "bar": {
+                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
+                    "integrity": "sha512-aaa"
+                },
+                "foo-bad": {
+                    "resolved": "foo",
+                    "integrity": "sha1-foo"
+                },
+                "foo-good": {
+                    "resolved": "foo",
+                    "integrity": "sha512-foo"
+                },
+            }
+        });
+
+        let expected = json!({
+            "lockfileVersion": 2,
+            "name": "foo",
+            "packages": {
+                "": {
+
+                },
+                "foo": {
+                    "resolved": "https://github.com/NixOS/nixpkgs",
+                    "integrity": ""
+                },
+                "bar": {
+                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
+                },
+                "foo-bad": {
+                    "resolved": "foo",
+                    "integrity": "sha512-foo"
+                },
+                "foo-good": {
+                    "resolved": "foo",
+                    "integrity": "sha512-foo"
+                },
+            }
+        });
+
+        let mut hashes = HashMap::new();
+
+        hashes.insert(
+            String::from("https://github.com/NixOS/nixpkgs"),
+            String::new(),
+        );
+
+        hashes.insert(
+            String::from("git+ssh://git@github.com/NixOS/nixpkgs.git"),
+            String::new(),
+        );
+
+        hashes.insert(String::from("foo"), String::from("sha512-foo"));
+
+        assert_eq!(
+            fixup_lockfile(input.as_object().unwrap().clone(), Some(&hashes))?,
+            Some(expected.as_object().unwrap().clone())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lockfile_v1_fixup() -> anyhow::Result<()> {
+        let input = json!({
+            "lockfileVersion": 1,
+            "name": "foo",
+            "dependencies": {
+                "foo": {
+                    "resolved": "https://github.com/NixOS/nixpkgs",
+                    "integrity": "sha512-aaa"
+                },
+                "foo-good": {
+                    "resolved": "foo",
+                    "integrity": "sha512-foo"
+                },
+                "bar": {
+                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
+                    "integrity": "sha512-bbb",
+                    "dependencies": {
+                        "foo-bad": {
+                            "resolved": "foo",
+                            "integrity": "sha1-foo"
+                        },
+                    },
+                },
+            }
+        });
+
+        let expected = json!({
+            "lockfileVersion": 1,
+            "name": "foo",
+            "dependencies": {
+                "foo": {
+                    "resolved": "https://github.com/NixOS/nixpkgs",
+                    "integrity": ""
+                },
+                "foo-good": {
+                    "resolved": "foo",
+                    "integrity": "sha512-foo"
+                },
+                "bar": {
+                    "resolved": "git+ssh://git@github.com/NixOS/nixpkgs.git",
+                    "dependencies": {
+                        "foo-bad": {
+                            "resolved": "foo",
+                            "integrity": "sha512-foo"
+                        },
+                    },
+                },
+            }
+        });
+
+        let mut hashes = HashMap::new();
+
+        hashes.insert(
+            String::from("https://github.com/NixOS/nixpkgs"),
+            String::new(),
+        );
+
+        hashes.insert(
+            String::from("git+ssh://git@github.com/NixOS/nixpkgs.git"),
+            String::new(),
+        );
+
+        hashes.insert(String::from("foo"), String::from("sha512-foo"));
+
+        assert_eq!(
+            fixup_lockfile(input.as_object().unwrap().clone(), Some(&hashes))?,
+            Some(expected.as_object().unwrap().clone())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinked_out_dir() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let real = dir.path().join("real");
+        let link = dir.path().join("link");
+
+        fs::create_dir(&real)?;
+        std::os::unix::fs::symlink(&real, &link)?;
+
+        let out = canonical_out_dir(&link)?;
+
+        assert_eq!(out, fs::canonicalize(&real)?);
+
+        Cache::new(out.join("_cacache")).init()?;
+
+        assert!(real.join("_cacache/content-v2").is_dir());
+        assert!(real.join("_cacache/index-v5").is_dir());
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_out_dir_is_created() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let out = canonical_out_dir(&dir.path().join("does/not/exist"))?;
+
+        assert!(out.is_dir());
+        assert!(out.is_absolute());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fetch_hook() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir()?;
+        let hook = dir.path().join("hook.sh");
+        let log = dir.path().join("log");
+
+        fs::write(
+            &hook,
+            format!("#!/bin/sh\necho \"$2 $(cat \"$1\")\" > {log:?}\n"),
+        )?;
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+
+        run_fetch_hook(&hook, "foo", b"tarball")?;
+
+        assert_eq!(fs::read_to_string(&log)?, "foo tarball\n");
+
+        fs::write(&hook, "#!/bin/sh\nexit 1\n")?;
+
+        assert!(run_fetch_hook(&hook, "foo", b"tarball").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_lockfile() -> anyhow::Result<()> {
+        let a = canonicalize_lockfile(
+            r#"{"lockfileVersion": 3, "name": "foo", "packages": {"": {}, "node_modules/bar": {"version": "1.0.0", "resolved": "https://example.com/bar.tgz"}}}"#,
+        )?;
+        let b = canonicalize_lockfile(
+            r#"{
+              "packages": {
+                "node_modules/bar": {
+                  "resolved": "https://example.com/bar.tgz",
+                  "version": "1.0.0"
+                },
+                "": {}
+              },
+              "name": "foo",
+              "lockfileVersion": 3
+            }"#,
+        )?;
+
+        assert_eq!(a, b);
+        assert!(a.starts_with("{\n  \"lockfileVersion\": 3,"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn double_gzip() -> anyhow::Result<()> {
+        let gzip = |data: &[u8]| -> anyhow::Result<Vec<u8>> {
+            let dir = tempdir()?;
+            let path = dir.path().join("data");
+
+            fs::write(&path, data)?;
+
+            Ok(std::process::Command::new("gzip")
+                .args(["-n", "-c"])
+                .arg(path)
+                .output()?
+                .stdout)
+        };
+
+        let tarball = gzip(b"package")?;
+        let doubled = gzip(&tarball)?;
+        let integrity = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(&tarball).finalize())
+        );
+
+        let enabled = FetchOptions {
+            unwrap_double_gzip: true,
+            ..FetchOptions::default()
+        };
+
+        assert_eq!(
+            unwrap_double_gzip(&doubled, &integrity, &enabled)?,
+            Some(tarball.clone())
+        );
+        assert_eq!(
+            unwrap_double_gzip(&doubled, &integrity, &FetchOptions::default())?,
+            None
+        );
+        assert_eq!(
+            unwrap_double_gzip(&tarball, &integrity, &enabled)?,
+            None,
+            "singly-compressed tarballs shouldn't be unwrapped"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn recompressed_integrity() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("package.tar");
+
+        fs::write(&path, b"package")?;
+
+        let tarball = std::process::Command::new("gzip")
+            .args(["-n", "-c", "-1"])
+            .arg(&path)
+            .output()?
+            .stdout;
+        let integrity = ContentAlgorithm::Sha512.sri(b"package");
+        let package = |resolved: &str| -> anyhow::Result<_> {
+            Ok(parse::registry_lockfile(
+                &json!({
+                    "lockfileVersion": 3,
+                    "packages": {
+                        "node_modules/foo": { "resolved": resolved, "integrity": integrity }
+                    }
+                })
+                .to_string(),
+                &parse::Options::default(),
+            )?
+            .remove(0))
+        };
+        let recompressing = config::Config {
+            decompressed_hash_hosts: vec![String::from("registry.example.com")],
+            ..config::Config::default()
+        };
+        let registry = package("https://registry.example.com/foo/-/foo-1.0.0.tgz")?;
+
+        assert_eq!(
+            checked_integrity(
+                &registry,
+                &mut tarball.clone(),
+                &FetchOptions::default(),
+                &recompressing
+            )?,
+            None,
+            "recompressed tarballs should be cached under their own digest"
+        );
+        assert!(checked_integrity(
+            &registry,
+            &mut tarball.clone(),
+            &FetchOptions::default(),
+            &config::Config::default()
+        )
+        .is_err());
+        assert_eq!(
+            checked_integrity(
+                &package("https://codeload.github.com/foo/foo/tar.gz/abc")?,
+                &mut tarball.clone(),
+                &FetchOptions::default(),
+                &config::Config::default()
+            )?,
+            Some(integrity),
+            "unverified hosts shouldn't be checked"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_verification() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let integrity = |data: &[u8]| {
+            format!(
+                "sha512-{}",
+                BASE64_STANDARD.encode(Sha512::new().chain(data).finalize())
+            )
+        };
+
+        let registry = Url::parse("https://registry.npmjs.org/")?;
+        let url = |name: &str| registry.join(&format!("{name}/-/{name}-1.0.0.tgz"));
+
+        cache.init()?;
+        cache.put(
+            cache_key(&url("good")?),
+            url("good")?,
+            b"good",
+            Some(integrity(b"good")),
+            None,
+        )?;
+        cache.put(
+            cache_key(&url("stale")?),
+            url("stale")?,
+            b"old",
+            Some(integrity(b"old")),
+            None,
+        )?;
+
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/good": {
+                    "resolved": url("good")?,
+                    "integrity": integrity(b"good"),
+                },
+                "node_modules/stale": {
+                    "resolved": url("stale")?,
+                    "integrity": integrity(b"new"),
+                },
+                "node_modules/missing": {
+                    "resolved": url("missing")?,
+                    "integrity": integrity(b"missing"),
+                },
+            }
+        });
+
+        let packages = parse::registry_lockfile(&lockfile.to_string(), &parse::Options::default())?;
+        let problems = verify_cache(&cache, &packages)?;
+
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].starts_with("node_modules/missing at"));
+        assert!(problems[0].ends_with("missing from the cache"));
+        assert!(problems[1].starts_with("node_modules/stale at"));
+        assert!(problems[1].contains(&format!("cached with integrity {}", integrity(b"old"))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_verification_checks_content() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let url = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz")?;
+
+        cache.init()?;
+        cache.put(cache_key(&url), url.clone(), b"foo", None, None)?;
+
+        let integrity = cache.get(&cache_key(&url))?.unwrap().integrity;
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/foo": { "resolved": url, "integrity": integrity }
+            }
+        })
+        .to_string();
+        let packages = parse::registry_lockfile(&lockfile, &parse::Options::default())?;
+
+        assert!(verify_cache(&cache, &packages)?.is_empty());
+
+        for entry in walkdir::WalkDir::new(dir.path().join("_cacache/content-v2")) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                fs::write(entry.path(), b"corrupted")?;
+            }
+        }
+
+        assert_eq!(
+            verify_cache(&cache, &packages)?,
+            vec![format!(
+                "node_modules/foo at {url}: cached content doesn't match its integrity"
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn byte_order_mark() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"tarball")]);
+        let integrity = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(b"tarball").finalize())
+        );
+
+        let dir = tempdir()?;
+        let lockfile = dir.path().join("package-lock.json");
+        let out = dir.path().join("out");
+
+        fs::write(
+            &lockfile,
+            format!(
+                "\u{feff}{}",
+                json!({
+                    "lockfileVersion": 3,
+                    "packages": {
+                        "node_modules/foo": {
+                            "resolved": server.url.join("foo/-/foo-1.0.0.tgz")?,
+                            "integrity": integrity,
+                        },
+                    }
+                })
+            ),
+        )?;
+
+        prefetch(read_lockfile(&lockfile)?, Some(&out), &Options::default())?;
+
+        let written = fs::read(out.join("package-lock.json"))?;
+
+        assert!(written.starts_with(b"{"), "the BOM should be stripped");
+        assert_eq!(written, &fs::read(&lockfile)?[3..]);
+
+        fs::write(&lockfile, b"{\"lockfileVersion\": \xff}")?;
+
+        let err = read_lockfile(&lockfile).unwrap_err().to_string();
+
+        assert!(
+            err.ends_with("isn't valid UTF-8 (from byte 20), which lockfiles must be"),
+            "{err}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn refetch_sha1_entries() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"old")]);
+        let dir = tempdir()?;
+        let cache = Cache::new(dir.path().join("_cacache"));
+        let (old, new) = (
+            server.url.join("old/-/old-1.0.0.tgz")?,
+            server.url.join("new/-/new-1.0.0.tgz")?,
+        );
+        let sha1 = format!(
+            "sha1-{}",
+            BASE64_STANDARD.encode(sha1::Sha1::new().chain(b"old").finalize())
+        );
+        let sha512 = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(b"new").finalize())
+        );
+
+        cache.init()?;
+        cache.put(
+            cache_key(&old),
+            old.clone(),
+            b"old",
+            Some(sha1.clone()),
+            None,
+        )?;
+        cache.put(
+            cache_key(&new),
+            new.clone(),
+            b"new",
+            Some(sha512.clone()),
+            None,
+        )?;
+
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/old": { "resolved": old, "integrity": sha1 },
+                "node_modules/new": { "resolved": new, "integrity": sha512 },
+            }
+        })
+        .to_string();
+
+        assert_eq!(
+            refetch_where_hash(
+                &dir.path().join("_cacache"),
+                &lockfile,
+                "sha1",
+                &parse::Options::default()
+            )?,
+            1
+        );
+
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 1, "only sha1 entries should be re-fetched");
+        assert!(requests[0].starts_with("GET /old/-/old-1.0.0.tgz "));
+
+        let upgraded = cache.get(&cache_key(&old))?.unwrap().integrity;
+
+        assert!(upgraded.starts_with("sha512-"), "{upgraded}");
+        assert_eq!(cache.content(&upgraded)?.as_deref(), Some(&b"old"[..]));
+        assert_eq!(cache.get(&cache_key(&new))?.unwrap().integrity, sha512);
+
+        Ok(())
+    }
+
+    #[test]
+    fn progress_events() -> anyhow::Result<()> {
+        let server = Server::new(vec![
+            response(200, &[], b"tarball"),
+            response(200, &[], b"tarball"),
+        ]);
+        let integrity = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(b"tarball").finalize())
+        );
+
+        let dir = tempdir()?;
+        let lockfile = dir.path().join("package-lock.json");
+        let events = dir.path().join("events.ndjson");
+
+        fs::write(
+            &lockfile,
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": {
+                        "resolved": server.url.join("foo/-/foo-1.0.0.tgz")?,
+                        "integrity": integrity,
+                    },
+                    "node_modules/bar": {
+                        "resolved": server.url.join("bar/-/bar-1.0.0.tgz")?,
+                        "integrity": integrity,
+                    },
+                }
+            })
+            .to_string(),
+        )?;
+
+        prefetch(
+            fs::read_to_string(&lockfile)?,
+            Some(&dir.path().join("out")),
+            &Options {
+                report: ReportOptions {
+                    events: Some(events.clone()),
+                    ..ReportOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+
+        let events = fs::read_to_string(events)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Value>, _>>()?;
+        let kinds = |kind: &str| events.iter().filter(|e| e["event"] == kind).count();
+
+        assert_eq!(events.len(), 5, "{events:?}");
+        assert_eq!(kinds("started"), 2);
+        assert_eq!(kinds("fetched"), 2);
+        assert_eq!(events[4]["event"], "done");
+        assert_eq!(events[4]["packages"], 2);
+
+        for event in events.iter().filter(|e| e["event"] == "fetched") {
+            assert_eq!(event["bytes"], 7);
+            assert!(event["seconds"].is_f64());
+
+            let started = events
+                .iter()
+                .position(|e| e["event"] == "started" && e["name"] == event["name"]);
+            let fetched = events.iter().position(|e| e == event);
+
+            assert!(
+                started < fetched,
+                "{} was fetched before it started",
+                event["name"]
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_write_lockfile() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let lockfile = dir.path().join("package-lock.json");
+
+        fs::write(
+            &lockfile,
+            r#"{ "lockfileVersion": 3, "packages": { "": {} } }"#,
+        )?;
+
+        for write_lockfile in [true, false] {
+            let out = dir.path().join(format!("out-{write_lockfile}"));
+
+            prefetch(
+                fs::read_to_string(&lockfile)?,
+                Some(&out),
+                &Options {
+                    parse: parse::Options {
+                        force_empty_cache: true,
+                        ..parse::Options::default()
+                    },
+                    lockfile: LockfileOptions {
+                        write: write_lockfile,
+                        ..LockfileOptions::default()
+                    },
+                    ..Options::default()
+                },
+            )?;
+
+            assert!(out.join("_cacache").is_dir());
+            assert_eq!(out.join("package-lock.json").exists(), write_lockfile);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn total_timeout() -> anyhow::Result<()> {
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": (0..256)
+                .map(|i| {
+                    (
+                        format!("node_modules/dep-{i}"),
+                        json!({
+                            "resolved": format!("https://registry.npmjs.org/dep-{i}/-/dep-{i}-1.0.0.tgz"),
+                            "integrity": "sha512-dep",
+                        }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>(),
+        })
+        .to_string();
+        let packages = || parse::registry_lockfile(&lockfile, &parse::Options::default());
+        let slow_fetch = |_| {
+            thread::sleep(Duration::from_millis(20));
+
+            Ok(())
+        };
+
+        let err = fetch_all(packages()?, Some(Duration::from_millis(10)), slow_fetch)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.starts_with("exceeded the total timeout of 0.01s, after fetching"),
+            "{err}"
+        );
+        assert!(err.ends_with("of 256 packages"), "{err}");
+
+        assert!(fetch_all(packages()?, None, |_| Ok(())).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn temp_out_dir_is_removed_on_failure() -> anyhow::Result<()> {
+        let build_top = tempdir()?;
+        let server = Server::new(vec![response(200, &[], b"not the tarball")]);
+        let lockfile = build_top.path().join("package-lock.json");
+
+        fs::write(
+            &lockfile,
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": {
+                        "resolved": server.url.join("foo/-/foo-1.0.0.tgz")?,
+                        "integrity": format!(
+                            "sha512-{}",
+                            BASE64_STANDARD.encode(Sha512::new().chain(b"foo").finalize())
+                        ),
+                    },
+                }
+            })
+            .to_string(),
+        )?;
+
+        let result = prefetch_in(
+            fs::read_to_string(&lockfile)?,
+            None,
+            build_top.path(),
+            &Options::default(),
+        );
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("integrity mismatch"));
+        assert_eq!(
+            fs::read_dir(build_top.path())?
+                .map(|e| Ok(e?.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?,
+            vec![std::ffi::OsString::from("package-lock.json")],
+            "the temporary output directory should have been created under the temporary root and removed"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_overrides() -> anyhow::Result<()> {
+        let server = Server::new(vec![
+            response(200, &[], b"tarball"),
+            response(200, &[], b"tarball"),
+        ]);
+        let sri = |data: &[u8]| {
+            format!(
+                "sha512-{}",
+                BASE64_STANDARD.encode(Sha512::new().chain(data).finalize())
+            )
+        };
+
+        let dir = tempdir()?;
+        let lockfile = dir.path().join("package-lock.json");
+
+        fs::write(
+            &lockfile,
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": {
+                        "resolved": server.url.join("foo/-/foo-1.0.0.tgz")?,
+                        "integrity": sri(b"what the registry claimed"),
+                    },
+                }
+            })
+            .to_string(),
+        )?;
+
+        let options = |integrity_overrides| Options {
+            parse: parse::Options {
+                integrity_overrides,
+                ..parse::Options::default()
+            },
+            lockfile: LockfileOptions {
+                write: false,
+                ..LockfileOptions::default()
+            },
+            ..Options::default()
+        };
+
+        let err = prefetch(
+            fs::read_to_string(&lockfile)?,
+            Some(&dir.path().join("without")),
+            &options(HashMap::new()),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("integrity mismatch"), "{err}");
+
+        let out = dir.path().join("with");
+
+        prefetch(
+            fs::read_to_string(&lockfile)?,
+            Some(&out),
+            &options(HashMap::from([(
+                String::from("node_modules/foo"),
+                sri(b"tarball"),
+            )])),
+        )?;
+
+        let key = cache_key(&server.url.join("foo/-/foo-1.0.0.tgz")?);
+
+        assert_eq!(
+            Cache::new(out.join("_cacache"))
+                .get(&key)?
+                .unwrap()
+                .integrity,
+            sri(b"tarball")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn git_lockfile() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let git = |args: &[&str]| -> anyhow::Result<()> {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .status()?;
+
+            anyhow::ensure!(status.success(), "git {args:?} failed");
+
+            Ok(())
+        };
+
+        git(&["init", "--quiet"])?;
+        fs::create_dir(dir.path().join("app"))?;
+        fs::write(dir.path().join("app/package-lock.json"), "\u{feff}old")?;
+        git(&["add", "."])?;
+        git(&["commit", "--quiet", "-m", "old"])?;
+        git(&["tag", "v1"])?;
+        fs::write(dir.path().join("app/package-lock.json"), "new")?;
+
+        let repo = dir.path().display();
+
+        assert_eq!(
+            read_git_lockfile(&format!("{repo}#v1:app/package-lock.json"))?,
+            "old",
+            "the lockfile should be read at the ref, not from the working tree, without its BOM"
+        );
+        assert!(read_git_lockfile(&format!("{repo}#v1:missing.json")).is_err());
+        assert!(read_git_lockfile(&format!("{repo}#v1")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sbom_output() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"tarball")]);
+        let sri = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(b"tarball").finalize())
+        );
+        let url = server.url.join("@scope/foo/-/foo-1.2.3.tgz")?;
+
+        let dir = tempdir()?;
+        let sbom = dir.path().join("sbom.json");
+
+        prefetch(
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/@scope/foo": {
+                        "version": "1.2.3",
+                        "resolved": url,
+                        "integrity": sri,
+                    },
+                }
+            })
+            .to_string(),
+            Some(&dir.path().join("out")),
+            &Options {
+                lockfile: LockfileOptions {
+                    write: false,
+                    ..LockfileOptions::default()
+                },
+                sbom: Some(sbom.clone()),
+                ..Options::default()
+            },
+        )?;
+
+        let sbom: Value = serde_json::from_slice(&fs::read(sbom)?)?;
+        let components = sbom["components"].as_array().unwrap();
+
+        assert_eq!(sbom["bomFormat"], "CycloneDX");
+        assert_eq!(components.len(), 1);
+        assert_eq!(components[0]["name"], "@scope/foo");
+        assert_eq!(components[0]["version"], "1.2.3");
+        assert_eq!(components[0]["purl"], "pkg:npm/%40scope/foo@1.2.3");
+        assert_eq!(components[0]["externalReferences"][0]["url"], url.as_str());
+        assert_eq!(components[0]["hashes"][0]["alg"], "SHA-512");
+        assert_eq!(
+            components[0]["hashes"][0]["content"],
+            crate::cacache::hex(&Sha512::new().chain(b"tarball").finalize())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npm_shorthand_resolved() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"tarball")]);
+        let dir = tempdir()?;
+
+        prefetch(
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/@scope/foo": {
+                        "version": "1.0.0",
+                        "resolved": "npm:@scope/foo@1.0.0",
+                        "integrity": format!(
+                            "sha512-{}",
+                            BASE64_STANDARD.encode(Sha512::new().chain(b"tarball").finalize())
+                        ),
+                    },
+                }
+            })
+            .to_string(),
+            Some(&dir.path().join("out")),
+            &Options {
+                parse: parse::Options {
+                    registry: Some(server.url.join("npm/")?),
+                    ..parse::Options::default()
+                },
+                lockfile: LockfileOptions {
+                    write: false,
+                    ..LockfileOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+
+        assert!(server.requests()[0].starts_with("GET /npm/@scope/foo/-/foo-1.0.0.tgz "));
+
+        Ok(())
+    }
+
+    /// Seeds a cache at `path` with the packages `a` and `b` from `server`, returning a lockfile with those and `c` and `d`,
+    /// which are only on `server`.
+    fn partly_cached(path: &Path, server: &Server) -> anyhow::Result<String> {
+        let cache = Cache::new(path.to_path_buf());
+        let url = |name: &str| server.url.join(&format!("{name}/-/{name}-1.0.0.tgz"));
+
+        cache.init()?;
+
+        for name in ["a", "b"] {
+            cache.put(
+                cache_key(&url(name)?),
+                url(name)?,
+                name.as_bytes(),
+                Some(ContentAlgorithm::Sha512.sri(name.as_bytes())),
+                None,
+            )?;
+        }
+
+        let mut packages = serde_json::Map::new();
+
+        for name in ["a", "b", "c", "d"] {
+            packages.insert(
+                format!("node_modules/{name}"),
+                json!({ "resolved": url(name)?, "integrity": ContentAlgorithm::Sha512.sri(name.as_bytes()) }),
+            );
+        }
+
+        Ok(json!({ "lockfileVersion": 3, "packages": packages }).to_string())
+    }
+
+    #[test]
+    fn base_cache() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"c"), response(200, &[], b"d")]);
+        let dir = tempdir()?;
+        let url = |name: &str| server.url.join(&format!("{name}/-/{name}-1.0.0.tgz"));
+        let lockfile = partly_cached(&dir.path().join("base"), &server)?;
+        let out = dir.path().join("out");
+
+        prefetch(
+            lockfile,
+            Some(&out),
+            &Options {
+                lockfile: LockfileOptions {
+                    write: false,
+                    ..LockfileOptions::default()
+                },
+                cache: CacheOptions {
+                    base_cache: Some(dir.path().join("base")),
+                    ..CacheOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+
+        let mut requests = server.requests();
+
+        requests.sort();
+
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("GET /c/-/c-1.0.0.tgz "));
+        assert!(requests[1].starts_with("GET /d/-/d-1.0.0.tgz "));
+
+        let cache = Cache::new(out.join("_cacache"));
+
+        assert!(cache.get(&cache_key(&url("a")?))?.is_none());
+        assert!(cache.get(&cache_key(&url("c")?))?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefer_offline() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"c"), response(200, &[], b"d")]);
+        let dir = tempdir()?;
+        let url = |name: &str| server.url.join(&format!("{name}/-/{name}-1.0.0.tgz"));
+        let lockfile = partly_cached(&dir.path().join("offline"), &server)?;
+        let out = dir.path().join("out");
+
+        prefetch(
+            lockfile,
+            Some(&out),
+            &Options {
+                fetch: FetchOptions {
+                    offline: Some(Cache::new(dir.path().join("offline"))),
+                    ..FetchOptions::default()
+                },
+                lockfile: LockfileOptions {
+                    write: false,
+                    ..LockfileOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+
+        let mut requests = server.requests();
+
+        requests.sort();
+
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("GET /c/-/c-1.0.0.tgz "));
+        assert!(requests[1].starts_with("GET /d/-/d-1.0.0.tgz "));
+
+        let cache = Cache::new(out.join("_cacache"));
+
+        for name in ["a", "b", "c", "d"] {
+            let entry = cache.get(&cache_key(&url(name)?))?.unwrap();
+
+            assert_eq!(cache.content(&entry.integrity)?.unwrap(), name.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn dumped_keys() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("keys.json");
+
+        dump_keys(
+            &path,
+            &json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": {
+                        "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                        "integrity": "sha512-foo",
+                    },
+                    "node_modules/bar": {
+                        "resolved": "git+ssh://git@github.com/foo/bar.git#0123456789abcdef0123456789abcdef01234567",
+                    },
+                }
+            })
+            .to_string(),
+            &parse::Options::default(),
+        )?;
+
+        assert_eq!(
+            serde_json::from_slice::<Value>(&fs::read(path)?)?,
+            json!({
+                "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz":
+                    "make-fetch-happen:request-cache:https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                "git+ssh://git@github.com/foo/bar.git#0123456789abcdef0123456789abcdef01234567":
+                    "make-fetch-happen:request-cache:https://codeload.github.com/foo/bar/tar.gz/0123456789abcdef0123456789abcdef01234567",
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn nix_json() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let (out, path) = (dir.path().join("out"), dir.path().join("hashes.json"));
+        let lockfile = r#"{ "lockfileVersion": 3, "packages": { "": {} } }"#;
+
+        prefetch(
+            lockfile.to_string(),
+            Some(&out),
+            &Options {
+                parse: parse::Options {
+                    force_empty_cache: true,
+                    ..parse::Options::default()
+                },
+                emit_nix_json: Some(path.clone()),
+                ..Options::default()
+            },
+        )?;
+
+        assert_eq!(
+            serde_json::from_slice::<Value>(&fs::read(path)?)?,
+            json!({
+                "lockfileHash": format!(
+                    "sha256-{}",
+                    BASE64_STANDARD.encode(Sha256::new().chain(lockfile).finalize())
+                ),
+                "cacheHash": nar::hash_path(&out)?,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn kept_lockfile_name() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let out = dir.path().join("out");
+
+        prefetch(
+            String::from(r#"{ "lockfileVersion": 3, "packages": { "": {} } }"#),
+            Some(&out),
+            &Options {
+                parse: parse::Options {
+                    force_empty_cache: true,
+                    ..parse::Options::default()
+                },
+                lockfile: LockfileOptions {
+                    name: Some(String::from("npm-shrinkwrap.json")),
+                    ..LockfileOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+
+        assert!(out.join("npm-shrinkwrap.json").is_file());
+        assert!(!out.join("package-lock.json").exists());
+
+        assert_eq!(
+            lockfile_name(None, "src/npm-shrinkwrap.json").as_deref(),
+            Some("npm-shrinkwrap.json")
+        );
+        assert_eq!(
+            lockfile_name(Some("/src/repo#main:app/package-lock.json"), "").as_deref(),
+            Some("package-lock.json")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn self_checked_lockfile() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("package-lock.json");
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo",
+                },
+                "node_modules/bar": {
+                    "resolved": "https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                    "integrity": "sha512-bar",
+                },
+            }
+        });
+        let options = parse::Options::default();
+        let expected = parse::fetch_urls(&lockfile.to_string(), &options)?;
+
+        fs::write(&path, canonicalize_lockfile(&lockfile.to_string())?)?;
+        self_check(&path, &expected, &options)?;
+
+        let mut corrupted = lockfile.clone();
+
+        corrupted["packages"]
+            .as_object_mut()
+            .unwrap()
+            .remove("node_modules/bar");
+        fs::write(&path, corrupted.to_string())?;
+
+        assert_eq!(
+            self_check(&path, &expected, &options)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "the written lockfile {} doesn't match the one that was read: missing https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                path.display()
+            )
+        );
+
+        fs::write(&path, &lockfile.to_string()[1..])?;
+
+        assert!(self_check(&path, &expected, &options)
+            .unwrap_err()
+            .to_string()
+            .contains("doesn't parse"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_registry_env() -> anyhow::Result<()> {
+        let env = HashMap::from([
+            (
+                "FETCH_NPM_DEPS_DEFAULT_REGISTRY",
+                "https://artifacts.example.com/api/npm/",
+            ),
+            ("npm_config_registry", "https://registry.example.com/"),
+        ]);
+        let registry = |names: &[&str]| {
+            default_registry(|name| names.contains(&name).then(|| env[name].to_string()))
+        };
+
+        assert_eq!(
+            registry(&["FETCH_NPM_DEPS_DEFAULT_REGISTRY", "npm_config_registry"])?
+                .map(String::from)
+                .as_deref(),
+            Some("https://artifacts.example.com/api/npm/")
+        );
+        assert_eq!(
+            registry(&["npm_config_registry"])?
+                .map(String::from)
+                .as_deref(),
+            Some("https://registry.example.com/")
+        );
+        assert_eq!(registry(&[])?, None);
+
+        let packages = parse::registry_lockfile(
+            r#"{
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": {
+                        "resolved": "foo/-/foo-1.0.0.tgz",
+                        "integrity": "sha512-foo"
+                    }
+                }
+            }"#,
+            &parse::Options {
+                registry: registry(&["FETCH_NPM_DEPS_DEFAULT_REGISTRY"])?,
+                ..parse::Options::default()
+            },
+        )?;
+
+        assert_eq!(
+            packages[0].url.as_str(),
+            "https://artifacts.example.com/api/npm/foo/-/foo-1.0.0.tgz"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_directory() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+
+        fs::create_dir(dir.path().join("@scope"))?;
+        fs::write(dir.path().join("foo-1.0.0.tgz"), b"foo")?;
+        fs::write(dir.path().join("@scope/bar-2.0.0.tgz"), b"bar")?;
+
+        assert_eq!(
+            hash_dir(dir.path())?,
+            [
+                format!(
+                    "{}\t@scope/bar-2.0.0.tgz",
+                    ContentAlgorithm::Sha512.sri(b"bar")
+                ),
+                format!("{}\tfoo-1.0.0.tgz", ContentAlgorithm::Sha512.sri(b"foo")),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fragmented_tarball_urls() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"tarball")]);
+        let dir = tempdir()?;
+        let out = dir.path().join("out");
+        let url = server.url.join("foo/-/foo-1.0.0.tgz")?;
+        let fragmented = Url::parse(&format!("{url}#sha1=0123abcd"))?;
+        let integrity = format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::new().chain(b"tarball").finalize())
+        );
+
+        assert_eq!(cache_key(&fragmented), cache_key(&url));
+
+        prefetch(
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": { "resolved": fragmented, "integrity": integrity },
+                }
+            })
+            .to_string(),
+            Some(&out),
+            &Options {
+                lockfile: LockfileOptions {
+                    write: false,
+                    ..LockfileOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 1);
+        assert!(
+            requests[0].starts_with("GET /foo/-/foo-1.0.0.tgz "),
+            "{}",
+            requests[0]
+        );
+
+        let cache = Cache::new(out.join("_cacache"));
+
+        assert_eq!(
+            cache
+                .get(&format!("make-fetch-happen:request-cache:{url}"))?
+                .map(|key| key.integrity),
+            Some(integrity)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_output() -> anyhow::Result<()> {
+        // Answers with the tarball's name, so that `foo`'s content depends on which run fetches it.
+        let mut runs = 0;
+        let server = Server::with_handler(3, move |request| {
+            let body = if request.starts_with("GET /foo/") {
+                runs += 1;
+
+                format!("foo{}", runs - 1)
+            } else {
+                String::from("bar")
+            };
+
+            response(200, &[], body.as_bytes())
+        });
+        let dir = tempdir()?;
+        let out = dir.path().join("out");
+        let (foo, bar) = (
+            server.url.join("foo/-/foo-1.0.0.tgz")?,
+            server.url.join("bar/-/bar-1.0.0.tgz")?,
+        );
+        let run = |lockfile: Value, incremental: bool| {
+            prefetch(
+                lockfile.to_string(),
+                Some(&out),
+                &Options {
+                    lockfile: LockfileOptions {
+                        write: false,
+                        ..LockfileOptions::default()
+                    },
+                    cache: CacheOptions {
+                        incremental,
+                        ..CacheOptions::default()
+                    },
+                    ..Options::default()
+                },
+            )
+        };
+
+        run(
+            json!({
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/foo": { "resolved": foo, "integrity": ContentAlgorithm::Sha512.sri(b"foo0") },
+                }
+            }),
+            false,
+        )?;
+
+        // `foo` is now stale, and `bar` is new.
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/foo": { "resolved": foo, "integrity": ContentAlgorithm::Sha512.sri(b"foo1") },
+                "node_modules/bar": { "resolved": bar, "integrity": ContentAlgorithm::Sha512.sri(b"bar") },
+            }
+        });
+
+        let err = run(lockfile.clone(), false).unwrap_err().to_string();
+
+        assert!(err.contains("already has a cache"), "{err}");
+        assert_eq!(server.requests().len(), 1, "nothing should be fetched");
+
+        run(lockfile, true)?;
+
+        let cache = Cache::new(out.join("_cacache"));
+
+        for (url, data) in [(&foo, &b"foo1"[..]), (&bar, b"bar")] {
+            let entry = cache.get(&cache_key(url))?.unwrap();
+
+            assert_eq!(entry.integrity, ContentAlgorithm::Sha512.sri(data));
+            assert_eq!(cache.content(&entry.integrity)?.as_deref(), Some(data));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_after_fetching() -> anyhow::Result<()> {
+        let server = Server::with_handler(3, |request| {
+            let name = request.split('/').nth(1).unwrap_or_default();
+
+            response(200, &[], name.as_bytes())
+        });
+        let dir = tempdir()?;
+        let url = |name: &str| server.url.join(&format!("{name}/-/{name}-1.0.0.tgz"));
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/foo": { "resolved": url("foo")?, "integrity": ContentAlgorithm::Sha512.sri(b"foo") },
+                "node_modules/bar": { "resolved": url("bar")?, "integrity": ContentAlgorithm::Sha512.sri(b"bar") },
+                // Seeded with the wrong integrity, which should only be caught after everything's fetched.
+                "node_modules/baz": { "resolved": url("baz")?, "integrity": ContentAlgorithm::Sha512.sri(b"not baz") },
+            }
+        });
+
+        let err = prefetch(
+            lockfile.to_string(),
+            Some(&dir.path().join("out")),
+            &Options {
+                fetch: FetchOptions {
+                    verify_after: true,
+                    ..FetchOptions::default()
+                },
+                lockfile: LockfileOptions {
+                    write: false,
+                    ..LockfileOptions::default()
+                },
+                ..Options::default()
+            },
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert_eq!(
+            server.requests().len(),
+            3,
+            "everything should be fetched first"
+        );
+        assert_eq!(
+            err,
+            format!(
+                "integrity mismatch for:\nnode_modules/baz at {}: expected {}",
+                url("baz")?,
+                ContentAlgorithm::Sha512.sri(b"not baz")
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn verified_after_fetching_is_rekeyed() -> anyhow::Result<()> {
+        let server = Server::with_handler(4, |request| {
+            let name = request.split('/').nth(1).unwrap_or_default();
+
+            response(200, &[], name.as_bytes())
+        });
+        let dir = tempdir()?;
+        let url = |name: &str| server.url.join(&format!("{name}/-/{name}-1.0.0.tgz"));
+        // SHA-1, so that verified content has to move away from the SHA-512 digest it was first cached under.
+        let lockfile = json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/foo": { "resolved": url("foo")?, "integrity": ContentAlgorithm::Sha1.sri(b"foo") },
+                "node_modules/bar": { "resolved": url("bar")?, "integrity": ContentAlgorithm::Sha1.sri(b"bar") },
+            }
+        });
+        let fetch = |out: &str, verify_after| {
+            let out = dir.path().join(out);
+
+            prefetch(
+                lockfile.to_string(),
+                Some(&out),
+                &Options {
+                    fetch: FetchOptions {
+                        verify_after,
+                        ..FetchOptions::default()
+                    },
+                    lockfile: LockfileOptions {
+                        write: false,
+                        ..LockfileOptions::default()
+                    },
+                    ..Options::default()
+                },
+            )?;
+
+            nar::hash_path(&out)
+        };
+
+        assert_eq!(
+            fetch("after", true)?,
+            fetch("during", false)?,
+            "verifying afterwards should leave the same cache behind"
+        );
+
+        for flags in [
+            &["--verify-after", "--unwrap-double-gzip"][..],
+            &["--verify-after", "--on-fetch", "hook"],
+        ] {
+            let mut args = flags.iter().map(ToString::to_string).collect();
+
+            assert!(FetchOptions::from_args(&mut args).is_err(), "{flags:?}");
+        }
 
         Ok(())
     }
@@ -0,0 +1,185 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use digest::{Digest, Update};
+use sha2::Sha256;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+};
+
+/// Returns the hash of `path` as `nix hash path` would, i.e. the SRI sha256 hash of its NAR serialization.
+pub fn hash_path(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = HashWriter(Sha256::new());
+
+    dump(path, &mut hasher)?;
+
+    Ok(format!(
+        "sha256-{}",
+        BASE64_STANDARD.encode(hasher.0.finalize())
+    ))
+}
+
+/// Writes the NAR serialization of `path` to `out`, as `nix-store --dump` would.
+pub fn dump(path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    write_str(out, b"nix-archive-1")?;
+    dump_node(path, out)
+}
+
+fn dump_node(path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    write_str(out, b"(")?;
+    write_str(out, b"type")?;
+
+    if metadata.is_symlink() {
+        write_str(out, b"symlink")?;
+        write_str(out, b"target")?;
+        write_str(out, fs::read_link(path)?.as_os_str().as_bytes())?;
+    } else if metadata.is_dir() {
+        write_str(out, b"directory")?;
+
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Entries are ordered by the bytes of their names.
+        entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        for name in entries {
+            write_str(out, b"entry")?;
+            write_str(out, b"(")?;
+            write_str(out, b"name")?;
+            write_str(out, name.as_bytes())?;
+            write_str(out, b"node")?;
+            dump_node(&path.join(name), out)?;
+            write_str(out, b")")?;
+        }
+    } else {
+        write_str(out, b"regular")?;
+
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(out, b"executable")?;
+            write_str(out, b"")?;
+        }
+
+        write_str(out, b"contents")?;
+        out.write_all(&metadata.len().to_le_bytes())?;
+
+        let copied = io::copy(&mut fs::File::open(path)?.take(metadata.len()), out)?;
+
+        if copied != metadata.len() {
+            anyhow::bail!("{path:?} changed while it was being hashed");
+        }
+
+        write_padding(out, copied)?;
+    }
+
+    write_str(out, b")")?;
+
+    Ok(())
+}
+
+/// Writes a length-prefixed string, padded to a multiple of 8 bytes.
+fn write_str(out: &mut impl Write, s: &[u8]) -> io::Result<()> {
+    out.write_all(&(s.len() as u64).to_le_bytes())?;
+    out.write_all(s)?;
+
+    write_padding(out, s.len() as u64)
+}
+
+fn write_padding(out: &mut impl Write, len: u64) -> io::Result<()> {
+    let padding = (8 - len % 8) % 8;
+
+    out.write_all(&[0; 8][..usize::try_from(padding).unwrap()])
+}
+
+/// Feeds everything written to it into a hasher.
+struct HashWriter<D>(D);
+
+impl<D: Update> Write for HashWriter<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, hash_path};
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tempfile::tempdir;
+
+    /// Encodes a NAR string by hand, for comparison.
+    fn s(s: &str) -> Vec<u8> {
+        let mut out = (s.len() as u64).to_le_bytes().to_vec();
+
+        out.extend_from_slice(s.as_bytes());
+        out.resize(8 + s.len().div_ceil(8) * 8, 0);
+
+        out
+    }
+
+    #[test]
+    fn serialization() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+
+        fs::write(dir.path().join("foo"), "hello")?;
+
+        let mut nar = Vec::new();
+
+        dump(dir.path(), &mut nar)?;
+
+        let expected = [
+            "nix-archive-1",
+            "(",
+            "type",
+            "directory",
+            "entry",
+            "(",
+            "name",
+            "foo",
+            "node",
+            "(",
+            "type",
+            "regular",
+            "contents",
+            "hello",
+            ")",
+            ")",
+            ")",
+        ]
+        .into_iter()
+        .flat_map(s)
+        .collect::<Vec<_>>();
+
+        assert_eq!(nar, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn known_hash() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().join("root");
+
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("b.txt"), "hello\n")?;
+        fs::write(root.join("a.sh"), "#!/bin/sh\necho hi\n")?;
+        fs::set_permissions(root.join("a.sh"), fs::Permissions::from_mode(0o755))?;
+        fs::write(root.join("sub/empty"), "")?;
+        std::os::unix::fs::symlink("../b.txt", root.join("sub/link"))?;
+
+        assert_eq!(
+            hash_path(&root)?,
+            "sha256-r1ukM5v2J99CMRicthWV3CTM+UVKP+uHX0JdG9JcMjg="
+        );
+
+        Ok(())
+    }
+}
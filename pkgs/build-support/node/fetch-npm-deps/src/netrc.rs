@@ -0,0 +1,137 @@
+use std::{collections::HashMap, env, fs, io, path::PathBuf};
+
+/// Credentials for HTTP Basic auth, by host, as read from a `.netrc` file.
+///
+/// `default` entries are ignored, so that credentials are never sent to hosts they weren't explicitly given for.
+#[derive(Debug, Default, PartialEq)]
+pub struct Netrc {
+    machines: HashMap<String, Credentials>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Credentials {
+    pub login: String,
+    pub password: String,
+}
+
+impl Netrc {
+    /// Reads the file at `NETRC`, or `~/.netrc`, if there is one.
+    pub fn load() -> anyhow::Result<Option<Netrc>> {
+        let path = match env::var_os("NETRC") {
+            Some(path) => PathBuf::from(path),
+            None => match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".netrc"),
+                None => return Ok(None),
+            },
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(Netrc::parse(&content))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("couldn't read {}: {e}", path.display())),
+        }
+    }
+
+    pub fn parse(content: &str) -> Netrc {
+        let mut machines = HashMap::new();
+        let mut current: Option<(Option<String>, Credentials)> = None;
+        let mut lines = content.lines();
+
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" => {
+                        if let Some((Some(host), credentials)) = current.take() {
+                            machines.insert(host, credentials);
+                        }
+
+                        let host = if token == "machine" {
+                            tokens.next().map(str::to_ascii_lowercase)
+                        } else {
+                            None
+                        };
+
+                        current = Some((host, Credentials::default()));
+                    }
+                    "login" | "password" | "account" => {
+                        let value = tokens.next().unwrap_or_default().to_string();
+
+                        if let Some((_, credentials)) = &mut current {
+                            match token {
+                                "login" => credentials.login = value,
+                                "password" => credentials.password = value,
+                                _ => {}
+                            }
+                        }
+                    }
+                    // Macro definitions run until the next empty line.
+                    "macdef" => {
+                        for line in lines.by_ref() {
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+
+                        break;
+                    }
+                    // Comments run until the end of the line.
+                    _ if token.starts_with('#') => break,
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some((Some(host), credentials)) = current {
+            machines.insert(host, credentials);
+        }
+
+        Netrc { machines }
+    }
+
+    /// Returns the credentials for `host`, if any.
+    pub fn get(&self, host: &str) -> Option<&Credentials> {
+        self.machines.get(&host.to_ascii_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credentials, Netrc};
+
+    #[test]
+    fn parse() {
+        let netrc = Netrc::parse(
+            "# mirrors
+machine mirror.example.com login alice password s3cret
+machine Other.Example.com
+    login bob
+    account ignored
+    password hunter2
+
+macdef init
+machine macro.example.com login mallory password nope
+
+default login anonymous password guest
+",
+        );
+
+        assert_eq!(
+            netrc.get("mirror.example.com"),
+            Some(&Credentials {
+                login: String::from("alice"),
+                password: String::from("s3cret"),
+            })
+        );
+        assert_eq!(
+            netrc.get("other.example.com"),
+            Some(&Credentials {
+                login: String::from("bob"),
+                password: String::from("hunter2"),
+            })
+        );
+        assert_eq!(netrc.get("macro.example.com"), None);
+        assert_eq!(netrc.get("registry.npmjs.org"), None);
+    }
+}
@@ -1,37 +1,22 @@
 use anyhow::{anyhow, bail, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{debug, warn};
 use rayon::slice::ParallelSliceMut;
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer,
 };
+use serde_json::Value;
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     fmt,
+    path::Path,
 };
 use url::Url;
 
-pub(super) fn packages(content: &str) -> anyhow::Result<Vec<Package>> {
-    let lockfile: Lockfile = serde_json::from_str(content)?;
-
-    let mut packages = match lockfile.version {
-        1 => {
-            let initial_url = get_initial_url()?;
-
-            to_new_packages(lockfile.dependencies.unwrap_or_default(), &initial_url)?
-        }
-        2 | 3 => lockfile
-            .packages
-            .unwrap_or_default()
-            .into_iter()
-            .filter(|(n, p)| !n.is_empty() && matches!(p.resolved, Some(UrlOrString::Url(_))))
-            .map(|(n, p)| Package { name: Some(n), ..p })
-            .collect(),
-        _ => bail!(
-            "We don't support lockfile version {}, please file an issue.",
-            lockfile.version
-        ),
-    };
+pub(super) fn packages(content: &str, options: &super::Options) -> anyhow::Result<Vec<Package>> {
+    let mut packages = entries(content, options)?;
 
     packages.par_sort_by(|x, y| {
         x.resolved
@@ -46,35 +31,540 @@ pub(super) fn packages(content: &str) -> anyhow::Result<Vec<Package>> {
             )
     });
 
+    check_duplicates(&packages)?;
+
     packages.dedup_by(|x, y| x.resolved == y.resolved);
 
     Ok(packages)
 }
 
+/// Returns every fetchable entry in the lockfile, including (unlike [`packages`]) all of those that resolve to the same
+/// URL.
+pub(super) fn entries(content: &str, options: &super::Options) -> anyhow::Result<Vec<Package>> {
+    if content.trim().is_empty() {
+        bail!("The lockfile is empty.");
+    }
+
+    let lockfile: Value =
+        serde_json::from_str(content).context("The lockfile is not valid JSON")?;
+
+    if lockfile.get("packages").is_none()
+        && lockfile.get("dependencies").is_none()
+        && !options.force_empty_cache
+    {
+        bail!("The lockfile is valid JSON, but has neither `packages` nor `dependencies`. Please check that it was generated correctly.");
+    }
+
+    let mut lockfile: Lockfile = serde_json::from_value(lockfile)?;
+
+    let registry = match &options.registry {
+        Some(registry) => registry.clone(),
+        None => Url::parse(DEFAULT_REGISTRY)?,
+    };
+
+    // Lockfiles generated on Windows may use backslashes as path separators in their keys.
+    lockfile.packages = lockfile
+        .packages
+        .map(|packages| {
+            packages
+                .into_iter()
+                .map(|(n, p)| {
+                    let p = p.with_dist()?.with_registry_shorthand(&registry)?;
+
+                    Ok((n.replace('\\', "/"), p))
+                })
+                .collect::<anyhow::Result<_>>()
+        })
+        .transpose()?;
+
+    if options.require_resolved {
+        let mut missing = missing_resolved(&lockfile);
+
+        if !missing.is_empty() {
+            missing.sort();
+
+            bail!(
+                "The following dependencies are missing a `resolved` URL in the lockfile: {}",
+                missing.join(", ")
+            );
+        }
+    }
+
+    let mut packages = match lockfile.version {
+        1 => {
+            let initial_url = get_initial_url()?;
+            let mut dependencies = lockfile.dependencies.unwrap_or_default();
+
+            if let Some(depth) = options.depth {
+                truncate_v1(&mut dependencies, depth);
+            }
+
+            to_new_packages(dependencies, &initial_url)?
+        }
+        2 | 3 => v2_packages(lockfile.packages.unwrap_or_default(), options),
+        _ => bail!(
+            "We don't support lockfile version {}, please file an issue.",
+            lockfile.version
+        ),
+    };
+
+    if !options.exclude.is_empty() {
+        packages = exclude(packages, options)?;
+    }
+
+    if let Some(check) = options.check_origins {
+        check_origins(&packages, options, &registry, check)?;
+    }
+
+    for package in &mut packages {
+        if let Some(integrity) = package
+            .name
+            .as_ref()
+            .and_then(|name| options.integrity_overrides.get(name))
+        {
+            warn!(
+                "overriding the integrity of {} with {integrity}",
+                package.name.as_deref().unwrap_or_default()
+            );
+
+            package.integrity = Some(HashCollection::from_str(integrity)?);
+        }
+    }
+
+    if let Some(policy) = &options.min_hash {
+        check_min_hash(&packages, policy, options.legacy_sha1)?;
+    }
+
+    Ok(packages)
+}
+
+/// Checks that the hash each package's integrity will be stored with (see [`HashCollection::into_preferred`]) is at
+/// least as strong as `policy` allows.
+fn check_min_hash(
+    packages: &[Package],
+    policy: &super::HashPolicy,
+    legacy_sha1: bool,
+) -> anyhow::Result<()> {
+    let strength = |algo: &str| ALGOS.iter().position(|&a| a == algo);
+    let mut weak = packages
+        .iter()
+        .filter_map(|package| {
+            let hash = package.integrity.as_ref()?.preferred(legacy_sha1)?;
+
+            (strength(hash.algorithm()) < strength(&policy.min)).then(|| {
+                format!(
+                    "{} only has {} integrity",
+                    package.name.as_deref().unwrap_or_default(),
+                    hash.algorithm()
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+
+    weak.sort();
+
+    match weak.is_empty() {
+        true => {}
+        false if policy.warn => {
+            for weak in &weak {
+                warn!("{weak}, which is weaker than {}", policy.min);
+            }
+        }
+        false => bail!(
+            "Some dependencies' integrity is weaker than {} allows:\n{}",
+            policy.min,
+            weak.join("\n")
+        ),
+    }
+
+    Ok(())
+}
+
+/// Takes the fetchable packages from a v2 or v3 lockfile's `packages`.
+fn v2_packages(packages: HashMap<String, Package>, options: &super::Options) -> Vec<Package> {
+    let omitted_peers = if options.omit.is_empty() {
+        HashSet::new()
+    } else {
+        optional_peers(&packages)
+    };
+
+    packages
+        .into_iter()
+        .filter(|(n, p)| !n.is_empty() && matches!(p.resolved, Some(UrlOrString::Url(_))))
+        .filter(|(n, p)| {
+            let workspace = p.resolved.as_ref().is_some_and(UrlOrString::is_workspace)
+                || p.version
+                    .as_deref()
+                    .is_some_and(|v| v.starts_with("workspace:"));
+
+            if workspace {
+                debug!("skipping workspace dependency {n}");
+            }
+
+            !workspace
+        })
+        .filter(|(n, p)| {
+            let omitted = p.peer && omitted_peers.contains(package_name(n));
+
+            if omitted {
+                debug!("omitting optional peer {n}");
+            }
+
+            !omitted
+        })
+        .filter(|(n, _)| {
+            options
+                .depth
+                .is_none_or(|depth| n.matches("node_modules/").count() <= depth + 1)
+        })
+        .map(|(n, p)| Package { name: Some(n), ..p })
+        .collect()
+}
+
+/// Returns the parent to child edges of the dependency tree in `content`, as `packages` keys (the root being `""`).
+///
+/// These follow where dependencies are nested, as v1 lockfiles record no other relationship between them.
+pub(super) fn edges(content: &str) -> anyhow::Result<BTreeSet<(String, String)>> {
+    fn v1(
+        parent: &str,
+        dependencies: &HashMap<String, OldPackage>,
+        edges: &mut BTreeSet<(String, String)>,
+    ) {
+        for (name, package) in dependencies {
+            let key = if parent.is_empty() {
+                format!("node_modules/{name}")
+            } else {
+                format!("{parent}/node_modules/{name}")
+            };
+
+            if let Some(dependencies) = &package.dependencies {
+                v1(&key, dependencies, edges);
+            }
+
+            edges.insert((parent.to_string(), key));
+        }
+    }
+
+    let lockfile: Lockfile = serde_json::from_str(content)?;
+    let mut edges = BTreeSet::new();
+
+    match lockfile.version {
+        1 => v1("", &lockfile.dependencies.unwrap_or_default(), &mut edges),
+        2 | 3 => {
+            for key in lockfile.packages.unwrap_or_default().into_keys() {
+                let key = key.replace('\\', "/");
+
+                if let Some((parent, _)) = key.rsplit_once("/node_modules/") {
+                    edges.insert((parent.to_string(), key));
+                } else if !key.is_empty() {
+                    edges.insert((String::new(), key));
+                }
+            }
+        }
+        _ => bail!(
+            "We don't support lockfile version {}, please file an issue.",
+            lockfile.version
+        ),
+    }
+
+    Ok(edges)
+}
+
+/// Returns the names of dependencies that should have a `resolved` URL, but don't.
+///
+/// Links, workspace members, and bundled dependencies legitimately don't have one.
+fn missing_resolved(lockfile: &Lockfile) -> Vec<String> {
+    fn v1(dependencies: &HashMap<String, OldPackage>, missing: &mut Vec<String>) {
+        for (name, package) in dependencies {
+            let resolvable = match &package.version {
+                UrlOrString::String(_) => true,
+                UrlOrString::Url(v) => v.scheme() == "npm",
+            };
+
+            if resolvable && !package.bundled && package.resolved.is_none() {
+                missing.push(name.clone());
+            }
+
+            if let Some(dependencies) = &package.dependencies {
+                v1(dependencies, missing);
+            }
+        }
+    }
+
+    let mut missing = Vec::new();
+
+    if let Some(packages) = &lockfile.packages {
+        missing.extend(
+            packages
+                .iter()
+                .filter(|(n, p)| {
+                    // Workspace members are keyed by their path, rather than being under `node_modules`.
+                    (n.starts_with("node_modules/") || n.contains("/node_modules/"))
+                        && !p.link
+                        && !p.in_bundle
+                        && p.resolved.is_none()
+                })
+                .map(|(n, _)| n.clone()),
+        );
+    } else if let Some(dependencies) = &lockfile.dependencies {
+        v1(dependencies, &mut missing);
+    }
+
+    missing
+}
+
 #[derive(Deserialize)]
 struct Lockfile {
     #[serde(rename = "lockfileVersion")]
     version: u8,
     dependencies: Option<HashMap<String, OldPackage>>,
+    #[serde(default, deserialize_with = "lenient_packages")]
     packages: Option<HashMap<String, Package>>,
 }
 
+/// Deserializes `packages`, skipping (with a warning) entries that don't have the expected shape, rather than failing to
+/// parse the whole lockfile over one of them.
+fn lenient_packages<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<HashMap<String, Package>>, D::Error> {
+    let Some(packages) = Option::<HashMap<String, Value>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(
+        packages
+            .into_iter()
+            .filter_map(|(name, package)| match serde_json::from_value(package) {
+                Ok(package) => Some((name, package)),
+                Err(e) => {
+                    warn!("skipping {name:?}, as its lockfile entry is malformed: {e}");
+
+                    None
+                }
+            })
+            .collect(),
+    ))
+}
+
 #[derive(Deserialize)]
 struct OldPackage {
     version: UrlOrString,
     #[serde(default)]
     bundled: bool,
+    #[serde(default)]
+    optional: bool,
     resolved: Option<UrlOrString>,
     integrity: Option<HashCollection>,
     dependencies: Option<HashMap<String, OldPackage>>,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+#[allow(clippy::struct_excessive_bools)]
 pub(super) struct Package {
     #[serde(default)]
     pub(super) name: Option<String>,
+    pub(super) version: Option<String>,
     pub(super) resolved: Option<UrlOrString>,
     pub(super) integrity: Option<HashCollection>,
+    #[serde(default)]
+    pub(super) os: Vec<String>,
+    #[serde(default)]
+    pub(super) cpu: Vec<String>,
+    #[serde(default)]
+    pub(super) link: bool,
+    #[serde(default)]
+    pub(super) in_bundle: bool,
+    #[serde(default)]
+    pub(super) optional: bool,
+    #[serde(default)]
+    pub(super) peer: bool,
+    #[serde(default)]
+    pub(super) peer_dependencies: HashMap<String, String>,
+    #[serde(default)]
+    pub(super) peer_dependencies_meta: HashMap<String, PeerDependencyMeta>,
+    pub(super) dist: Option<Dist>,
+    #[serde(default)]
+    pub(super) signatures: Vec<Signature>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+pub(super) struct PeerDependencyMeta {
+    #[serde(default)]
+    optional: bool,
+}
+
+/// The registry's metadata for a package's tarball, which some converted lockfiles have instead of flat fields.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+pub(super) struct Dist {
+    tarball: Option<UrlOrString>,
+    integrity: Option<HashCollection>,
+    shasum: Option<String>,
+    #[serde(default)]
+    signatures: Vec<Signature>,
+}
+
+/// A registry's ECDSA signature over a package's `<name>@<version>:<integrity>`, made with the key identified by `keyid`.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub(super) struct Signature {
+    pub(super) keyid: String,
+    pub(super) sig: String,
+}
+
+impl Package {
+    /// Falls back to the `dist` object for `resolved`, `integrity` and `signatures`, if they're missing.
+    fn with_dist(mut self) -> anyhow::Result<Package> {
+        if let Some(dist) = self.dist.take() {
+            if self.resolved.is_none() {
+                self.resolved = dist.tarball;
+            }
+
+            if self.signatures.is_empty() {
+                self.signatures = dist.signatures;
+            }
+
+            if self.integrity.is_none() {
+                self.integrity = match (dist.integrity, dist.shasum) {
+                    (Some(integrity), _) => Some(integrity),
+                    (None, Some(shasum)) => {
+                        Some(HashCollection::from_str(shasum_to_sri(&shasum)?)?)
+                    }
+                    (None, None) => None,
+                };
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Resolves a `resolved` field that's an `npm:<name>@<version>` shorthand, rather than a URL, to the tarball URL of
+    /// that version on `registry`, and one that's a bare path (as some converted lockfiles have) against `registry`.
+    fn with_registry_shorthand(mut self, registry: &Url) -> anyhow::Result<Package> {
+        let url = match &self.resolved {
+            Some(UrlOrString::Url(url)) if url.scheme() == "npm" => {
+                registry_tarball_url(registry, &url.as_str()["npm:".len()..])?
+            }
+            Some(UrlOrString::String(s)) if s.starts_with("npm:") => {
+                registry_tarball_url(registry, &s["npm:".len()..])?
+            }
+            // Links' `resolved` paths are to the linked package's directory, not the registry.
+            Some(UrlOrString::String(path)) if !self.link && !path.is_empty() => {
+                registry_base(registry).join(path.trim_start_matches('/'))?
+            }
+            _ => return Ok(self),
+        };
+
+        self.resolved = Some(UrlOrString::Url(url));
+
+        Ok(self)
+    }
+}
+
+pub(super) const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org/";
+
+/// Returns the URL of the tarball for `spec` (`<name>@<version>`, where the version must be exact) on `registry`.
+fn registry_tarball_url(registry: &Url, spec: &str) -> anyhow::Result<Url> {
+    // Scoped names start with an `@` of their own.
+    let (name, version) = spec
+        .rfind('@')
+        .filter(|&i| i > 0)
+        .map(|i| (&spec[..i], &spec[i + 1..]))
+        .ok_or_else(|| anyhow!("can't resolve npm:{spec}, as it has no version"))?;
+
+    if version.is_empty()
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'))
+    {
+        bail!("can't resolve npm:{spec}, as {version:?} isn't an exact version");
+    }
+
+    let basename = name.rsplit('/').next().unwrap_or(name);
+
+    Ok(registry_base(registry).join(&format!("{name}/-/{basename}-{version}.tgz"))?)
+}
+
+/// Returns the package name, version, tarball URL on `registry` (or the public npm registry) and integrity of the package
+/// manager pinned by a `package.json`'s `packageManager` field, as corepack reads it: `<name>@<version>+<algo>.<hex>`.
+///
+/// Corepack also pins with sha224 and sha256, which integrity is never checked with here, so those are skipped with a
+/// warning.
+pub(super) fn package_manager(
+    spec: &str,
+    registry: Option<&Url>,
+) -> anyhow::Result<Option<(String, String, Url, Hash)>> {
+    let (pinned, digest) = spec.split_once('+').ok_or_else(|| {
+        anyhow!("packageManager {spec:?} isn't pinned to a hash, which prefetching it needs")
+    })?;
+    let (name, version) = pinned
+        .split_once('@')
+        .ok_or_else(|| anyhow!("expected packageManager as <name>@<version>, got {spec:?}"))?;
+    let (algo, hex) = digest.split_once('.').ok_or_else(|| {
+        anyhow!("expected packageManager's hash as <algorithm>.<hex>, got {digest:?}")
+    })?;
+
+    if matches!(algo, "sha224" | "sha256") {
+        warn!(
+            "not prefetching {pinned}, as it's pinned with {algo}, which isn't supported for integrity"
+        );
+
+        return Ok(None);
+    }
+
+    // Yarn 2 and later are published as their CLI's bundle.
+    let package = match name {
+        "npm" | "pnpm" => name,
+        "yarn" if version.starts_with("1.") => name,
+        "yarn" => "@yarnpkg/cli-dist",
+        _ => bail!("unsupported package manager {name:?}"),
+    };
+    let registry = match registry {
+        Some(registry) => registry.clone(),
+        None => Url::parse(DEFAULT_REGISTRY)?,
+    };
+
+    Ok(Some((
+        package.to_string(),
+        version.to_string(),
+        registry_tarball_url(&registry, &format!("{package}@{version}"))?,
+        Hash::new(hex_to_sri(algo, hex)?)?,
+    )))
+}
+
+/// Returns `registry` with a trailing slash, as registries may be served under a path, which must be kept when joining.
+pub(super) fn registry_base(registry: &Url) -> Url {
+    let mut registry = registry.clone();
+
+    if !registry.path().ends_with('/') {
+        registry.set_path(&format!("{}/", registry.path()));
+    }
+
+    registry
+}
+
+/// Converts a hex sha1 digest (as found in `shasum` fields) to an SRI hash.
+fn shasum_to_sri(shasum: &str) -> anyhow::Result<String> {
+    hex_to_sri("sha1", shasum)
+}
+
+/// Converts a hex digest made with `algo` to an SRI hash.
+fn hex_to_sri(algo: &str, hex: &str) -> anyhow::Result<String> {
+    let len = match algo {
+        "sha1" => 40,
+        "sha512" => 128,
+        _ => bail!("unsupported hash algorithm {algo:?}"),
+    };
+    let bytes = (hex.len() == len)
+        .then(|| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<_>>>()
+        })
+        .flatten()
+        .ok_or_else(|| anyhow!("expected a hex {algo} digest, got {hex:?}"))?;
+
+    Ok(format!("{algo}-{}", BASE64_STANDARD.encode(bytes)))
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -84,6 +574,17 @@ pub(super) enum UrlOrString {
     String(String),
 }
 
+impl UrlOrString {
+    /// Whether this is a `workspace:` specifier, which refers to a package in the project itself rather than anything
+    /// fetchable.
+    fn is_workspace(&self) -> bool {
+        match self {
+            UrlOrString::Url(url) => url.scheme() == "workspace",
+            UrlOrString::String(string) => string.starts_with("workspace:"),
+        }
+    }
+}
+
 impl fmt::Display for UrlOrString {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -98,18 +599,52 @@ pub struct HashCollection(HashSet<Hash>);
 
 impl HashCollection {
     pub fn from_str(s: impl AsRef<str>) -> anyhow::Result<HashCollection> {
+        // Some tools separate hashes with commas, rather than whitespace.
         let hashes = s
             .as_ref()
-            .split_ascii_whitespace()
+            .split(|c: char| c.is_ascii_whitespace() || c == ',')
+            .filter(|hash| !hash.is_empty())
             .map(Hash::new)
             .collect::<anyhow::Result<_>>()?;
 
         Ok(HashCollection(hashes))
     }
 
+    /// Returns a hash from each collection that use the same algorithm, but differ, if there are any.
+    fn conflict<'a>(&'a self, other: &'a HashCollection) -> Option<(&'a Hash, &'a Hash)> {
+        self.0.iter().find_map(|x| {
+            other
+                .0
+                .iter()
+                .find(|y| x.partial_cmp(y) == Some(Ordering::Equal) && x != *y)
+                .map(|y| (x, y))
+        })
+    }
+
     pub fn into_best(self) -> Option<Hash> {
         self.0.into_iter().max()
     }
+
+    /// Like [`HashCollection::into_best`], but with `legacy_sha1`, prefers a sha1 hash (if there is one), for old
+    /// registries that only serve sha1 integrity.
+    pub fn into_preferred(self, legacy_sha1: bool) -> Option<Hash> {
+        match self.legacy_sha1(legacy_sha1) {
+            Some(sha1) => Some(sha1.clone()),
+            None => self.into_best(),
+        }
+    }
+
+    /// Like [`HashCollection::into_preferred`], without consuming the collection.
+    pub(super) fn preferred(&self, legacy_sha1: bool) -> Option<&Hash> {
+        self.legacy_sha1(legacy_sha1)
+            .or_else(|| self.0.iter().max())
+    }
+
+    fn legacy_sha1(&self, legacy_sha1: bool) -> Option<&Hash> {
+        self.0
+            .iter()
+            .find(|h| legacy_sha1 && h.0.starts_with("sha1-"))
+    }
 }
 
 impl PartialOrd for HashCollection {
@@ -126,7 +661,7 @@ impl<'de> Deserialize<'de> for HashCollection {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_string(HashCollectionVisitor)
+        deserializer.deserialize_any(HashCollectionVisitor)
     }
 }
 
@@ -136,7 +671,9 @@ impl<'de> Visitor<'de> for HashCollectionVisitor {
     type Value = HashCollection;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a single SRI hash or a collection of them (separated by spaces)")
+        formatter.write_str(
+            "a single SRI hash or a collection of them (separated by spaces, as an array, or as an object keyed by algorithm)",
+        )
     }
 
     fn visit_str<E>(self, value: &str) -> Result<HashCollection, E>
@@ -145,13 +682,44 @@ impl<'de> Visitor<'de> for HashCollectionVisitor {
     {
         HashCollection::from_str(value).map_err(E::custom)
     }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<HashCollection, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut hashes = Vec::new();
+
+        while let Some(hash) = seq.next_element::<String>()? {
+            hashes.push(hash);
+        }
+
+        HashCollection::from_str(hashes.join(" ")).map_err(de::Error::custom)
+    }
+
+    /// Converted lockfiles may give integrity as e.g. `{ "sha512": "<digest>", "sha1": "<digest>" }`.
+    fn visit_map<A>(self, mut map: A) -> Result<HashCollection, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        let mut hashes = Vec::new();
+
+        while let Some((algorithm, digest)) = map.next_entry::<String, String>()? {
+            if digest.starts_with(&format!("{algorithm}-")) {
+                hashes.push(digest);
+            } else {
+                hashes.push(format!("{algorithm}-{digest}"));
+            }
+        }
+
+        HashCollection::from_str(hashes.join(" ")).map_err(de::Error::custom)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
 pub struct Hash(String);
 
 // Hash algorithms, in ascending preference.
-const ALGOS: &[&str] = &["sha1", "sha512"];
+pub(super) const ALGOS: &[&str] = &["sha1", "sha512"];
 
 impl Hash {
     fn new(s: impl AsRef<str>) -> anyhow::Result<Hash> {
@@ -161,7 +729,7 @@ impl Hash {
             .ok_or_else(|| anyhow!("expected SRI hash, got {:?}", s.as_ref()))?
             .0;
 
-        if ALGOS.iter().any(|&a| algo == a) {
+        if ALGOS.contains(&algo) {
             Ok(Hash(s.as_ref().to_string()))
         } else {
             Err(anyhow!("unknown hash algorithm {algo:?}"))
@@ -171,6 +739,10 @@ impl Hash {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    fn algorithm(&self) -> &str {
+        self.0.split_once('-').map_or("", |(algo, _)| algo)
+    }
 }
 
 impl fmt::Display for Hash {
@@ -179,7 +751,7 @@ impl fmt::Display for Hash {
     }
 }
 
-#[allow(clippy::incorrect_partial_ord_impl_on_ord_type)]
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for Hash {
     fn partial_cmp(&self, other: &Hash) -> Option<Ordering> {
         let lhs = self.0.split_once('-')?.0;
@@ -213,6 +785,17 @@ fn to_new_packages(
             continue;
         }
 
+        if package.version.is_workspace()
+            || package
+                .resolved
+                .as_ref()
+                .is_some_and(UrlOrString::is_workspace)
+        {
+            debug!("skipping workspace dependency {name}");
+
+            continue;
+        }
+
         if let UrlOrString::Url(v) = &package.version {
             if v.scheme() == "npm" {
                 if let Some(UrlOrString::Url(ref url)) = &package.resolved {
@@ -247,14 +830,22 @@ fn to_new_packages(
             }
         }
 
+        // Names aren't unique in v1 lockfiles: `overrides` (or plain nesting) can make the same name resolve to different
+        // URLs at different depths. Packages are keyed (and later deduplicated) on their resolved URL, not their name.
         new.push(Package {
             name: Some(name),
+            version: match &package.version {
+                UrlOrString::String(version) => Some(version.clone()),
+                UrlOrString::Url(_) => None,
+            },
             resolved: if matches!(package.version, UrlOrString::Url(_)) {
                 Some(package.version)
             } else {
                 package.resolved
             },
             integrity: package.integrity,
+            optional: package.optional,
+            ..Package::default()
         });
 
         if let Some(dependencies) = package.dependencies {
@@ -265,36 +856,208 @@ fn to_new_packages(
     Ok(new)
 }
 
-fn get_initial_url() -> anyhow::Result<Url> {
-    Url::parse("git+ssh://git@a.b").context("initial url should be valid")
+fn check_origins(
+    packages: &[Package],
+    options: &super::Options,
+    registry: &Url,
+    check: super::OriginCheck,
+) -> anyhow::Result<()> {
+    let mismatches = origin_mismatches(packages, options, registry);
+
+    match check {
+        _ if mismatches.is_empty() => {}
+        super::OriginCheck::Warn => {
+            for mismatch in &mismatches {
+                warn!("{mismatch}");
+            }
+        }
+        super::OriginCheck::Fail => bail!(
+            "Some registry tarballs aren't served from their package's registry:\n{}",
+            mismatches.join("\n")
+        ),
+    }
+
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        get_initial_url, packages, to_new_packages, Hash, HashCollection, OldPackage, Package,
-        UrlOrString,
-    };
-    use std::{
-        cmp::Ordering,
-        collections::{HashMap, HashSet},
-    };
-    use url::Url;
+/// Returns a description of each registry tarball in `packages` whose host isn't that of its package's registry: the one
+/// for its scope in `options.scope_registries`, or otherwise `registry`.
+///
+/// Tarballs that don't look like they're from a registry (e.g. Git dependencies) aren't checked.
+fn origin_mismatches(
+    packages: &[Package],
+    options: &super::Options,
+    registry: &Url,
+) -> Vec<String> {
+    let mut mismatches = Vec::new();
 
-    #[test]
-    fn git_shorthand_v1() -> anyhow::Result<()> {
-        let old = {
-            let mut o = HashMap::new();
-            o.insert(
-                String::from("sqlite3"),
-                OldPackage {
-                    version: UrlOrString::Url(
+    for package in packages {
+        let Some(UrlOrString::Url(url)) = &package.resolved else {
+            continue;
+        };
+
+        if !matches!(url.scheme(), "http" | "https")
+            || !url.path().contains("/-/")
+            || !Path::new(url.path())
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("tgz"))
+        {
+            continue;
+        }
+
+        let key = package.name.as_deref().unwrap_or_default();
+        let name = package_name(key);
+        let expected = name
+            .split_once('/')
+            .and_then(|(scope, _)| options.scope_registries.get(scope))
+            .unwrap_or(registry);
+
+        if (url.host_str(), url.port_or_known_default())
+            != (expected.host_str(), expected.port_or_known_default())
+        {
+            mismatches.push(format!(
+                "{key} is served from {url}, but its registry is {expected}"
+            ));
+        }
+    }
+
+    mismatches.sort();
+
+    mismatches
+}
+
+/// Returns the names of peers that every package depending on them marks optional in its `peerDependenciesMeta`.
+fn optional_peers(packages: &HashMap<String, Package>) -> HashSet<String> {
+    let mut optional = HashSet::new();
+    let mut required = HashSet::new();
+
+    for package in packages.values() {
+        for name in package.peer_dependencies.keys() {
+            if package
+                .peer_dependencies_meta
+                .get(name)
+                .is_some_and(|meta| meta.optional)
+            {
+                optional.insert(name.as_str());
+            } else {
+                required.insert(name.as_str());
+            }
+        }
+
+        // Peers can be marked optional without being listed in `peerDependencies` at all.
+        for (name, meta) in &package.peer_dependencies_meta {
+            if meta.optional {
+                optional.insert(name.as_str());
+            }
+        }
+    }
+
+    optional
+        .difference(&required)
+        .map(|name| (*name).to_string())
+        .collect()
+}
+
+/// Returns the package name from a `packages` key, i.e. the part after the last `node_modules/`.
+pub(super) fn package_name(key: &str) -> &str {
+    key.rsplit_once("node_modules/")
+        .map_or(key, |(_, name)| name)
+}
+
+/// Fails if any (sorted) packages resolve to the same URL, but with integrity that can't both be right, as they'd be
+/// cached under the same key.
+fn check_duplicates(packages: &[Package]) -> anyhow::Result<()> {
+    for duplicates in packages.chunk_by(|x, y| x.resolved == y.resolved) {
+        for (i, x) in duplicates.iter().enumerate() {
+            for y in &duplicates[i + 1..] {
+                // Sorted, so that the error is the same whichever order the lockfile gave them in.
+                let (x, y) = if x.name <= y.name { (x, y) } else { (y, x) };
+                let (Some(xi), Some(yi)) = (&x.integrity, &y.integrity) else {
+                    continue;
+                };
+
+                if let Some((xh, yh)) = xi.conflict(yi) {
+                    bail!(
+                        "{} and {} both resolve to {}, but with different integrity ({xh} and {yh}), so they can't both be cached",
+                        x.name.as_deref().unwrap_or_default(),
+                        y.name.as_deref().unwrap_or_default(),
+                        x.resolved.as_ref().map(ToString::to_string).unwrap_or_default(),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Drops the packages whose names are in `options.exclude`.
+fn exclude(packages: Vec<Package>, options: &super::Options) -> anyhow::Result<Vec<Package>> {
+    let mut kept = Vec::new();
+
+    for package in packages {
+        let key = package.name.as_deref().unwrap_or_default();
+        let name = key
+            .rsplit_once("node_modules/")
+            .map_or(key, |(_, name)| name);
+
+        if !options.exclude.contains(name) {
+            kept.push(package);
+        } else if package.optional {
+            debug!("excluding {key}");
+        } else if options.strict_exclude {
+            bail!("{key} is excluded, but it's a required dependency");
+        } else {
+            warn!("excluding {key}, which is a required dependency");
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Drops the dependencies of v1 lockfile entries nested deeper than `depth`.
+fn truncate_v1(dependencies: &mut HashMap<String, OldPackage>, depth: usize) {
+    for package in dependencies.values_mut() {
+        match (&mut package.dependencies, depth) {
+            (Some(_), 0) => package.dependencies = None,
+            (Some(nested), _) => truncate_v1(nested, depth - 1),
+            (None, _) => {}
+        }
+    }
+}
+
+fn get_initial_url() -> anyhow::Result<Url> {
+    Url::parse("git+ssh://git@a.b").context("initial url should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{HashPolicy, Omit, Options, OriginCheck},
+        get_initial_url, origin_mismatches, packages, registry_tarball_url, shasum_to_sri,
+        to_new_packages, Hash, HashCollection, OldPackage, Package, UrlOrString,
+    };
+    use std::{
+        cmp::Ordering,
+        collections::{HashMap, HashSet},
+    };
+    use url::Url;
+
+    #[test]
+    fn git_shorthand_v1() -> anyhow::Result<()> {
+        let old = {
+            let mut o = HashMap::new();
+            o.insert(
+                String::from("sqlite3"),
+                OldPackage {
+                    version: UrlOrString::Url(
                         Url::parse(
                             "github:mapbox/node-sqlite3#593c9d498be2510d286349134537e3bf89401c4a",
                         )
                         .unwrap(),
                     ),
                     bundled: false,
+                    optional: false,
                     resolved: None,
                     integrity: None,
                     dependencies: None,
@@ -311,12 +1074,92 @@ mod tests {
         assert_eq!(new[0], Package {
             name: Some(String::from("sqlite3")),
             resolved: Some(UrlOrString::Url(Url::parse("git+ssh://git@github.com/mapbox/node-sqlite3.git#593c9d498be2510d286349134537e3bf89401c4a").unwrap())),
-            integrity: None
+            integrity: None,
+            ..Package::default()
         });
 
         Ok(())
     }
 
+    #[test]
+    fn v1_overrides_keep_distinct_urls() -> anyhow::Result<()> {
+        let packages = packages(
+            r#"{
+                "lockfileVersion": 1,
+                "dependencies": {
+                    "foo": {
+                        "version": "1.0.0",
+                        "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                        "integrity": "sha512-foo1"
+                    },
+                    "bar": {
+                        "version": "1.0.0",
+                        "resolved": "https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                        "integrity": "sha512-bar",
+                        "dependencies": {
+                            "foo": {
+                                "version": "1.0.0",
+                                "resolved": "https://registry.npmjs.org/foo/-/foo-2.0.0.tgz",
+                                "integrity": "sha512-foo2"
+                            }
+                        }
+                    }
+                }
+            }"#,
+            &Options::default(),
+        )?;
+
+        let foos = packages
+            .iter()
+            .filter(|p| p.name.as_deref() == Some("foo"))
+            .map(|p| p.resolved.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            foos,
+            [
+                "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                "https://registry.npmjs.org/foo/-/foo-2.0.0.tgz"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_array() -> anyhow::Result<()> {
+        let string: HashCollection = serde_json::from_str(r#""sha1-foo sha512-bar""#)?;
+        let array: HashCollection = serde_json::from_str(r#"["sha1-foo", "sha512-bar"]"#)?;
+
+        assert_eq!(string, array);
+        assert_eq!(array.into_best(), Some(Hash(String::from("sha512-bar"))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_object() -> anyhow::Result<()> {
+        let object: HashCollection =
+            serde_json::from_str(r#"{ "sha1": "foo", "sha512": "sha512-bar" }"#)?;
+
+        assert_eq!(object, HashCollection::from_str("sha1-foo sha512-bar")?);
+        assert_eq!(object.into_best(), Some(Hash(String::from("sha512-bar"))));
+
+        let package: Package = serde_json::from_str(
+            r#"{
+                "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                "integrity": { "sha512": "bar", "sha1": "foo" }
+            }"#,
+        )?;
+
+        assert_eq!(
+            package.integrity.and_then(HashCollection::into_best),
+            Some(Hash(String::from("sha512-bar")))
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn hash_preference() {
         assert_eq!(
@@ -336,6 +1179,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn legacy_sha1_preference() -> anyhow::Result<()> {
+        let both = || HashCollection::from_str("sha512-foo sha1-bar");
+
+        assert_eq!(
+            both()?.into_preferred(true),
+            Some(Hash(String::from("sha1-bar")))
+        );
+        assert_eq!(
+            both()?.into_preferred(false),
+            Some(Hash(String::from("sha512-foo")))
+        );
+        assert_eq!(
+            HashCollection::from_str("sha512-foo")?.into_preferred(true),
+            Some(Hash(String::from("sha512-foo"))),
+            "the best hash should still be used when there's no sha1"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn min_hash() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/old": {
+                    "resolved": "https://registry.npmjs.org/old/-/old-1.0.0.tgz",
+                    "integrity": "sha1-old"
+                },
+                "node_modules/new": {
+                    "resolved": "https://registry.npmjs.org/new/-/new-1.0.0.tgz",
+                    "integrity": "sha512-new sha1-new"
+                }
+            }
+        }"#;
+        let options = |min: &str, warn| -> anyhow::Result<Options> {
+            Ok(Options {
+                min_hash: Some(HashPolicy::new(min.to_string(), warn)?),
+                ..Options::default()
+            })
+        };
+
+        let err = packages(lockfile, &options("sha512", false)?).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("node_modules/old only has sha1 integrity"),
+            "{err}"
+        );
+        assert!(!err.to_string().contains("node_modules/new"), "{err}");
+        assert_eq!(packages(lockfile, &options("sha512", true)?)?.len(), 2);
+        assert_eq!(packages(lockfile, &options("sha1", false)?)?.len(), 2);
+        assert!(HashPolicy::new(String::from("md5"), false).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn parse_lockfile_correctly() {
         let packages = packages(
@@ -356,7 +1257,9 @@ mod tests {
                         }
                     }
                 }
-            }"#).unwrap();
+            }"#,
+            &Options::default(),
+        ).unwrap();
 
         assert_eq!(packages.len(), 1);
         assert_eq!(
@@ -367,4 +1270,665 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn require_resolved() {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/good": {
+                    "resolved": "https://registry.npmjs.org/good/-/good-1.0.0.tgz",
+                    "integrity": "sha512-good"
+                },
+                "node_modules/broken": {
+                    "version": "1.0.0"
+                },
+                "node_modules/linked": {
+                    "resolved": "packages/linked",
+                    "link": true
+                },
+                "node_modules/good/node_modules/bundled": {
+                    "version": "1.0.0",
+                    "inBundle": true
+                },
+                "packages/linked": {
+                    "version": "1.0.0"
+                }
+            }
+        }"#;
+
+        assert_eq!(packages(lockfile, &Options::default()).unwrap().len(), 1);
+
+        let err = packages(
+            lockfile,
+            &Options {
+                require_resolved: true,
+                ..Options::default()
+            },
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.ends_with(": node_modules/broken"), "{err}");
+    }
+
+    #[test]
+    fn require_resolved_v1() {
+        let err = packages(
+            r#"{
+                "lockfileVersion": 1,
+                "dependencies": {
+                    "broken": { "version": "1.0.0" },
+                    "local": { "version": "file:../local" },
+                    "parent": {
+                        "version": "1.0.0",
+                        "resolved": "https://registry.npmjs.org/parent/-/parent-1.0.0.tgz",
+                        "integrity": "sha512-parent",
+                        "dependencies": {
+                            "nested": { "version": "npm:foo@1.0.0" }
+                        }
+                    }
+                }
+            }"#,
+            &Options {
+                require_resolved: true,
+                ..Options::default()
+            },
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.ends_with(": broken, nested"), "{err}");
+    }
+
+    #[test]
+    fn backslash_keys() -> anyhow::Result<()> {
+        let packages = packages(
+            r#"{
+                "lockfileVersion": 3,
+                "packages": {
+                    "": {},
+                    "node_modules\\@esbuild\\linux-x64": {
+                        "resolved": "https://registry.npmjs.org/@esbuild/linux-x64/-/linux-x64-0.19.0.tgz",
+                        "integrity": "sha512-esbuild"
+                    },
+                    "node_modules\\foo\\node_modules\\bundled": {
+                        "version": "1.0.0",
+                        "inBundle": true
+                    }
+                }
+            }"#,
+            &Options {
+                require_resolved: true,
+                ..Options::default()
+            },
+        )?;
+
+        assert_eq!(packages.len(), 1);
+        assert_eq!(
+            packages[0].name.as_deref(),
+            Some("node_modules/@esbuild/linux-x64")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_lockfile_diagnostics() {
+        let err = |content| {
+            packages(content, &Options::default())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(err(""), "The lockfile is empty.");
+        assert_eq!(err(" \n\t"), "The lockfile is empty.");
+        assert_eq!(
+            err("{ lockfileVersion: 3"),
+            "The lockfile is not valid JSON"
+        );
+        assert!(err("{}").contains("neither `packages` nor `dependencies`"));
+        assert!(
+            err(r#"{ "lockfileVersion": 3 }"#).contains("neither `packages` nor `dependencies`")
+        );
+
+        assert!(packages(
+            r#"{ "lockfileVersion": 1 }"#,
+            &Options {
+                force_empty_cache: true,
+                ..Options::default()
+            }
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn dist_object() -> anyhow::Result<()> {
+        let packages = packages(
+            r#"{
+                "lockfileVersion": 2,
+                "packages": {
+                    "node_modules/foo": {
+                        "version": "1.0.0",
+                        "dist": {
+                            "tarball": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                            "integrity": "sha512-foo"
+                        }
+                    },
+                    "node_modules/bar": {
+                        "version": "1.0.0",
+                        "dist": {
+                            "tarball": "https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                            "shasum": "f1d2d2f924e986ac86fdf7b36c94bcdf32beec15"
+                        }
+                    },
+                    "node_modules/baz": {
+                        "resolved": "https://registry.npmjs.org/baz/-/baz-2.0.0.tgz",
+                        "integrity": "sha512-flat",
+                        "dist": {
+                            "tarball": "https://registry.npmjs.org/baz/-/baz-1.0.0.tgz",
+                            "integrity": "sha512-dist"
+                        }
+                    }
+                }
+            }"#,
+            &Options::default(),
+        )?;
+
+        let get = |name: &str| {
+            packages
+                .iter()
+                .find(|p| p.name.as_deref() == Some(name))
+                .unwrap()
+        };
+
+        assert_eq!(
+            get("node_modules/foo")
+                .resolved
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz"
+        );
+        assert_eq!(
+            get("node_modules/foo").integrity,
+            Some(HashCollection::from_str("sha512-foo")?)
+        );
+        assert_eq!(
+            get("node_modules/bar").integrity,
+            Some(HashCollection::from_str(
+                "sha1-8dLS+STphqyG/fezbJS83zK+7BU="
+            )?)
+        );
+        assert_eq!(
+            get("node_modules/baz")
+                .resolved
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "https://registry.npmjs.org/baz/-/baz-2.0.0.tgz",
+            "flat fields should take precedence"
+        );
+        assert_eq!(
+            get("node_modules/baz").integrity,
+            Some(HashCollection::from_str("sha512-flat")?)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shasum_conversion() {
+        assert_eq!(
+            shasum_to_sri("f1d2d2f924e986ac86fdf7b36c94bcdf32beec15").unwrap(),
+            "sha1-8dLS+STphqyG/fezbJS83zK+7BU="
+        );
+        assert!(shasum_to_sri("f1d2").is_err());
+        assert!(shasum_to_sri("zzd2d2f924e986ac86fdf7b36c94bcdf32beec15").is_err());
+    }
+
+    #[test]
+    fn npm_shorthand() -> anyhow::Result<()> {
+        let registry = Url::parse("https://registry.example.com/npm")?;
+
+        assert_eq!(
+            registry_tarball_url(&registry, "@scope/foo@1.2.3-beta.1")?.as_str(),
+            "https://registry.example.com/npm/@scope/foo/-/foo-1.2.3-beta.1.tgz"
+        );
+        assert!(registry_tarball_url(&registry, "foo@^1.0.0").is_err());
+        assert!(registry_tarball_url(&registry, "@scope/foo").is_err());
+
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/foo": {
+                    "version": "1.0.0",
+                    "resolved": "npm:foo@1.0.0",
+                    "integrity": "sha512-foo"
+                }
+            }
+        }"#;
+        let packages = packages(lockfile, &Options::default())?;
+
+        assert_eq!(
+            packages[0].resolved,
+            Some(UrlOrString::Url(Url::parse(
+                "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz"
+            )?))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_entry_is_skipped() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/good": {
+                    "resolved": "https://registry.npmjs.org/good/-/good-1.0.0.tgz",
+                    "integrity": "sha512-good",
+                    "funding": { "url": "https://example.com/fund" },
+                    "engines": ["node >= 18"]
+                },
+                "node_modules/bad": {
+                    "resolved": { "url": "https://registry.npmjs.org/bad/-/bad-1.0.0.tgz" },
+                    "integrity": "sha512-bad"
+                }
+            }
+        }"#;
+
+        let packages = packages(lockfile, &Options::default())?;
+
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name.as_deref(), Some("node_modules/good"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn depth() -> anyhow::Result<()> {
+        let v1 = r#"{
+            "lockfileVersion": 1,
+            "dependencies": {
+                "foo": {
+                    "version": "1.0.0",
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo",
+                    "dependencies": {
+                        "bar": {
+                            "version": "1.0.0",
+                            "resolved": "https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                            "integrity": "sha512-bar"
+                        }
+                    }
+                }
+            }
+        }"#;
+        let v3 = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/foo/node_modules/bar": {
+                    "resolved": "https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                    "integrity": "sha512-bar"
+                }
+            }
+        }"#;
+
+        for lockfile in [v1, v3] {
+            let direct = packages(
+                lockfile,
+                &Options {
+                    depth: Some(0),
+                    ..Options::default()
+                },
+            )?;
+
+            assert_eq!(direct.len(), 1);
+            assert!(direct[0].name.as_deref().unwrap().ends_with("foo"));
+
+            assert_eq!(packages(lockfile, &Options::default())?.len(), 2);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn excluded_packages() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/public": {
+                    "resolved": "https://registry.npmjs.org/public/-/public-1.0.0.tgz",
+                    "integrity": "sha512-public"
+                },
+                "node_modules/@internal/required": {
+                    "resolved": "https://registry.npmjs.org/@internal/required/-/required-1.0.0.tgz",
+                    "integrity": "sha512-required"
+                },
+                "node_modules/public/node_modules/@internal/optional": {
+                    "resolved": "https://registry.npmjs.org/@internal/optional/-/optional-1.0.0.tgz",
+                    "integrity": "sha512-optional",
+                    "optional": true
+                }
+            }
+        }"#;
+        let options = |strict_exclude| Options {
+            exclude: HashSet::from([
+                String::from("@internal/required"),
+                String::from("@internal/optional"),
+            ]),
+            strict_exclude,
+            ..Options::default()
+        };
+
+        let packages = packages(lockfile, &options(false))?;
+
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name.as_deref(), Some("node_modules/public"));
+
+        let err = super::packages(lockfile, &options(true)).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "node_modules/@internal/required is excluded, but it's a required dependency"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn origin_check() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/public": {
+                    "resolved": "https://registry.npmjs.org/public/-/public-1.0.0.tgz",
+                    "integrity": "sha512-public"
+                },
+                "node_modules/@corp/good": {
+                    "resolved": "https://npm.corp.example.com/@corp/good/-/good-1.0.0.tgz",
+                    "integrity": "sha512-good"
+                },
+                "node_modules/@corp/evil": {
+                    "resolved": "https://registry.npmjs.org/@corp/evil/-/evil-1.0.0.tgz",
+                    "integrity": "sha512-evil"
+                },
+                "node_modules/git": {
+                    "resolved": "https://codeload.github.com/foo/git/tar.gz/main"
+                }
+            }
+        }"#;
+        let corp = Url::parse("https://npm.corp.example.com/")?;
+        let options = |check_origins| Options {
+            scope_registries: HashMap::from([(String::from("@corp"), corp.clone())]),
+            check_origins: Some(check_origins),
+            ..Options::default()
+        };
+
+        let mismatches = origin_mismatches(
+            &packages(lockfile, &options(OriginCheck::Warn))?,
+            &options(OriginCheck::Warn),
+            &Url::parse("https://registry.npmjs.org/")?,
+        );
+
+        assert_eq!(
+            mismatches,
+            ["node_modules/@corp/evil is served from https://registry.npmjs.org/@corp/evil/-/evil-1.0.0.tgz, but its registry is https://npm.corp.example.com/"]
+        );
+
+        let err = packages(lockfile, &options(OriginCheck::Fail)).unwrap_err();
+
+        assert!(err.to_string().contains(&mismatches[0]), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn optional_peers() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/plugin": {
+                    "resolved": "https://registry.npmjs.org/plugin/-/plugin-1.0.0.tgz",
+                    "integrity": "sha512-plugin",
+                    "peerDependencies": {
+                        "host": "^1.0.0",
+                        "typescript": "^5.0.0"
+                    },
+                    "peerDependenciesMeta": {
+                        "typescript": { "optional": true },
+                        "canvas": { "optional": true }
+                    }
+                },
+                "node_modules/other-plugin": {
+                    "resolved": "https://registry.npmjs.org/other-plugin/-/other-plugin-1.0.0.tgz",
+                    "integrity": "sha512-other-plugin",
+                    "peerDependencies": {
+                        "shared": "^1.0.0"
+                    }
+                },
+                "node_modules/third-plugin": {
+                    "resolved": "https://registry.npmjs.org/third-plugin/-/third-plugin-1.0.0.tgz",
+                    "integrity": "sha512-third-plugin",
+                    "peerDependencies": {
+                        "shared": "^1.0.0"
+                    },
+                    "peerDependenciesMeta": {
+                        "shared": { "optional": true }
+                    }
+                },
+                "node_modules/host": {
+                    "resolved": "https://registry.npmjs.org/host/-/host-1.0.0.tgz",
+                    "integrity": "sha512-host",
+                    "peer": true
+                },
+                "node_modules/typescript": {
+                    "resolved": "https://registry.npmjs.org/typescript/-/typescript-5.0.0.tgz",
+                    "integrity": "sha512-typescript",
+                    "peer": true
+                },
+                "node_modules/canvas": {
+                    "resolved": "https://registry.npmjs.org/canvas/-/canvas-2.0.0.tgz",
+                    "integrity": "sha512-canvas",
+                    "peer": true
+                },
+                "node_modules/shared": {
+                    "resolved": "https://registry.npmjs.org/shared/-/shared-1.0.0.tgz",
+                    "integrity": "sha512-shared",
+                    "peer": true
+                }
+            }
+        }"#;
+        let names = |omit: &[Omit]| -> anyhow::Result<Vec<String>> {
+            let options = Options {
+                omit: omit.iter().copied().collect(),
+                ..Options::default()
+            };
+            let mut names = packages(lockfile, &options)?
+                .into_iter()
+                .filter_map(|p| p.name)
+                .collect::<Vec<_>>();
+
+            names.sort();
+
+            Ok(names)
+        };
+
+        assert_eq!(names(&[])?.len(), 7);
+
+        for omit in [Omit::Peer, Omit::Optional] {
+            assert_eq!(
+                names(&[omit])?,
+                [
+                    "node_modules/host",
+                    "node_modules/other-plugin",
+                    "node_modules/plugin",
+                    "node_modules/shared",
+                    "node_modules/third-plugin",
+                ]
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn comma_separated_integrity() -> anyhow::Result<()> {
+        for integrity in [
+            "sha1-bar,sha512-foo",
+            "sha1-bar, sha512-foo",
+            ",sha512-foo,sha1-bar,",
+            "sha1-bar sha512-foo",
+        ] {
+            assert_eq!(
+                HashCollection::from_str(integrity)?.into_best(),
+                Some(Hash(String::from("sha512-foo"))),
+                "{integrity}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn bare_resolved_paths() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/foo": {
+                    "resolved": "foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/@scope/bar": {
+                    "resolved": "/@scope/bar/-/bar-2.0.0.tgz",
+                    "integrity": "sha512-bar"
+                }
+            }
+        }"#;
+        let options = Options {
+            registry: Some(Url::parse("https://artifacts.example.com/api/npm")?),
+            ..Options::default()
+        };
+
+        let mut resolved = packages(lockfile, &options)?
+            .into_iter()
+            .map(|p| p.resolved.unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        resolved.sort();
+
+        assert_eq!(
+            resolved,
+            [
+                "https://artifacts.example.com/api/npm/@scope/bar/-/bar-2.0.0.tgz",
+                "https://artifacts.example.com/api/npm/foo/-/foo-1.0.0.tgz",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_duplicates() -> anyhow::Result<()> {
+        let lockfile = |integrity| {
+            format!(
+                r#"{{
+                    "lockfileVersion": 3,
+                    "packages": {{
+                        "": {{}},
+                        "node_modules/foo": {{
+                            "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                            "integrity": "sha512-foo"
+                        }},
+                        "node_modules/bar/node_modules/foo": {{
+                            "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                            "integrity": "{integrity}"
+                        }}
+                    }}
+                }}"#
+            )
+        };
+
+        assert_eq!(
+            packages(&lockfile("sha512-foo"), &Options::default())?.len(),
+            1
+        );
+        assert_eq!(
+            packages(&lockfile("sha1-foo sha512-foo"), &Options::default())?.len(),
+            1,
+            "hashes with different algorithms don't conflict"
+        );
+
+        let err = packages(&lockfile("sha512-bar"), &Options::default())
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            err,
+            "node_modules/bar/node_modules/foo and node_modules/foo both resolve to https://registry.npmjs.org/foo/-/foo-1.0.0.tgz, but with different integrity (sha512-bar and sha512-foo), so they can't both be cached"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn workspace_dependencies() -> anyhow::Result<()> {
+        let v1 = r#"{
+            "lockfileVersion": 1,
+            "dependencies": {
+                "foo": {
+                    "version": "1.0.0",
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "@app/shared": {
+                    "version": "workspace:^1.0.0"
+                },
+                "@app/utils": {
+                    "version": "1.0.0",
+                    "resolved": "workspace:packages/utils"
+                }
+            }
+        }"#;
+        let v3 = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/@app/shared": {
+                    "version": "workspace:*",
+                    "resolved": "https://registry.npmjs.org/@app/shared/-/shared-1.0.0.tgz"
+                },
+                "node_modules/@app/utils": {
+                    "resolved": "workspace:packages/utils"
+                }
+            }
+        }"#;
+
+        for lockfile in [v1, v3] {
+            let packages = packages(lockfile, &Options::default())?;
+
+            assert_eq!(packages.len(), 1);
+            assert!(packages[0].name.as_deref().unwrap().ends_with("foo"));
+        }
+
+        Ok(())
+    }
 }
@@ -4,35 +4,160 @@ use log::{debug, info};
 use rayon::prelude::*;
 use serde_json::{Map, Value};
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
     io::Write,
     process::{Command, Stdio},
+    str::FromStr,
 };
-use tempfile::{tempdir, TempDir};
+use tempfile::TempDir;
 use url::Url;
 
-use crate::util;
+use crate::{config, util};
 
 pub mod lock;
+mod platform;
+mod signatures;
 
-pub fn lockfile(
-    content: &str,
-    force_git_deps: bool,
-    force_empty_cache: bool,
-) -> anyhow::Result<Vec<Package>> {
+pub use platform::Platform;
+
+#[derive(Clone, Debug, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Options {
+    pub force_git_deps: bool,
+    pub force_empty_cache: bool,
+    /// If set, per-platform prebuilt binary packages for other platforms are skipped.
+    pub platform: Option<Platform>,
+    /// If set, dependencies that should have a `resolved` URL but don't are an error, rather than being skipped.
+    pub require_resolved: bool,
+    /// If set, sha1 integrity is used over stronger hashes, for old registries that only serve sha1.
+    pub legacy_sha1: bool,
+    /// Corrected integrities for packages, by their key in the lockfile, which take precedence over the lockfile's own.
+    pub integrity_overrides: HashMap<String, String>,
+    /// The registry to resolve `npm:` shorthands in `resolved` fields against, if not the public npm registry.
+    pub registry: Option<Url>,
+    /// If set, only dependencies nested at most this deep are fetched (0 being direct dependencies).
+    pub depth: Option<usize>,
+    /// Names of packages that are provided out-of-band, and so are never fetched.
+    pub exclude: HashSet<String>,
+    /// If set, excluding a dependency that isn't optional is an error, rather than a warning.
+    pub strict_exclude: bool,
+    /// Registries for scoped packages (e.g. `@scope`), which take precedence over `registry`.
+    pub scope_registries: HashMap<String, Url>,
+    /// If set, what to do about registry tarballs that aren't served from their package's registry.
+    pub check_origins: Option<OriginCheck>,
+    /// Kinds of dependencies to leave out, as with npm's `--omit`.
+    pub omit: HashSet<Omit>,
+    /// If set, the weakest hash algorithm dependencies' integrity may be stored with.
+    pub min_hash: Option<HashPolicy>,
+    /// If set, Git dependencies resolved over SSH that can't be fetched as HTTPS archives are rejected up front.
+    pub require_https_git: bool,
+    /// If set, the registry signatures of dependencies that have them (in `dist.signatures`) are checked against the
+    /// registry's public keys.
+    pub verify_signatures: bool,
+    /// If set, the `resolved` URLs of Git dependencies are recorded in their cache entries alongside the URLs they're
+    /// fetched from. Off by default, as it changes the cache's (and so fixed-output derivations') hash.
+    pub record_resolved: bool,
+}
+
+/// The weakest hash algorithm allowed for dependencies' integrity, and whether dependencies whose integrity is only
+/// available with a weaker one are just warned about, rather than rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashPolicy {
+    pub min: String,
+    pub warn: bool,
+}
+
+impl HashPolicy {
+    pub fn new(min: String, warn: bool) -> anyhow::Result<HashPolicy> {
+        if lock::ALGOS.contains(&min.as_str()) {
+            Ok(HashPolicy { min, warn })
+        } else {
+            Err(anyhow!(
+                "unknown hash algorithm {min:?}, expected one of {}",
+                lock::ALGOS.join(", ")
+            ))
+        }
+    }
+}
+
+/// What to do about a registry tarball that isn't served from its package's registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OriginCheck {
+    Warn,
+    Fail,
+}
+
+impl FromStr for OriginCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<OriginCheck> {
+        match s {
+            "warn" => Ok(OriginCheck::Warn),
+            "fail" => Ok(OriginCheck::Fail),
+            _ => Err(anyhow!(
+                "unknown origin check {s:?}, expected \"warn\" or \"fail\""
+            )),
+        }
+    }
+}
+
+/// A kind of dependency that can be left out with `--omit`.
+///
+/// Only optional peers (per their dependents' `peerDependenciesMeta`) are dropped for either kind; required peers are
+/// always kept, as the packages depending on them won't work without them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Omit {
+    Optional,
+    Peer,
+}
+
+impl FromStr for Omit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Omit> {
+        match s {
+            "optional" => Ok(Omit::Optional),
+            "peer" => Ok(Omit::Peer),
+            _ => Err(anyhow!(
+                "unknown dependency kind to omit {s:?}, expected \"optional\" or \"peer\""
+            )),
+        }
+    }
+}
+
+pub fn lockfile(content: &str, options: &Options) -> anyhow::Result<Vec<Package>> {
     debug!("parsing lockfile with contents:\n{content}");
 
-    let mut packages = lock::packages(content)
+    let packages = lock::packages(content, options)
         .context("failed to extract packages from lockfile")?
+        .into_iter()
+        .filter(|p| for_platform(p, options))
+        .collect::<Vec<_>>();
+
+    if options.require_https_git {
+        check_https_git(&packages)?;
+    }
+
+    if options.verify_signatures {
+        let registry = match &options.registry {
+            Some(registry) => registry.clone(),
+            None => Url::parse(lock::DEFAULT_REGISTRY)?,
+        };
+
+        signatures::verify(&packages, options, &registry)?;
+    }
+
+    let mut packages = packages
         .into_par_iter()
         .map(|p| {
             let n = p.name.clone().unwrap();
 
-            Package::from_lock(p).with_context(|| format!("failed to parse data for {n}"))
+            Package::from_lock(p, options).with_context(|| format!("failed to parse data for {n}"))
         })
         .collect::<anyhow::Result<Vec<_>>>()?;
 
-    if packages.is_empty() && !force_empty_cache {
+    if packages.is_empty() && !options.force_empty_cache {
         bail!("No cacheable dependencies were found. Please inspect the upstream `package-lock.json` file and ensure that remote dependencies have `resolved` URLs and `integrity` hashes. If the lockfile is missing this data, attempt to get upstream to fix it via a tool like <https://github.com/jeslie0/npm-lockfile-fix>. If generating an empty cache is intentional and you would like to do it anyways, set `forceEmptyCache = true`.");
     }
 
@@ -70,7 +195,10 @@ pub fn lockfile(
                 "prepack",
                 "prepare",
             ] {
-                if scripts.contains_key(typ) && lockfile_contents.is_err() && !force_git_deps {
+                if scripts.contains_key(typ)
+                    && lockfile_contents.is_err()
+                    && !options.force_git_deps
+                {
                     bail!("Git dependency {} contains install scripts, but has no lockfile, which is something that will probably break. Open an issue if you can't feasibly patch this dependency out, and we'll come up with a workaround.\nIf you'd like to attempt to try to use this dependency anyways, set `forceGitDeps = true`.", pkg.name);
                 }
             }
@@ -79,10 +207,12 @@ pub fn lockfile(
         if let Ok(lockfile_contents) = lockfile_contents {
             new.append(&mut lockfile(
                 &lockfile_contents,
-                force_git_deps,
-                // force_empty_cache is turned on here since recursively parsed lockfiles should be
-                // allowed to have an empty cache without erroring by default
-                true,
+                &Options {
+                    // force_empty_cache is turned on here since recursively parsed lockfiles should be
+                    // allowed to have an empty cache without erroring by default
+                    force_empty_cache: true,
+                    ..options.clone()
+                },
             )?);
         }
     }
@@ -100,10 +230,334 @@ pub fn lockfile(
     Ok(packages)
 }
 
+/// Returns the registry dependencies in `content`, without fetching anything.
+///
+/// Git dependencies are skipped, as their lockfile entries have no integrity, and finding their own dependencies would
+/// mean fetching them.
+pub fn registry_lockfile(content: &str, options: &Options) -> anyhow::Result<Vec<Package>> {
+    let mut packages = Vec::new();
+
+    for pkg in lock::packages(content, options)
+        .context("failed to extract packages from lockfile")?
+        .into_iter()
+        .filter(|p| for_platform(p, options))
+    {
+        let name = pkg.name.unwrap();
+        let Some(UrlOrString::Url(url)) = pkg.resolved else {
+            panic!("at this point, all packages should have URLs");
+        };
+
+        if get_hosted_git_url(&url)?.is_some() {
+            info!("skipping git dependency {name}");
+
+            continue;
+        }
+
+        let integrity = pkg
+            .integrity
+            .and_then(|integrity| integrity.into_preferred(options.legacy_sha1))
+            .ok_or_else(|| anyhow!("{name} has no integrity in the lockfile"))?;
+
+        packages.push(Package {
+            name,
+            version: pkg.version,
+            url,
+            original_url: None,
+            specifics: Specifics::Registry { integrity },
+        });
+    }
+
+    packages.sort_by(|x, y| x.url.cmp(&y.url));
+    packages.dedup_by(|x, y| x.url == y.url);
+
+    Ok(packages)
+}
+
+/// Fails naming each package resolved over SSH (e.g. `git+ssh://`) that isn't on a host whose archives can be fetched
+/// over HTTPS instead, for environments without SSH access.
+fn check_https_git(packages: &[lock::Package]) -> anyhow::Result<()> {
+    let mut unfetchable = packages
+        .iter()
+        .filter_map(|p| match &p.resolved {
+            Some(UrlOrString::Url(url))
+                if (url.scheme() == "ssh" || url.scheme().ends_with("+ssh"))
+                    && !get_hosted_git_url(url)
+                        .is_ok_and(|u| u.is_some_and(|u| u.scheme() == "https")) =>
+            {
+                Some(format!("{} ({url})", p.name.as_deref().unwrap_or_default()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if unfetchable.is_empty() {
+        return Ok(());
+    }
+
+    unfetchable.sort();
+
+    bail!(
+        "The following Git dependencies are resolved over SSH, and can't be fetched over HTTPS instead:\n{}",
+        unfetchable.join("\n")
+    )
+}
+
+/// Returns the package manager pinned by the `packageManager` field of `package_json` (as corepack uses it), if it has one,
+/// so that it can be fetched along with the lockfile's dependencies.
+pub fn package_manager(package_json: &str, options: &Options) -> anyhow::Result<Option<Package>> {
+    let package_json: Value = serde_json::from_str(package_json)?;
+
+    let Some(spec) = package_json.get("packageManager").and_then(Value::as_str) else {
+        return Ok(None);
+    };
+
+    let Some((name, version, url, integrity)) =
+        lock::package_manager(spec, options.registry.as_ref())?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(Package {
+        name,
+        version: Some(version),
+        url,
+        original_url: None,
+        specifics: Specifics::Registry { integrity },
+    }))
+}
+
+/// Where a dependency is fetched from, as far as whether we support it is concerned.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Source {
+    pub scheme: String,
+    pub host: String,
+    pub supported: bool,
+}
+
+/// Counts the dependencies in `content` by their [`Source`], without fetching anything.
+pub fn sources(content: &str, options: &Options) -> anyhow::Result<BTreeMap<Source, usize>> {
+    let mut sources = BTreeMap::new();
+
+    for pkg in
+        lock::packages(content, options).context("failed to extract packages from lockfile")?
+    {
+        let Some(UrlOrString::Url(url)) = pkg.resolved else {
+            continue;
+        };
+
+        let supported = match url.scheme() {
+            "http" | "https" => true,
+            "s3" => config::get().s3_endpoint.is_some(),
+            _ => get_hosted_git_url(&url).is_ok_and(|hosted| hosted.is_some()),
+        };
+
+        let source = Source {
+            scheme: url.scheme().to_string(),
+            host: url.host_str().unwrap_or_default().to_string(),
+            supported,
+        };
+
+        *sources.entry(source).or_default() += 1;
+    }
+
+    Ok(sources)
+}
+
+/// Groups the names of the dependencies in `content` by their `resolved` URL, keeping only the URLs that more than one
+/// dependency resolves to (each of which is only downloaded once).
+pub fn duplicates(
+    content: &str,
+    options: &Options,
+) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let mut urls = BTreeMap::<_, Vec<_>>::new();
+
+    for pkg in lock::entries(content, options)
+        .context("failed to extract packages from lockfile")?
+        .into_iter()
+        .filter(|p| for_platform(p, options))
+    {
+        let Some(resolved) = &pkg.resolved else {
+            continue;
+        };
+
+        urls.entry(resolved.to_string())
+            .or_default()
+            .push(pkg.name.unwrap_or_default());
+    }
+
+    urls.retain(|_, names| names.len() > 1);
+
+    for names in urls.values_mut() {
+        names.sort();
+    }
+
+    Ok(urls)
+}
+
+/// Returns a warning for each of `package_json`'s `overrides` that `content` has no entry for: no package of its name at
+/// all, or (for overrides to an exact version) none of that version.
+pub fn missing_overrides(
+    content: &str,
+    package_json: &str,
+    options: &Options,
+) -> anyhow::Result<Vec<String>> {
+    let package_json: Value = serde_json::from_str(package_json)?;
+    let mut overrides = Vec::new();
+
+    if let Some(map) = package_json.get("overrides").and_then(Value::as_object) {
+        collect_overrides(map, &mut overrides);
+    }
+
+    let mut versions = HashMap::<String, HashSet<Option<String>>>::new();
+
+    for pkg in
+        lock::entries(content, options).context("failed to extract packages from lockfile")?
+    {
+        let name = lock::package_name(pkg.name.as_deref().unwrap_or_default()).to_string();
+
+        versions.entry(name).or_default().insert(pkg.version);
+    }
+
+    let mut missing = overrides
+        .into_iter()
+        .filter(|(name, spec)| {
+            let exact = spec.starts_with(|c: char| c.is_ascii_digit())
+                && spec
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'));
+
+            match versions.get(name) {
+                None => true,
+                Some(found) => exact && !found.contains(&Some(spec.clone())),
+            }
+        })
+        .map(|(name, spec)| {
+            format!("the override of {name} to {spec} in package.json has no matching entry in the lockfile")
+        })
+        .collect::<Vec<_>>();
+
+    missing.sort();
+    missing.dedup();
+
+    Ok(missing)
+}
+
+/// Collects the name and spec of each override in npm's `overrides`, where overrides may be nested under the package
+/// they apply within (with `.` overriding that package itself), and names may be followed by the versions they apply to.
+fn collect_overrides(overrides: &Map<String, Value>, out: &mut Vec<(String, String)>) {
+    for (selector, value) in overrides {
+        let name = selector
+            .rfind('@')
+            .filter(|&i| i > 0)
+            .map_or(selector.as_str(), |i| &selector[..i])
+            .to_string();
+
+        match value {
+            Value::String(spec) if selector != "." => out.push((name, spec.clone())),
+            Value::Object(nested) => {
+                if let Some(Value::String(spec)) = nested.get(".") {
+                    out.push((name, spec.clone()));
+                }
+
+                collect_overrides(nested, out);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A format to emit a dependency graph in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<GraphFormat> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(anyhow!(
+                "unknown graph format {s:?}, expected \"dot\" or \"json\""
+            )),
+        }
+    }
+}
+
+/// Renders the dependency tree in `content` as a graph, without fetching anything. Nodes are `packages` keys, with the
+/// root package being `""`.
+pub fn graph(content: &str, format: GraphFormat) -> anyhow::Result<String> {
+    use std::fmt::Write as _;
+
+    let edges = lock::edges(content).context("failed to extract the dependency graph")?;
+
+    Ok(match format {
+        GraphFormat::Dot => {
+            let mut dot = String::from("digraph dependencies {\n");
+
+            for (parent, child) in &edges {
+                writeln!(dot, "  {parent:?} -> {child:?};")?;
+            }
+
+            dot.push_str("}\n");
+            dot
+        }
+        GraphFormat::Json => serde_json::to_string_pretty(
+            &edges
+                .into_iter()
+                .map(|(parent, child)| serde_json::json!({ "from": parent, "to": child }))
+                .collect::<Vec<_>>(),
+        )?,
+    })
+}
+
+/// Maps each dependency's `resolved` URL in `content` to the URL it's fetched (and thus cached) from, which differs for Git
+/// dependencies, without fetching anything.
+pub fn fetch_urls(content: &str, options: &Options) -> anyhow::Result<BTreeMap<String, Url>> {
+    let mut urls = BTreeMap::new();
+
+    for pkg in lock::packages(content, options)
+        .context("failed to extract packages from lockfile")?
+        .into_iter()
+        .filter(|p| for_platform(p, options))
+    {
+        let Some(UrlOrString::Url(url)) = pkg.resolved else {
+            continue;
+        };
+
+        let fetched = get_hosted_git_url(&url)?.unwrap_or_else(|| url.clone());
+
+        urls.insert(url.to_string(), fetched);
+    }
+
+    Ok(urls)
+}
+
+fn for_platform(package: &lock::Package, options: &Options) -> bool {
+    match &options.platform {
+        Some(platform) if !platform.matches(package) => {
+            info!(
+                "skipping {} as it's for another platform",
+                package.name.as_deref().unwrap_or_default()
+            );
+
+            false
+        }
+        _ => true,
+    }
+}
+
 #[derive(Debug)]
 pub struct Package {
     pub name: String,
+    pub version: Option<String>,
     pub url: Url,
+    /// The `resolved` URL from the lockfile, if it was rewritten into `url` (as Git dependencies' are), and
+    /// [`Options::record_resolved`] is set.
+    pub original_url: Option<Url>,
     specifics: Specifics,
 }
 
@@ -114,7 +568,7 @@ enum Specifics {
 }
 
 impl Package {
-    fn from_lock(pkg: lock::Package) -> anyhow::Result<Package> {
+    fn from_lock(pkg: lock::Package, options: &Options) -> anyhow::Result<Package> {
         let mut resolved = match pkg
             .resolved
             .expect("at this point, packages should have URLs")
@@ -123,11 +577,13 @@ impl Package {
             UrlOrString::String(_) => panic!("at this point, all packages should have URLs"),
         };
 
+        let mut original_url = None;
+
         let specifics = match get_hosted_git_url(&resolved)? {
             Some(hosted) => {
                 let body = util::get_url_body_with_retry(&hosted)?;
 
-                let workdir = tempdir()?;
+                let workdir = util::tempdir()?;
 
                 let tar_path = workdir.path().join("package");
 
@@ -151,7 +607,11 @@ impl Package {
                     );
                 }
 
-                resolved = hosted;
+                let original = std::mem::replace(&mut resolved, hosted);
+
+                if options.record_resolved {
+                    original_url = Some(original);
+                }
 
                 Specifics::Git { workdir }
             }
@@ -159,21 +619,25 @@ impl Package {
                 integrity: pkg
                     .integrity
                     .expect("non-git dependencies should have associated integrity")
-                    .into_best()
+                    .into_preferred(options.legacy_sha1)
                     .expect("non-git dependencies should have non-empty associated integrity"),
             },
         };
 
         Ok(Package {
             name: pkg.name.unwrap(),
+            version: pkg.version,
             url: resolved,
+            original_url,
             specifics,
         })
     }
 
     pub fn tarball(&self) -> anyhow::Result<Vec<u8>> {
         match &self.specifics {
-            Specifics::Registry { .. } => Ok(util::get_url_body_with_retry(&self.url)?),
+            Specifics::Registry { .. } => Ok(util::get_url_body_with_retry(
+                &util::without_fragment(&self.url),
+            )?),
             Specifics::Git { workdir } => Ok(Command::new("tar")
                 .args([
                     "--sort=name",
@@ -202,19 +666,40 @@ impl Package {
     }
 }
 
-#[allow(clippy::case_sensitive_file_extension_comparisons)]
 fn get_hosted_git_url(url: &Url) -> anyhow::Result<Option<Url>> {
-    if ["git", "git+ssh", "git+https", "ssh"].contains(&url.scheme()) {
+    get_hosted_git_url_with(
+        url,
+        &config::get()
+            .gitlab_hosts
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Like [`get_hosted_git_url`], but with `gitlab_hosts` being the hosts of self-hosted GitLab instances, which are
+/// fetched through GitLab's archive endpoint.
+#[allow(clippy::case_sensitive_file_extension_comparisons)]
+fn get_hosted_git_url_with(url: &Url, gitlab_hosts: &[&str]) -> anyhow::Result<Option<Url>> {
+    if ["git", "git+ssh", "git+https", "ssh", "hg+ssh", "hg+https"].contains(&url.scheme()) {
         let mut s = url
             .path_segments()
             .ok_or_else(|| anyhow!("bad URL: {url}"))?;
 
-        let mut get_url = || match url.host_str()? {
+        let host = normalize_host(url.host_str().unwrap_or_default());
+
+        let mut get_url = || match host.as_str() {
+            // Listed hosts take precedence, so that gitlab.com can be opted into.
+            host if gitlab_hosts.iter().any(|h| normalize_host(h) == host) => {
+                gitlab_archive_url(url, host)
+            }
             "github.com" => {
                 let user = s.next()?;
                 let mut project = s.next()?;
                 let typ = s.next();
-                let mut commit = s.next();
+                // Branch names may contain slashes, so the ref is everything after `tree`.
+                let tree_ref = s.by_ref().collect::<Vec<_>>().join("/");
+                let mut commit = Some(tree_ref.as_str()).filter(|r| !r.is_empty());
 
                 if typ.is_none() {
                     commit = url.fragment();
@@ -226,7 +711,8 @@ fn get_hosted_git_url(url: &Url) -> anyhow::Result<Option<Url>> {
                     project = project.strip_suffix(".git")?;
                 }
 
-                let commit = commit.unwrap();
+                // Without a ref (e.g. a bare `tree/`, or no fragment), there's nothing to download an archive of.
+                let commit = commit.filter(|c| !c.is_empty())?;
 
                 Some(
                     Url::parse(&format!(
@@ -238,10 +724,16 @@ fn get_hosted_git_url(url: &Url) -> anyhow::Result<Option<Url>> {
             "bitbucket.org" => {
                 let user = s.next()?;
                 let mut project = s.next()?;
-                let aux = s.next();
 
-                if aux == Some("get") {
-                    return None;
+                // `/{user}/{project}/get/{commit}.tar.gz` is a download URL, rather than a repository, but other paths
+                // may contain a `get` segment too.
+                if let ["get", archive] = s.by_ref().collect::<Vec<_>>()[..] {
+                    if [".tar.gz", ".tar.bz2", ".zip"]
+                        .iter()
+                        .any(|ext| archive.ends_with(ext))
+                    {
+                        return None;
+                    }
                 }
 
                 if project.ends_with(".git") {
@@ -305,6 +797,24 @@ fn get_hosted_git_url(url: &Url) -> anyhow::Result<Option<Url>> {
                     .ok()?,
                 )
             }
+            "hg.sr.ht" => {
+                let user = s.next()?;
+                let project = s.next()?;
+                let aux = s.next();
+
+                if aux == Some("archive") {
+                    return None;
+                }
+
+                let commit = url.fragment()?;
+
+                Some(
+                    Url::parse(&format!(
+                        "https://hg.sr.ht/{user}/{project}/archive/{commit}.tar.gz"
+                    ))
+                    .ok()?,
+                )
+            }
             _ => None,
         };
 
@@ -317,9 +827,49 @@ fn get_hosted_git_url(url: &Url) -> anyhow::Result<Option<Url>> {
     }
 }
 
+/// Lowercases `host` and strips any trailing dot (as in DNS-absolute names like `github.com.`), so that it can be matched
+/// against known hosts.
+fn normalize_host(host: &str) -> String {
+    host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+}
+
+/// Returns the archive URL for a git `url` on the self-hosted GitLab instance at `host`.
+fn gitlab_archive_url(url: &Url, host: &str) -> Option<Url> {
+    // Projects may be nested in any number of (sub)groups.
+    let path = url.path().trim_start_matches('/');
+
+    if path.ends_with("/archive.tar.gz") {
+        return None;
+    }
+
+    // Everything after a `/-/` separator is a page of the project, e.g. `/{group}/{project}/-/tree/{ref}`, where the ref
+    // may itself contain slashes.
+    let (path, tree_ref) = match path.split_once("/-/") {
+        Some((path, page)) => (path, Some(page.strip_prefix("tree/")?)),
+        None => (path, None),
+    };
+
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let commit = url
+        .fragment()
+        .or(tree_ref)
+        .filter(|commit| !commit.is_empty())?;
+
+    let mut archive =
+        Url::parse(&format!("https://{host}/{path}/repository/archive.tar.gz")).ok()?;
+
+    archive.query_pairs_mut().append_pair("ref", commit);
+
+    Some(archive)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::get_hosted_git_url;
+    use super::{
+        duplicates, get_hosted_git_url, get_hosted_git_url_with, graph, lockfile,
+        missing_overrides, package_manager, registry_lockfile, sources, GraphFormat, Options,
+        Source,
+    };
     use url::Url;
 
     #[test]
@@ -333,10 +883,27 @@ mod tests {
                 "git+ssh://bitbucket.org/foo/bar#branch",
                 Some("https://bitbucket.org/foo/bar/get/branch.tar.gz")
             ),
+            ("https://bitbucket.org/foo/bar/get/0a1b2c3d.tar.gz", None),
+            (
+                "git+ssh://git@bitbucket.org/get/get.git#branch",
+                Some("https://bitbucket.org/get/get/get/branch.tar.gz")
+            ),
+            (
+                "git+ssh://git@bitbucket.org/foo/bar/get#branch",
+                Some("https://bitbucket.org/foo/bar/get/branch.tar.gz")
+            ),
             (
                 "git+ssh://git.sr.ht/~foo/bar#branch",
                 Some("https://git.sr.ht/~foo/bar/archive/branch.tar.gz")
             ),
+            (
+                "hg+https://hg.sr.ht/~foo/bar#0a1b2c3d",
+                Some("https://hg.sr.ht/~foo/bar/archive/0a1b2c3d.tar.gz")
+            ),
+            (
+                "hg+ssh://hg@hg.sr.ht/~foo/bar#default",
+                Some("https://hg.sr.ht/~foo/bar/archive/default.tar.gz")
+            ),
         ] {
             assert_eq!(
                 get_hosted_git_url(&Url::parse(input).unwrap()).unwrap(),
@@ -349,5 +916,431 @@ mod tests {
                 .is_err(),
             "GitLab URLs should be marked as invalid (lol)"
         );
+
+        assert!(
+            get_hosted_git_url(
+                &Url::parse("git+https://bitbucket.org/foo/bar/get/0a1b2c3d.tar.gz").unwrap()
+            )
+            .is_err(),
+            "Bitbucket download URLs aren't repositories"
+        );
+    }
+
+    #[test]
+    fn github_tree_refs() {
+        for (input, expected) in [
+            (
+                "git+https://github.com/foo/bar/tree/release/1.0",
+                "https://codeload.github.com/foo/bar/tar.gz/release/1.0",
+            ),
+            (
+                "git+https://github.com/foo/bar.git/tree/main",
+                "https://codeload.github.com/foo/bar/tar.gz/main",
+            ),
+        ] {
+            assert_eq!(
+                get_hosted_git_url(&Url::parse(input).unwrap()).unwrap(),
+                Some(Url::parse(expected).unwrap())
+            );
+        }
+
+        for input in [
+            "git+https://github.com/foo/bar/tree/",
+            "git+ssh://git@github.com/foo/bar.git",
+        ] {
+            let err = get_hosted_git_url(&Url::parse(input).unwrap())
+                .unwrap_err()
+                .to_string();
+
+            assert!(err.contains("a malformed URL"), "{input}: {err}");
+        }
+    }
+
+    #[test]
+    fn require_https_git() {
+        let content = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/internal": {
+                    "resolved": "git+ssh://git@git.example.com/corp/internal.git#0a1b2c3d"
+                },
+                "node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                }
+            }
+        }"#;
+        let options = Options {
+            require_https_git: true,
+            ..Options::default()
+        };
+
+        let err = lockfile(content, &options).unwrap_err().to_string();
+
+        assert!(err.contains("resolved over SSH"), "{err}");
+        assert!(
+            err.contains(
+                "node_modules/internal (git+ssh://git@git.example.com/corp/internal.git#0a1b2c3d)"
+            ),
+            "{err}"
+        );
+        assert!(!err.contains("node_modules/foo"), "{err}");
+    }
+
+    #[test]
+    fn overrides_missing_from_lockfile() -> anyhow::Result<()> {
+        let content = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/foo": {
+                    "version": "1.2.3",
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.2.3.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/bar/node_modules/@scope/baz": {
+                    "version": "2.0.0",
+                    "resolved": "https://registry.npmjs.org/@scope/baz/-/baz-2.0.0.tgz",
+                    "integrity": "sha512-baz"
+                }
+            }
+        }"#;
+        let package_json = r#"{
+            "overrides": {
+                "foo": "1.2.3",
+                "bar": { ".": "3.0.0", "@scope/baz@^1": "2.0.0" },
+                "qux": "^4.0.0",
+                "stale": "1.0.0",
+                "foo@1": "1.2.4"
+            }
+        }"#;
+
+        assert_eq!(
+            missing_overrides(content, package_json, &Options::default())?,
+            [
+                "the override of bar to 3.0.0 in package.json has no matching entry in the lockfile",
+                "the override of foo to 1.2.4 in package.json has no matching entry in the lockfile",
+                "the override of qux to ^4.0.0 in package.json has no matching entry in the lockfile",
+                "the override of stale to 1.0.0 in package.json has no matching entry in the lockfile",
+            ]
+        );
+        assert!(missing_overrides(content, "{}", &Options::default())?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn jsr_packages() -> anyhow::Result<()> {
+        // JSR packages are installed through its npm compatibility registry, under the `@jsr` scope.
+        let url = "https://npm.jsr.io/~/11/@jsr/std__path/1.0.8.tgz";
+        let packages = registry_lockfile(
+            &format!(
+                r#"{{
+                    "lockfileVersion": 3,
+                    "packages": {{
+                        "node_modules/@std/path": {{
+                            "name": "@jsr/std__path",
+                            "version": "1.0.8",
+                            "resolved": "{url}",
+                            "integrity": "sha512-jsr"
+                        }}
+                    }}
+                }}"#
+            ),
+            &Options::default(),
+        )?;
+
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "node_modules/@std/path");
+        assert_eq!(packages[0].url.as_str(), url);
+        assert_eq!(
+            packages[0].integrity().map(ToString::to_string).as_deref(),
+            Some("sha512-jsr")
+        );
+        assert!(get_hosted_git_url(&packages[0].url)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_report() -> anyhow::Result<()> {
+        let report = sources(
+            r#"{
+                "lockfileVersion": 3,
+                "packages": {
+                    "node_modules/a": {
+                        "resolved": "https://registry.npmjs.org/a/-/a-1.0.0.tgz",
+                        "integrity": "sha512-a"
+                    },
+                    "node_modules/b": {
+                        "resolved": "https://registry.npmjs.org/b/-/b-1.0.0.tgz",
+                        "integrity": "sha512-b"
+                    },
+                    "node_modules/c": {
+                        "resolved": "git+ssh://git@github.com/foo/c.git#0a1b2c3d"
+                    },
+                    "node_modules/d": {
+                        "resolved": "git+https://git.example.com/foo/d.git#0a1b2c3d"
+                    },
+                    "node_modules/e": {
+                        "resolved": "ftp://ftp.example.com/e.tgz"
+                    }
+                }
+            }"#,
+            &Options::default(),
+        )?;
+
+        let source = |scheme: &str, host: &str, supported| Source {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            supported,
+        };
+
+        assert_eq!(
+            report.into_iter().collect::<Vec<_>>(),
+            vec![
+                (source("ftp", "ftp.example.com", false), 1),
+                (source("git+https", "git.example.com", false), 1),
+                (source("git+ssh", "github.com", true), 1),
+                (source("https", "registry.npmjs.org", true), 2),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn self_hosted_gitlab() {
+        let hosts = ["gitlab.example.com"];
+
+        for (input, expected) in [
+            (
+                "git+ssh://git@gitlab.example.com/foo/bar.git#0a1b2c3d",
+                "https://gitlab.example.com/foo/bar/repository/archive.tar.gz?ref=0a1b2c3d",
+            ),
+            (
+                "git+https://gitlab.example.com/group/subgroup/project.git#fix/bug",
+                "https://gitlab.example.com/group/subgroup/project/repository/archive.tar.gz?ref=fix%2Fbug",
+            ),
+            (
+                "git+https://gitlab.example.com/group/subgroup/project/-/tree/release/1.x",
+                "https://gitlab.example.com/group/subgroup/project/repository/archive.tar.gz?ref=release%2F1.x",
+            ),
+            (
+                "git+https://gitlab.example.com/group/project/-/tree/main#0a1b2c3d",
+                "https://gitlab.example.com/group/project/repository/archive.tar.gz?ref=0a1b2c3d",
+            ),
+        ] {
+            assert_eq!(
+                get_hosted_git_url_with(&Url::parse(input).unwrap(), &hosts)
+                    .unwrap()
+                    .map(String::from)
+                    .as_deref(),
+                Some(expected)
+            );
+        }
+
+        assert!(
+            get_hosted_git_url_with(
+                &Url::parse("git+ssh://git@gitlab.other.com/foo/bar.git#0a1b2c3d").unwrap(),
+                &hosts
+            )
+            .is_err(),
+            "only listed hosts should be treated as GitLab"
+        );
+        assert!(get_hosted_git_url_with(
+            &Url::parse("ssh://git@gitlab.com/foo/bar.git#0a1b2c3d").unwrap(),
+            &hosts
+        )
+        .is_err());
+        assert!(
+            get_hosted_git_url_with(
+                &Url::parse("git+https://gitlab.example.com/group/project/-/blob/main/README.md")
+                    .unwrap(),
+                &hosts
+            )
+            .is_err(),
+            "only tree pages name a ref"
+        );
+        assert_eq!(
+            get_hosted_git_url_with(
+                &Url::parse("git+https://gitlab.com/group/project/-/tree/v1.0.0").unwrap(),
+                &["gitlab.com"]
+            )
+            .unwrap()
+            .map(String::from)
+            .as_deref(),
+            Some("https://gitlab.com/group/project/repository/archive.tar.gz?ref=v1.0.0")
+        );
+    }
+
+    #[test]
+    fn unnormalized_hosts() {
+        for input in [
+            "git+ssh://git@github.com./foo/bar.git#abc123",
+            "git+ssh://git@GitHub.com/foo/bar.git#abc123",
+            "git+https://GITHUB.COM./foo/bar.git#abc123",
+        ] {
+            assert_eq!(
+                get_hosted_git_url(&Url::parse(input).unwrap())
+                    .unwrap()
+                    .map(String::from)
+                    .as_deref(),
+                Some("https://codeload.github.com/foo/bar/tar.gz/abc123"),
+                "{input}"
+            );
+        }
+
+        assert_eq!(
+            get_hosted_git_url_with(
+                &Url::parse("git+ssh://git@Git.Example.com./group/project.git#abc123").unwrap(),
+                &["git.example.com"]
+            )
+            .unwrap()
+            .map(String::from)
+            .as_deref(),
+            Some("https://git.example.com/group/project/repository/archive.tar.gz?ref=abc123")
+        );
+    }
+
+    #[test]
+    fn dependency_graph() -> anyhow::Result<()> {
+        let v1 = r#"{
+            "lockfileVersion": 1,
+            "dependencies": {
+                "a": {
+                    "version": "1.0.0",
+                    "dependencies": {
+                        "b": { "version": "1.0.0" }
+                    }
+                },
+                "c": { "version": "1.0.0" }
+            }
+        }"#;
+        let v3 = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/a": {},
+                "node_modules/a/node_modules/b": {},
+                "node_modules/c": {}
+            }
+        }"#;
+
+        for lockfile in [v1, v3] {
+            assert_eq!(
+                graph(lockfile, GraphFormat::Dot)?,
+                r#"digraph dependencies {
+  "" -> "node_modules/a";
+  "" -> "node_modules/c";
+  "node_modules/a" -> "node_modules/a/node_modules/b";
+}
+"#
+            );
+        }
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&graph(v3, GraphFormat::Json)?)?,
+            serde_json::json!([
+                { "from": "", "to": "node_modules/a" },
+                { "from": "", "to": "node_modules/c" },
+                { "from": "node_modules/a", "to": "node_modules/a/node_modules/b" },
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_urls() -> anyhow::Result<()> {
+        let lockfile = r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": {},
+                "node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/a/node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/b/node_modules/foo": {
+                    "resolved": "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                    "integrity": "sha512-foo"
+                },
+                "node_modules/bar": {
+                    "resolved": "https://registry.npmjs.org/bar/-/bar-1.0.0.tgz",
+                    "integrity": "sha512-bar"
+                },
+                "node_modules/c/node_modules/bar": {
+                    "resolved": "https://registry.npmjs.org/bar/-/bar-2.0.0.tgz",
+                    "integrity": "sha512-bar2"
+                }
+            }
+        }"#;
+
+        let duplicates = duplicates(lockfile, &Options::default())?;
+
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates["https://registry.npmjs.org/foo/-/foo-1.0.0.tgz"],
+            [
+                "node_modules/a/node_modules/foo",
+                "node_modules/b/node_modules/foo",
+                "node_modules/foo",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn package_manager_tarball() -> anyhow::Result<()> {
+        use crate::util::test_server::{response, Server};
+        use sha2::{Digest, Sha512};
+
+        let tarball = b"pnpm tarball";
+        let server = Server::new(vec![response(200, &[], tarball)]);
+        let options = Options {
+            registry: Some(server.url.clone()),
+            ..Options::default()
+        };
+        let digest = crate::cacache::hex(&Sha512::digest(tarball));
+
+        let package = package_manager(
+            &format!(r#"{{ "packageManager": "pnpm@8.15.4+sha512.{digest}" }}"#),
+            &options,
+        )?
+        .unwrap();
+        let body = package.tarball()?;
+
+        assert_eq!(package.url, server.url.join("pnpm/-/pnpm-8.15.4.tgz")?);
+        assert_eq!(body, tarball);
+        assert!(server.requests()[0].starts_with("GET /pnpm/-/pnpm-8.15.4.tgz "));
+        assert!(package.integrity().unwrap().as_str().starts_with("sha512-"));
+
+        assert_eq!(
+            package_manager(r#"{ "packageManager": "yarn@4.1.0+sha1.f1d2d2f924e986ac86fdf7b36c94bcdf32beec15" }"#, &Options::default())?
+                .unwrap()
+                .url
+                .as_str(),
+            "https://registry.npmjs.org/@yarnpkg/cli-dist/-/cli-dist-4.1.0.tgz"
+        );
+        assert!(package_manager(
+            r#"{ "packageManager": "pnpm@8.15.4" }"#,
+            &Options::default()
+        )
+        .is_err());
+        assert!(
+            package_manager(
+                r#"{ "packageManager": "yarn@3.2.3+sha224.953c8233f7a92884eee2de69a1b92d1f2ec1655e66d08071ba9a02fa" }"#,
+                &Options::default()
+            )?
+            .is_none(),
+            "unsupported hashes should be skipped"
+        );
+        assert!(package_manager("{}", &Options::default())?.is_none());
+
+        Ok(())
     }
 }
@@ -0,0 +1,170 @@
+use anyhow::anyhow;
+use std::{env, str::FromStr};
+
+use super::lock;
+
+// Node's names for operating systems and CPU architectures, as used in `os`/`cpu` fields and platform-specific package names.
+const OSES: &[&str] = &[
+    "aix", "android", "darwin", "freebsd", "linux", "netbsd", "openbsd", "sunos", "win32",
+];
+const CPUS: &[&str] = &[
+    "arm", "arm64", "ia32", "loong64", "mips64el", "ppc64", "riscv64", "s390x", "x64",
+];
+
+/// A target platform, used to prune the per-platform prebuilt binary packages (such as `@esbuild/linux-x64` or
+/// `@swc/core-darwin-arm64`) that don't apply to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    os: String,
+    cpu: String,
+}
+
+impl Platform {
+    /// Returns the platform we're running on.
+    pub fn host() -> Platform {
+        let os = match env::consts::OS {
+            "macos" => "darwin",
+            "windows" => "win32",
+            os => os,
+        };
+
+        let cpu = match env::consts::ARCH {
+            "x86_64" => "x64",
+            "aarch64" => "arm64",
+            "x86" => "ia32",
+            "powerpc64" => "ppc64",
+            "loongarch64" => "loong64",
+            cpu => cpu,
+        };
+
+        Platform {
+            os: os.to_string(),
+            cpu: cpu.to_string(),
+        }
+    }
+
+    /// Returns whether `package` should be fetched for this platform.
+    ///
+    /// The package's `os` and `cpu` fields are respected if present. Otherwise, packages whose name contains both an OS and
+    /// a CPU (e.g. `@esbuild/linux-x64`, `esbuild-darwin-64`, `@img/sharp-win32-ia32`) are assumed to be prebuilt binaries
+    /// for that platform.
+    pub(super) fn matches(&self, package: &lock::Package) -> bool {
+        if !package.os.is_empty() || !package.cpu.is_empty() {
+            return matches_field(&package.os, &self.os) && matches_field(&package.cpu, &self.cpu);
+        }
+
+        let name = package.name.as_deref().unwrap_or_default();
+        let name = name.rsplit("node_modules/").next().unwrap_or(name);
+
+        let tokens = name
+            .split(['/', '-', '_', '.'])
+            .map(|t| match t {
+                "64" => "x64",
+                "32" => "ia32",
+                "windows" => "win32",
+                t => t,
+            })
+            .collect::<Vec<_>>();
+
+        let os = tokens.iter().find(|t| OSES.contains(t));
+        let cpu = tokens.iter().find(|t| CPUS.contains(t));
+
+        match (os, cpu) {
+            (Some(os), Some(_)) => *os == self.os && tokens.iter().any(|t| *t == self.cpu),
+            _ => true,
+        }
+    }
+}
+
+/// Checks an npm `os`/`cpu` field, where entries may be negated with a leading `!`.
+fn matches_field(field: &[String], value: &str) -> bool {
+    if field.is_empty() {
+        return true;
+    }
+
+    if field.iter().any(|f| f.strip_prefix('!') == Some(value)) {
+        return false;
+    }
+
+    let allowed = field
+        .iter()
+        .filter(|f| !f.starts_with('!'))
+        .collect::<Vec<_>>();
+
+    allowed.is_empty() || allowed.iter().any(|f| *f == value)
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// Parses a platform in the form of `<os>-<cpu>`, e.g. `linux-x64`.
+    fn from_str(s: &str) -> anyhow::Result<Platform> {
+        let (os, cpu) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("expected a platform like `linux-x64`, got {s:?}"))?;
+
+        Ok(Platform {
+            os: os.to_string(),
+            cpu: cpu.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Platform;
+    use crate::parse::lock::Package;
+
+    fn package(name: &str, os: &[&str], cpu: &[&str]) -> Package {
+        Package {
+            name: Some(name.to_string()),
+            os: os.iter().map(ToString::to_string).collect(),
+            cpu: cpu.iter().map(ToString::to_string).collect(),
+            ..Package::default()
+        }
+    }
+
+    #[test]
+    fn prune_by_name() -> anyhow::Result<()> {
+        let platform = "linux-x64".parse::<Platform>()?;
+
+        for (name, keep) in [
+            ("node_modules/@esbuild/linux-x64", true),
+            ("node_modules/@esbuild/linux-arm64", false),
+            ("node_modules/@esbuild/darwin-x64", false),
+            ("node_modules/@esbuild/win32-ia32", false),
+            ("node_modules/esbuild-linux-64", true),
+            ("node_modules/esbuild-windows-64", false),
+            ("node_modules/@swc/core-linux-x64-gnu", true),
+            ("node_modules/@swc/core-darwin-arm64", false),
+            ("node_modules/@img/sharp-libvips-linux-x64", true),
+            ("node_modules/@img/sharp-win32-x64", false),
+            ("node_modules/esbuild", true),
+            ("node_modules/is-linux", true),
+            ("node_modules/foo/node_modules/@esbuild/linux-x64", true),
+        ] {
+            assert_eq!(
+                platform.matches(&package(name, &[], &[])),
+                keep,
+                "{name} should be {}",
+                if keep { "kept" } else { "pruned" }
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune_by_fields() -> anyhow::Result<()> {
+        let platform = "darwin-arm64".parse::<Platform>()?;
+
+        assert!(platform.matches(&package("fsevents", &["darwin"], &[])));
+        assert!(!platform.matches(&package("fsevents", &["linux"], &[])));
+        assert!(!platform.matches(&package("foo", &["!darwin"], &[])));
+        assert!(!platform.matches(&package("foo", &["darwin"], &["x64"])));
+        // Explicit fields take precedence over the name.
+        assert!(platform.matches(&package("@esbuild/linux-x64", &["darwin"], &["arm64"])));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, bail, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::{Command, Stdio},
+};
+use tempfile::NamedTempFile;
+use url::Url;
+
+use super::lock::{self, package_name};
+use crate::util;
+
+/// One of the public keys (from its `-/npm/v1/keys` endpoint) that a registry signs packages' integrity with.
+#[derive(Debug, Deserialize)]
+pub(super) struct Key {
+    keyid: String,
+    /// The base64 of the key's DER encoding.
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct Keys {
+    keys: Vec<Key>,
+}
+
+fn registry_keys(registry: &Url) -> anyhow::Result<Vec<Key>> {
+    let url = lock::registry_base(registry).join("-/npm/v1/keys")?;
+    let body = util::get_url_body_with_retry(&url)?;
+
+    Ok(serde_json::from_slice::<Keys>(&body)
+        .with_context(|| format!("{url} didn't return the registry's keys"))?
+        .keys)
+}
+
+/// Checks the registry signatures of each of `packages` that has any, against the keys of its registry: the one for its
+/// scope in `options.scope_registries`, or otherwise `registry`.
+pub(super) fn verify(
+    packages: &[lock::Package],
+    options: &super::Options,
+    registry: &Url,
+) -> anyhow::Result<()> {
+    let mut keys = HashMap::new();
+    let mut failures = Vec::new();
+
+    for package in packages.iter().filter(|p| !p.signatures.is_empty()) {
+        let key = package.name.as_deref().unwrap_or_default();
+        let name = package_name(key);
+        let registry = name
+            .split_once('/')
+            .and_then(|(scope, _)| options.scope_registries.get(scope))
+            .unwrap_or(registry);
+
+        if !keys.contains_key(registry) {
+            keys.insert(registry.clone(), registry_keys(registry)?);
+        }
+
+        if let Err(e) = verify_package(name, package, &keys[registry]) {
+            failures.push(format!("{key} {e}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        failures.sort();
+
+        bail!(
+            "Some dependencies' registry signatures couldn't be verified:\n{}",
+            failures.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks each of `package`'s signatures, which registries make over `<name>@<version>:<integrity>`.
+fn verify_package(name: &str, package: &lock::Package, keys: &[Key]) -> anyhow::Result<()> {
+    let version = package
+        .version
+        .as_deref()
+        .ok_or_else(|| anyhow!("has no version"))?;
+    let integrity = package
+        .integrity
+        .as_ref()
+        .and_then(|integrity| integrity.preferred(false))
+        .ok_or_else(|| anyhow!("has no integrity"))?;
+    let message = format!("{name}@{version}:{integrity}");
+
+    for signature in &package.signatures {
+        let key = keys
+            .iter()
+            .find(|key| key.keyid == signature.keyid)
+            .ok_or_else(|| anyhow!("is signed with unknown key {}", signature.keyid))?;
+
+        if !verify_signature(&message, &signature.sig, &key.key)? {
+            bail!("has an invalid signature from key {}", key.keyid);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the base64 ECDSA `signature` of `message` with the base64 DER public `key`, using `openssl`.
+fn verify_signature(message: &str, signature: &str, key: &str) -> anyhow::Result<bool> {
+    let mut key_file = NamedTempFile::new()?;
+    key_file.write_all(&BASE64_STANDARD.decode(key)?)?;
+
+    let mut signature_file = NamedTempFile::new()?;
+    signature_file.write_all(&BASE64_STANDARD.decode(signature)?)?;
+
+    let mut cmd = Command::new("openssl")
+        .args(["dgst", "-sha256", "-keyform", "DER", "-verify"])
+        .arg(key_file.path())
+        .arg("-signature")
+        .arg(signature_file.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("failed to run openssl")?;
+
+    cmd.stdin.take().unwrap().write_all(message.as_bytes())?;
+
+    Ok(cmd.wait()?.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::Options, verify, verify_package, Key};
+    use crate::{
+        parse::lock::packages,
+        util::test_server::{response, Server},
+    };
+    use serde_json::json;
+    use url::Url;
+
+    const KEY: &str = "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEhF8PzLsPJ3XEio0VQxH3LRVnYdfWt2sHQJD2HcanYouiPpCdHSJX1aX4U9nQVrgCJym+u/wIK0GlZzdu82kEBg==";
+    // The signature of `foo@1.0.0:sha512-Zm9v` with `KEY`.
+    const SIGNATURE: &str = "MEUCIA708Qh28rQEToSjyYYzczi+HRdqbG8E48vQCJ/RCp1IAiEA9HSXDGWyepiRXpdQAnC9iyP92p+JcEOqMiwbk8195FE=";
+
+    fn lockfile(version: &str, keyid: &str) -> String {
+        format!(
+            r#"{{
+                "lockfileVersion": 3,
+                "packages": {{
+                    "node_modules/foo": {{
+                        "version": "{version}",
+                        "dist": {{
+                            "tarball": "https://registry.npmjs.org/foo/-/foo-{version}.tgz",
+                            "integrity": "sha512-Zm9v",
+                            "signatures": [{{ "keyid": "{keyid}", "sig": "{SIGNATURE}" }}]
+                        }}
+                    }},
+                    "node_modules/unsigned": {{
+                        "resolved": "https://registry.npmjs.org/unsigned/-/unsigned-1.0.0.tgz",
+                        "integrity": "sha512-dW5zaWduZWQ="
+                    }}
+                }}
+            }}"#
+        )
+    }
+
+    #[test]
+    fn signatures() -> anyhow::Result<()> {
+        let options = Options::default();
+        let registry = Url::parse("https://registry.npmjs.org/")?;
+        let keys = [Key {
+            keyid: String::from("SHA256:test"),
+            key: String::from(KEY),
+        }];
+        let check = |lockfile: &str| -> anyhow::Result<()> {
+            let packages = packages(lockfile, &options)?;
+
+            assert_eq!(packages[0].signatures.len(), 1);
+
+            for package in &packages {
+                if !package.signatures.is_empty() {
+                    verify_package("foo", package, &keys)?;
+                }
+            }
+
+            Ok(())
+        };
+
+        check(&lockfile("1.0.0", "SHA256:test"))?;
+
+        let tampered = check(&lockfile("1.0.1", "SHA256:test")).unwrap_err();
+        assert_eq!(
+            tampered.to_string(),
+            "has an invalid signature from key SHA256:test"
+        );
+
+        let unknown = check(&lockfile("1.0.0", "SHA256:other")).unwrap_err();
+        assert_eq!(
+            unknown.to_string(),
+            "is signed with unknown key SHA256:other"
+        );
+
+        // Unsigned packages are skipped, so the registry's keys aren't even fetched.
+        verify(
+            &packages(&lockfile("1.0.0", "SHA256:test"), &options)?[1..],
+            &options,
+            &registry,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn registry_keys() -> anyhow::Result<()> {
+        let keys = json!({ "keys": [{ "keyid": "SHA256:test", "key": KEY }] });
+        let server = Server::new(vec![response(200, &[], keys.to_string().as_bytes())]);
+        let options = Options::default();
+
+        // Served under a path, which the keys' endpoint must stay under.
+        verify(
+            &packages(&lockfile("1.0.0", "SHA256:test"), &options)?,
+            &options,
+            &server.url.join("api/npm")?,
+        )?;
+
+        assert!(server.requests()[0].starts_with("GET /api/npm/-/npm/v1/keys "));
+
+        Ok(())
+    }
+}
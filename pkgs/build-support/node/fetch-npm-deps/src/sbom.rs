@@ -0,0 +1,131 @@
+use anyhow::anyhow;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::cacache::hex;
+
+/// A fetched dependency, as listed in the SBOM.
+#[derive(Debug)]
+pub struct Component {
+    /// The package's key in the lockfile, e.g. `node_modules/foo/node_modules/@bar/baz`.
+    pub key: String,
+    pub version: Option<String>,
+    pub url: Url,
+    /// The SRI hash the tarball was stored in the cache with.
+    pub integrity: String,
+}
+
+impl Component {
+    /// Returns the package's name, from its key in the lockfile.
+    fn name(&self) -> &str {
+        self.key
+            .rsplit_once("node_modules/")
+            .map_or(self.key.as_str(), |(_, name)| name)
+    }
+
+    fn to_json(&self) -> anyhow::Result<Value> {
+        let name = self.name();
+        let (algo, hash) = self
+            .integrity
+            .split_once('-')
+            .ok_or_else(|| anyhow!("malformed integrity {:?}", self.integrity))?;
+        let alg = match algo {
+            "sha1" => "SHA-1",
+            "sha256" => "SHA-256",
+            "sha512" => "SHA-512",
+            _ => return Err(anyhow!("unknown hash algorithm {algo:?}")),
+        };
+
+        let mut component = json!({
+            "type": "library",
+            "name": name,
+            "hashes": [{ "alg": alg, "content": hex(&BASE64_STANDARD.decode(hash)?) }],
+            "externalReferences": [{ "type": "distribution", "url": self.url }],
+        });
+
+        if let Some(version) = &self.version {
+            component["version"] = json!(version);
+            component["purl"] = json!(format!(
+                "pkg:npm/{}@{version}",
+                name.replacen('@', "%40", 1)
+            ));
+        }
+
+        Ok(component)
+    }
+}
+
+/// Returns a minimal `CycloneDX` SBOM listing `components`.
+pub fn cyclonedx(components: &[Component]) -> anyhow::Result<Value> {
+    let mut components = components
+        .iter()
+        .map(Component::to_json)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    components.sort_by(|a, b| {
+        (a["name"].as_str(), a["version"].as_str())
+            .cmp(&(b["name"].as_str(), b["version"].as_str()))
+    });
+
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "components": components,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cyclonedx, Component};
+    use serde_json::json;
+    use url::Url;
+
+    #[test]
+    fn components() -> anyhow::Result<()> {
+        let sbom = cyclonedx(&[
+            Component {
+                key: String::from("node_modules/foo/node_modules/@scope/bar"),
+                version: Some(String::from("2.0.0")),
+                url: Url::parse("https://registry.npmjs.org/@scope/bar/-/bar-2.0.0.tgz")?,
+                integrity: String::from("sha1-8dLS+STphqyG/fezbJS83zK+7BU="),
+            },
+            Component {
+                key: String::from("node_modules/foo"),
+                version: None,
+                url: Url::parse("https://codeload.github.com/foo/foo/tar.gz/main")?,
+                integrity: String::from("sha1-8dLS+STphqyG/fezbJS83zK+7BU="),
+            },
+        ])?;
+
+        assert_eq!(sbom["bomFormat"], "CycloneDX");
+        assert_eq!(
+            sbom["components"],
+            json!([
+                {
+                    "type": "library",
+                    "name": "@scope/bar",
+                    "version": "2.0.0",
+                    "purl": "pkg:npm/%40scope/bar@2.0.0",
+                    "hashes": [{ "alg": "SHA-1", "content": "f1d2d2f924e986ac86fdf7b36c94bcdf32beec15" }],
+                    "externalReferences": [{
+                        "type": "distribution",
+                        "url": "https://registry.npmjs.org/@scope/bar/-/bar-2.0.0.tgz",
+                    }],
+                },
+                {
+                    "type": "library",
+                    "name": "foo",
+                    "hashes": [{ "alg": "SHA-1", "content": "f1d2d2f924e986ac86fdf7b36c94bcdf32beec15" }],
+                    "externalReferences": [{
+                        "type": "distribution",
+                        "url": "https://codeload.github.com/foo/foo/tar.gz/main",
+                    }],
+                },
+            ])
+        );
+
+        Ok(())
+    }
+}
@@ -1,14 +1,311 @@
+use anyhow::{anyhow, bail};
 use backoff::{retry, ExponentialBackoff};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use digest::{Digest, Update};
 use isahc::{
-    config::{CaCertificate, Configurable, RedirectPolicy, SslOption},
-    Body, Request, RequestExt,
+    config::{
+        CaCertificate, Configurable, RedirectPolicy, ResolveMap, SslOption, VersionNegotiation,
+    },
+    error::ErrorKind,
+    http::{request::Builder, HeaderMap, Method, StatusCode},
+    Body, HttpClient, Request, RequestExt, Response, ResponseExt,
 };
+use log::warn;
+use regex::Regex;
 use serde_json::{Map, Value};
-use std::{env, io::Read, path::Path};
-use url::Url;
+use sha1::Sha1;
+use sha2::Sha512;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::{self, Read, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::{NamedTempFile, TempDir, TempPath};
+use url::{Host, Url};
+
+use crate::{config, netrc::Netrc};
+
+static MINIMAL_HEADERS: AtomicBool = AtomicBool::new(false);
+
+static TRACE_HTTP: AtomicBool = AtomicBool::new(false);
+
+static HOST_REQUESTS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Returns how many requests have been sent to each host (with its port, if it isn't the scheme's default) so far.
+pub fn host_requests() -> BTreeMap<String, usize> {
+    HOST_REQUESTS.lock().unwrap().clone()
+}
+
+fn record_request(url: &Url) {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return,
+    };
+
+    *HOST_REQUESTS.lock().unwrap().entry(host).or_default() += 1;
+}
+
+/// Stops sending headers that aren't strictly needed, for old registries that reject requests with unexpected ones.
+pub fn use_minimal_headers() {
+    MINIMAL_HEADERS.store(true, Ordering::Relaxed);
+}
+
+/// Logs the headers of every request and response to stderr, with credentials redacted.
+pub fn trace_http() {
+    TRACE_HTTP.store(true, Ordering::Relaxed);
+}
+
+fn get_url_with(url: &Url, config: &config::Config) -> Result<Response<Body>, isahc::Error> {
+    send_with(url, config, |request| request)
+}
+
+/// Sends a HEAD request for `url`, for its headers alone.
+fn head_url_with(url: &Url, config: &config::Config) -> Result<Response<Body>, isahc::Error> {
+    send_with(url, config, |request| request.method(Method::HEAD))
+}
+
+static EXTRA_CA_BUNDLE: OnceLock<Option<TempPath>> = OnceLock::new();
+
+/// Where CA bundles are commonly found, if neither `SSL_CERT_FILE` nor `NIX_SSL_CERT_FILE` point to one.
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// Returns a CA bundle with the certificates from Node's `NODE_EXTRA_CA_CERTS` added to the default ones, if it's set.
+fn extra_ca_bundle() -> Option<&'static Path> {
+    EXTRA_CA_BUNDLE
+        .get_or_init(|| {
+            let extra = PathBuf::from(env::var_os("NODE_EXTRA_CA_CERTS")?);
+            let base = ["SSL_CERT_FILE", "NIX_SSL_CERT_FILE"]
+                .into_iter()
+                .filter_map(env::var_os)
+                .map(PathBuf::from)
+                .chain(SYSTEM_CA_BUNDLES.iter().map(PathBuf::from))
+                .find(|path| path.exists());
+
+            combine_ca_bundles(base.as_deref(), &extra)
+                .and_then(|bundle| {
+                    let mut file = NamedTempFile::new()?;
+                    file.write_all(&bundle)?;
+
+                    Ok(file.into_temp_path())
+                })
+                .map_err(|e| warn!("ignoring NODE_EXTRA_CA_CERTS: {e}"))
+                .ok()
+        })
+        .as_deref()
+}
+
+/// Appends the PEM certificates in `extra` to the CA bundle at `base`.
+fn combine_ca_bundles(base: Option<&Path>, extra: &Path) -> anyhow::Result<Vec<u8>> {
+    let extra_certs =
+        fs::read(extra).map_err(|e| anyhow!("failed to read {}: {e}", extra.display()))?;
+
+    if !String::from_utf8_lossy(&extra_certs).contains("-----BEGIN CERTIFICATE-----") {
+        bail!("{} doesn't contain any PEM certificates", extra.display());
+    }
+
+    let mut bundle = if let Some(base) = base {
+        fs::read(base)?
+    } else {
+        warn!("no default CA bundle was found, so only the certificates in NODE_EXTRA_CA_CERTS are trusted");
+
+        Vec::new()
+    };
+
+    if !bundle.is_empty() && !bundle.ends_with(b"\n") {
+        bundle.push(b'\n');
+    }
+
+    bundle.extend(extra_certs);
+
+    Ok(bundle)
+}
+
+/// Sends a GET request for `url` (unless `build` changes its method), built by `request_with` and then `build`.
+///
+/// If `url` is addressed by an IP endpoint with a hostname configured for it, the request is sent for that name instead
+/// (so that it's both the `Host` header and the TLS SNI name), with the name resolved back to the endpoint.
+fn send_with(
+    url: &Url,
+    config: &config::Config,
+    build: impl FnOnce(Builder) -> Builder,
+) -> Result<Response<Body>, isahc::Error> {
+    let mut stderr = io::stderr();
+
+    send_traced(
+        url,
+        config,
+        build,
+        TRACE_HTTP
+            .load(Ordering::Relaxed)
+            .then_some(&mut stderr as &mut dyn Write),
+    )
+}
+
+/// Like [`send_with`], but writes the request and response headers to `trace`, if given.
+fn send_traced(
+    url: &Url,
+    config: &config::Config,
+    build: impl FnOnce(Builder) -> Builder,
+    mut trace: Option<&mut dyn Write>,
+) -> Result<Response<Body>, isahc::Error> {
+    let (client, url) = match host_override(url, &config.host_overrides) {
+        Some((url, resolve)) => (
+            Some(HttpClient::builder().dns_resolve(resolve).build()?),
+            url,
+        ),
+        None => (None, url.clone()),
+    };
+    let request = build(request_with(&url, config)?).body(())?;
+
+    if let Some(trace) = &mut trace {
+        let _ = trace.write_all(trace_request(&request).as_bytes());
+    }
+
+    let response = match client {
+        Some(client) => client.send(request)?,
+        None => request.send()?,
+    };
+
+    if let Some(trace) = &mut trace {
+        let _ = trace.write_all(trace_response(&response).as_bytes());
+    }
+
+    // Redirects are followed by curl, so the host they end up at is only known from the response.
+    if let Some(effective) = response
+        .effective_uri()
+        .and_then(|uri| Url::parse(&uri.to_string()).ok())
+        .filter(|effective| effective.authority() != url.authority())
+    {
+        record_request(&effective);
+    }
+
+    Ok(response)
+}
+
+// Headers whose values are credentials, and so are never traced.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Describes `request` for `--trace-http`, as its request line followed by its headers.
+fn trace_request(request: &Request<()>) -> String {
+    let uri = request.uri().to_string();
+    let uri = Url::parse(&uri).map_or(uri, redact_url);
+
+    format!(
+        "> {} {uri}\n{}",
+        request.method(),
+        trace_headers('>', request.headers())
+    )
+}
+
+/// Returns `url` with its userinfo and query (which may carry tokens, e.g. for signed URLs) redacted.
+fn redact_url(mut url: Url) -> String {
+    let userinfo = !url.username().is_empty() || url.password().is_some();
+    let query = url.query().is_some();
+
+    // Set back to nothing and then written out by hand, as the URL would percent-encode the placeholder.
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.set_query(None);
+
+    let mut redacted = url.to_string();
+
+    if userinfo {
+        redacted = redacted.replacen("://", "://<redacted>@", 1);
+    }
+
+    if query {
+        redacted.push_str("?<redacted>");
+    }
+
+    redacted
+}
+
+/// Describes `response` for `--trace-http`, as its status line followed by its headers.
+fn trace_response(response: &Response<Body>) -> String {
+    format!(
+        "< {:?} {}\n{}",
+        response.version(),
+        response.status(),
+        trace_headers('<', response.headers())
+    )
+}
+
+fn trace_headers(prefix: char, headers: &HeaderMap) -> String {
+    use std::fmt::Write as _;
+
+    headers
+        .iter()
+        .fold(String::new(), |mut out, (name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
 
-pub fn get_url(url: &Url) -> Result<Body, isahc::Error> {
-    let mut request = Request::get(url.as_str()).redirect_policy(RedirectPolicy::Limit(10));
+            let _ = writeln!(out, "{prefix} {name}: {value}");
+            out
+        })
+}
+
+/// Returns `url` with its IP address replaced by the hostname `overrides` has for it (keyed by the address and port, or
+/// the address alone), along with the resolution of that name back to the address.
+fn host_override(url: &Url, overrides: &Map<String, Value>) -> Option<(Url, ResolveMap)> {
+    let ip = match url.host()? {
+        Host::Ipv4(ip) => IpAddr::from(ip),
+        Host::Ipv6(ip) => IpAddr::from(ip),
+        Host::Domain(_) => return None,
+    };
+    let host = url.host_str()?;
+    let name = url
+        .port()
+        .and_then(|port| overrides.get(&format!("{host}:{port}")))
+        .or_else(|| overrides.get(host))?
+        .as_str()?;
+
+    let mut new = url.clone();
+    new.set_host(Some(name)).ok()?;
+
+    Some((
+        new,
+        ResolveMap::new().add(name, url.port_or_known_default()?, ip),
+    ))
+}
+
+/// Builds a GET request for `url`, with the headers and options every request is sent with.
+fn request_with(
+    url: &Url,
+    config: &config::Config,
+) -> Result<isahc::http::request::Builder, isahc::Error> {
+    record_request(url);
+
+    let mut request = Request::get(url.as_str())
+        .redirect_policy(RedirectPolicy::Limit(10))
+        // Content encodings are handled in `decode_body`, so that what we hash is always the actual file.
+        .automatic_decompression(false);
+
+    if !MINIMAL_HEADERS.load(Ordering::Relaxed) {
+        request = request.header("Accept-Encoding", accept_encoding(url));
+    }
 
     // Respect SSL_CERT_FILE if environment variable exists
     if let Ok(ssl_cert_file) = env::var("SSL_CERT_FILE") {
@@ -23,44 +320,2177 @@ pub fn get_url(url: &Url) -> Result<Body, isahc::Error> {
         }
     }
 
-    // Respect NIX_NPM_TOKENS environment variable, which should be a JSON mapping in the shape of:
-    // `{ "registry.example.com": "example-registry-bearer-token", ... }`
-    if let Some(host) = url.host_str() {
-        if let Ok(npm_tokens) = env::var("NIX_NPM_TOKENS") {
-            if let Ok(tokens) = serde_json::from_str::<Map<String, Value>>(&npm_tokens) {
-                if let Some(token) = tokens.get(host).and_then(serde_json::Value::as_str) {
-                    request = request.header("Authorization", format!("Bearer {token}"));
-                }
+    if let Some(bundle) = extra_ca_bundle() {
+        request = request.ssl_ca_certificate(CaCertificate::file(bundle));
+    }
+
+    if uses_http2(url, &config.http2_hosts) {
+        // Many requests to the same host are then multiplexed over a single connection. As there's nothing to negotiate
+        // HTTP/2 with over plain HTTP, the host is assumed to support it.
+        request = request.version_negotiation(VersionNegotiation::http2());
+    }
+
+    let netrc = NETRC.get_or_init(|| {
+        Netrc::load().unwrap_or_else(|e| {
+            warn!("ignoring netrc file: {e}");
+
+            None
+        })
+    });
+
+    if let Some(authorization) = authorization(url, config, netrc.as_ref()) {
+        request = request.header("Authorization", authorization);
+    }
+
+    if let Some(proxy) = &config.proxy {
+        request = request.proxy(Some(proxy.parse().map_err(isahc::http::Error::from)?));
+    }
+
+    if let Some(timeout) = config.timeout() {
+        if config.timeout_rate.is_some() {
+            // Reading the body then has a timeout scaled by its size (see `scaled_timeout`), so only connecting and
+            // stalling are held to the flat one.
+            request = request
+                .connect_timeout(timeout)
+                .low_speed_timeout(1, timeout);
+        } else {
+            request = request.timeout(timeout);
+        }
+    }
+
+    Ok(request)
+}
+
+/// Returns whether requests to `url` should use HTTP/2, as its host (or host and port) is one of `hosts`, and libcurl
+/// supports it.
+fn uses_http2(url: &Url, hosts: &[String]) -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+    let listed = url.host_str().is_some_and(|host| {
+        hosts
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(host) || h.eq_ignore_ascii_case(url.authority()))
+    });
+
+    listed
+        && *SUPPORTED.get_or_init(|| {
+            let supported = isahc::is_http_version_supported(isahc::http::Version::HTTP_2);
+
+            if !supported {
+                warn!("HTTP/2 isn't supported by this build of libcurl, using HTTP/1.1 instead");
             }
+
+            supported
+        })
+}
+
+/// Returns the `Accept-Encoding` to request `url` with. Tarballs from registries are already compressed, so compressing
+/// them again in transit would only waste time, but git archive endpoints may serve uncompressed archives.
+fn accept_encoding(url: &Url) -> &'static str {
+    if Path::new(url.path())
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tgz"))
+    {
+        "identity"
+    } else {
+        "gzip"
+    }
+}
+
+/// Undoes the `Content-Encoding` of a response body.
+fn decode_body(content_encoding: Option<&str>, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match content_encoding.map(str::trim) {
+        None | Some("" | "identity") => Ok(body),
+        Some("gzip" | "x-gzip") => gunzip(&body),
+        Some(encoding) => bail!("unsupported content encoding {encoding:?}"),
+    }
+}
+
+static NETRC: OnceLock<Option<Netrc>> = OnceLock::new();
+
+/// Returns the `Authorization` header to send with requests to `url`: a bearer token if there is one, and otherwise Basic
+/// auth with credentials from `netrc`.
+fn authorization(url: &Url, config: &config::Config, netrc: Option<&Netrc>) -> Option<String> {
+    if let Some(token) = bearer_token(url, config) {
+        return Some(format!("Bearer {token}"));
+    }
+
+    let credentials = netrc?.get(url.host_str()?)?;
+
+    Some(format!(
+        "Basic {}",
+        BASE64_STANDARD.encode(format!("{}:{}", credentials.login, credentials.password))
+    ))
+}
+
+// Hosts that the GitHub token may be sent to.
+const GITHUB_HOSTS: &[&str] = &["github.com", "api.github.com", "codeload.github.com"];
+
+/// Returns the token to authenticate requests to `url` with.
+///
+/// Tokens from `NIX_NPM_TOKENS` (or the config file) should be a JSON mapping in the shape of:
+/// `{ "registry.example.com": "example-registry-bearer-token", ... }`. The GitHub token is only ever sent to GitHub.
+fn bearer_token<'a>(url: &Url, config: &'a config::Config) -> Option<&'a str> {
+    let host = url.host_str()?;
+
+    config.tokens.get(host).and_then(Value::as_str).or_else(|| {
+        config
+            .github_token
+            .as_deref()
+            .filter(|_| GITHUB_HOSTS.contains(&host))
+    })
+}
+
+/// Returns the authenticated API equivalent of a codeload.github.com tarball URL, which has a much higher rate limit.
+///
+/// The API redirects to codeload, so the tarball is the same either way.
+fn github_api_url(url: &Url) -> Option<Url> {
+    if url.host_str() != Some("codeload.github.com") {
+        return None;
+    }
+
+    let mut s = url.path_segments()?;
+    let (user, project, typ, commit) = (s.next()?, s.next()?, s.next()?, s.next()?);
+
+    if typ != "tar.gz" || s.next().is_some() {
+        return None;
+    }
+
+    Url::parse(&format!(
+        "https://api.github.com/repos/{user}/{project}/tarball/{commit}"
+    ))
+    .ok()
+}
+
+/// Returns the `Content-Length` of `url`, from a HEAD request so that its body isn't sent at all.
+pub fn content_length(url: &Url) -> anyhow::Result<Option<u64>> {
+    let config = config::get();
+    let response = head_url_with(&rewrite_url(url, &config.registry_rewrites), config)?;
+
+    Ok(header(&response, "Content-Length").and_then(|len| len.parse().ok()))
+}
+
+pub fn get_url_body_with_retry(url: &Url) -> anyhow::Result<Vec<u8>> {
+    let config = config::get();
+    let url = apply_url_rewrites(url, URL_REWRITES.get().map_or(&[], Vec::as_slice))?;
+    let mut url = rewrite_url(&url, &config.registry_rewrites);
+
+    if url.scheme() == "s3" {
+        let endpoint = config.s3_endpoint.as_deref().ok_or_else(|| {
+            anyhow!(
+                "can't fetch {url}, as no S3 endpoint is configured (FETCH_NPM_DEPS_S3_ENDPOINT)"
+            )
+        })?;
+
+        url = s3_url(&url, endpoint)?;
+    }
+
+    if config.github_token.is_some() {
+        if let Some(api) = github_api_url(&url) {
+            url = api;
         }
     }
 
-    Ok(request.body(())?.send()?.into_body())
+    get_url_body_with_mirrors(&url, &config.mirrors, ExponentialBackoff::default)
 }
 
-pub fn get_url_body_with_retry(url: &Url) -> Result<Vec<u8>, isahc::Error> {
-    retry(ExponentialBackoff::default(), || {
-        get_url(url)
-            .and_then(|mut body| {
-                let mut buf = Vec::new();
+/// Converts an `s3://<bucket>/<key>` URL to an HTTPS one, by filling in the `{bucket}` and `{key}` of `endpoint` (e.g.
+/// `https://{bucket}.s3.amazonaws.com/{key}`).
+fn s3_url(url: &Url, endpoint: &str) -> anyhow::Result<Url> {
+    let bucket = url
+        .host_str()
+        .ok_or_else(|| anyhow!("{url} has no bucket"))?;
+    let key = url.path().trim_start_matches('/');
 
-                body.read_to_end(&mut buf)?;
+    if key.is_empty() {
+        bail!("{url} has no key");
+    }
 
-                Ok(buf)
-            })
-            .map_err(|err| {
-                if err.is_network() || err.is_timeout() {
-                    backoff::Error::transient(err)
-                } else {
-                    backoff::Error::permanent(err)
-                }
-            })
+    Ok(Url::parse(
+        &endpoint.replace("{bucket}", bucket).replace("{key}", key),
+    )?)
+}
+
+static URL_REWRITES: OnceLock<Vec<UrlRewrite>> = OnceLock::new();
+
+/// Rewrites resolved URLs with `rewrites` before fetching them (cache keys still use the original URLs).
+pub fn set_url_rewrites(rewrites: Vec<UrlRewrite>) {
+    let _ = URL_REWRITES.set(rewrites);
+}
+
+/// A `<pattern>=><replacement>` rule (from `--rewrite`), where `pattern` is a regex matched against the whole URL and
+/// `replacement` may refer to its capture groups as `$1`, `${name}`, etc.
+#[derive(Debug)]
+pub struct UrlRewrite {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl FromStr for UrlRewrite {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> anyhow::Result<Self> {
+        let (pattern, replacement) = rule.split_once("=>").ok_or_else(|| {
+            anyhow!("rewrite rule {rule:?} isn't of the form <pattern>=><replacement>")
+        })?;
+
+        Ok(UrlRewrite {
+            pattern: Regex::new(pattern)
+                .map_err(|e| anyhow!("invalid pattern in rewrite rule {rule:?}: {e}"))?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+/// Applies the first of `rewrites` whose pattern matches `url`, if any.
+fn apply_url_rewrites(url: &Url, rewrites: &[UrlRewrite]) -> anyhow::Result<Url> {
+    let Some(rewrite) = rewrites.iter().find(|r| r.pattern.is_match(url.as_str())) else {
+        return Ok(url.clone());
+    };
+
+    let rewritten = rewrite.pattern.replace(url.as_str(), &rewrite.replacement);
+
+    Url::parse(&rewritten).map_err(|e| {
+        anyhow!(
+            "rewriting {url} with {:?} gave an invalid URL {rewritten:?}: {e}",
+            rewrite.pattern.as_str()
+        )
     })
-    .map_err(|backoff_err| match backoff_err {
-        backoff::Error::Permanent(err)
-        | backoff::Error::Transient {
-            err,
-            retry_after: _,
-        } => err,
+}
+
+/// Returns `url` without its fragment, which isn't part of the resource it refers to: it's neither sent when fetching it,
+/// nor part of make-fetch-happen's cache key for it.
+pub fn without_fragment(url: &Url) -> Url {
+    let mut url = url.clone();
+
+    url.set_fragment(None);
+
+    url
+}
+
+/// Rewrites the host of `url` according to `rewrites`, a JSON mapping in the shape of (as read from the
+/// `FETCH_NPM_DEPS_REGISTRY_REWRITES` environment variable): `{ "registry.npmjs.org": "registry.example.com:8080", ... }`
+/// or, for registries served under a path prefix, `{ "registry.npmjs.org": "registry.example.com/api/v1/npm", ... }`
+fn rewrite_url(url: &Url, rewrites: &Map<String, Value>) -> Url {
+    url.host_str()
+        .and_then(|host| rewrites.get(host))
+        .and_then(Value::as_str)
+        .and_then(|registry| with_registry(url, registry))
+        .unwrap_or_else(|| url.clone())
+}
+
+/// Replaces the host and port of `url` with those of `registry`, which may also have a path prefix that the path of
+/// `url` is then nested under (e.g. `registry.example.com/api/v1/npm`).
+fn with_registry(url: &Url, registry: &str) -> Option<Url> {
+    let (authority, prefix) = registry.split_once('/').unwrap_or((registry, ""));
+    let mut new = with_authority(url, authority)?;
+    let prefix = prefix.trim_matches('/');
+
+    if !prefix.is_empty() {
+        new.set_path(&format!("/{prefix}/{}", url.path().trim_start_matches('/')));
+    }
+
+    Some(new)
+}
+
+/// Replaces the host and port of `url` with those in `authority`.
+fn with_authority(url: &Url, authority: &str) -> Option<Url> {
+    let authority = Url::parse(&format!("{}://{authority}", url.scheme())).ok()?;
+    let mut new = url.clone();
+
+    new.set_host(authority.host_str()).ok()?;
+    new.set_port(authority.port()).ok()?;
+
+    Some(new)
+}
+
+/// Fetches `url`, falling back to each of its mirrors in turn if that fails.
+///
+/// `mirrors` should be a JSON mapping in the shape of (as read from the `FETCH_NPM_DEPS_MIRRORS` environment variable):
+/// `{ "registry.example.com": "mirror.example.com", "other.example.com": ["a.example.com", "b.example.com:8080"], ... }`
+fn get_url_body_with_mirrors(
+    url: &Url,
+    mirrors: &Map<String, Value>,
+    backoff: impl Fn() -> ExponentialBackoff,
+) -> anyhow::Result<Vec<u8>> {
+    let mut result = get_url_body_with_backoff(url, backoff());
+
+    for mirror in mirror_urls(url, mirrors) {
+        match result {
+            Ok(_) => break,
+            Err(err) => {
+                warn!("failed to fetch {url} ({err}), falling back to {mirror}");
+
+                result = get_url_body_with_backoff(&mirror, backoff());
+            }
+        }
+    }
+
+    result
+}
+
+fn mirror_urls(url: &Url, mirrors: &Map<String, Value>) -> Vec<Url> {
+    let hosts = match url.host_str().and_then(|host| mirrors.get(host)) {
+        Some(Value::String(host)) => vec![host.as_str()],
+        Some(Value::Array(hosts)) => hosts.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    hosts
+        .into_iter()
+        .filter_map(|host| with_authority(url, host))
+        .collect()
+}
+
+fn get_url_body_with_backoff(url: &Url, backoff: ExponentialBackoff) -> anyhow::Result<Vec<u8>> {
+    get_url_body_with_config(url, config::get(), CIRCUIT_BREAKER.get(), backoff)
+}
+
+fn get_url_body_with_config(
+    url: &Url,
+    config: &config::Config,
+    breaker: Option<&HostCircuitBreaker>,
+    backoff: ExponentialBackoff,
+) -> anyhow::Result<Vec<u8>> {
+    if let Some(allowed_hosts) = &config.allowed_hosts {
+        if !url
+            .host_str()
+            .is_some_and(|host| allowed_hosts.iter().any(|h| h == host))
+        {
+            bail!("refusing to fetch {url}, as its host isn't in the allowed hosts");
+        }
+    }
+
+    let limits = RetryLimits {
+        connect: config.connect_retries,
+        request: config.retries,
+    };
+
+    let budget = RETRY_BUDGET
+        .get_or_init(|| config.total_retries.map(RetryBudget::new))
+        .as_ref();
+
+    let slots = HOST_SLOTS
+        .get_or_init(|| config.per_host_jobs.map(HostSlots::new))
+        .as_ref();
+
+    retry_with_limits(backoff, limits, budget, || {
+        if let Some(breaker) = breaker {
+            breaker.check(url).map_err(Failure::Permanent)?;
+        }
+
+        // Error statuses count against the host as well, as a host that's down often still answers with a 503.
+        let result = read_response(url, config, slots).and_then(|response| {
+            if response.status.is_client_error() || response.status.is_server_error() {
+                Err(status_failure(url, response.status, config))
+            } else {
+                Ok(response)
+            }
+        });
+
+        if let Some(breaker) = breaker {
+            breaker.record(url, result.is_ok());
+        }
+
+        let ReadResponse {
+            encoding,
+            content_type,
+            body,
+            ..
+        } = result?;
+
+        // Unlike checking for the gzip magic bytes, this also catches landing pages of hosts that serve uncompressed
+        // archives.
+        if content_type
+            .as_deref()
+            .and_then(|t| t.split(';').next())
+            .is_some_and(|t| t.trim() == "text/html")
+        {
+            return Err(Failure::Permanent(anyhow!(
+                "{url} returned an HTML page instead of a tarball. Its `resolved` URL in the lockfile may point to a web page rather than a package."
+            )));
+        }
+
+        decode_body(encoding.as_deref(), body).map_err(Failure::Permanent)
+    })
+}
+
+/// A response read by [`read_response`], with the headers needed to check and decode its body.
+struct ReadResponse {
+    status: StatusCode,
+    encoding: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Reads the body of `url`, as ranges if [`ranged_length`] allows.
+fn read_response(
+    url: &Url,
+    config: &config::Config,
+    slots: Option<&HostSlots>,
+) -> Result<ReadResponse, Failure> {
+    if let Some((len, content_type)) = ranged_length(url, config, slots)? {
+        return Ok(ReadResponse {
+            status: StatusCode::OK,
+            encoding: None,
+            content_type,
+            body: read_ranges(url, config, len, RANGE_PARTS, slots)?,
+        });
+    }
+
+    // Held until the body has been read, and released before backing off.
+    let _slot = slots.map(|slots| slots.acquire(url));
+    let mut response = get_url_with(url, config)?;
+    let content_length = header(&response, "Content-Length").and_then(|len| len.parse().ok());
+    let timeout = config
+        .timeout()
+        .zip(config.timeout_rate)
+        .map(|(base, rate)| scaled_timeout(base, rate, content_length));
+
+    let body = read_body(
+        url,
+        response.body_mut(),
+        content_length,
+        config.max_size,
+        timeout,
+    )?;
+
+    Ok(ReadResponse {
+        status: response.status(),
+        encoding: header(&response, "Content-Encoding"),
+        content_type: header(&response, "Content-Type"),
+        body,
     })
 }
+
+/// Returns the lowercased value of the `name` header of `response`, if it has one.
+fn header(response: &Response<Body>, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_ascii_lowercase)
+}
+
+/// Returns the length and `Content-Type` of `url` if it's over `FETCH_NPM_DEPS_RANGE_THRESHOLD` and its server accepts
+/// ranges, as found with a HEAD request (taking a slot from `slots` while it's sent), so that nothing is downloaded twice.
+fn ranged_length(
+    url: &Url,
+    config: &config::Config,
+    slots: Option<&HostSlots>,
+) -> Result<Option<(u64, Option<String>)>, Failure> {
+    let Some(threshold) = config.range_threshold else {
+        return Ok(None);
+    };
+
+    let response = {
+        let _slot = slots.map(|slots| slots.acquire(url));
+
+        head_url_with(url, config)?
+    };
+
+    Ok(header(&response, "Content-Length")
+        .and_then(|len| len.parse().ok())
+        .filter(|&len| {
+            response.status() == StatusCode::OK
+                && header(&response, "Content-Encoding").is_none()
+                && header(&response, "Accept-Ranges").as_deref() == Some("bytes")
+                && len >= threshold
+                && config.max_size.is_none_or(|max_size| len <= max_size)
+        })
+        .map(|len| (len, header(&response, "Content-Type"))))
+}
+
+/// Returns the failure for `url` responding with an error `status`, which is retried if `config.status_retries` says so,
+/// or otherwise if it's a 429 or 5xx (or a 404 from a caching proxy, see [`not_found`]).
+fn status_failure(url: &Url, status: StatusCode, config: &config::Config) -> Failure {
+    let retry = config
+        .status_retries
+        .get(status.as_str())
+        .and_then(Value::as_bool);
+
+    if retry.is_none() && status == StatusCode::NOT_FOUND {
+        return not_found(url, &config.caching_proxy_hosts);
+    }
+
+    let err = anyhow!("{url} returned HTTP {status}");
+
+    if retry.unwrap_or(status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()) {
+        Failure::Request(err)
+    } else {
+        Failure::Permanent(err)
+    }
+}
+
+/// Returns the failure for `url` being not found, which is only retried if it's from one of `caching_proxy_hosts`, as those
+/// may not have fetched it from their uplink yet.
+fn not_found(url: &Url, caching_proxy_hosts: &[String]) -> Failure {
+    if url
+        .host_str()
+        .is_some_and(|host| caching_proxy_hosts.iter().any(|h| h == host))
+    {
+        Failure::Request(anyhow!(
+            "{url} was not found (HTTP 404) on a caching proxy, which may not have fetched it from its uplink yet"
+        ))
+    } else {
+        Failure::Permanent(anyhow!(
+            "{url} was not found (HTTP 404). The package may have been unpublished, or the lockfile may be stale; try regenerating it."
+        ))
+    }
+}
+
+/// Reads a response body, failing without reading any of it if its `Content-Length` is over `max_size`, and
+/// failing if reading it takes longer than `timeout`.
+///
+/// Not every server sends a `Content-Length`, so the body is also cut off once it's read more than `max_size` bytes.
+fn read_body(
+    url: &Url,
+    body: impl Read,
+    content_length: Option<u64>,
+    max_size: Option<u64>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>, Failure> {
+    if let (Some(len), Some(max_size)) = (content_length, max_size) {
+        if len > max_size {
+            return Err(Failure::Permanent(anyhow!(
+                "{url} is {len} bytes, which is over the maximum size of {max_size} bytes"
+            )));
+        }
+    }
+
+    let mut buf = Vec::new();
+    let mut body = body.take(max_size.map_or(u64::MAX, |max_size| max_size + 1));
+    let read = match timeout {
+        Some(timeout) => read_until(&mut body, Instant::now() + timeout, &mut buf),
+        None => body.read_to_end(&mut buf).map(|_| true),
+    };
+
+    if !read.map_err(|e| Failure::from(isahc::Error::from(e)))? {
+        return Err(Failure::Request(anyhow!(
+            "timed out reading {url} after {}s",
+            timeout.unwrap_or_default().as_secs()
+        )));
+    }
+
+    match max_size {
+        Some(max_size) if buf.len() as u64 > max_size => Err(Failure::Permanent(anyhow!(
+            "{url} is over the maximum size of {max_size} bytes"
+        ))),
+        _ => Ok(buf),
+    }
+}
+
+/// How many ranges a download over `FETCH_NPM_DEPS_RANGE_THRESHOLD` is split into.
+const RANGE_PARTS: u64 = 4;
+
+/// Downloads the `len` bytes of `url` as `parts` ranges in parallel, reassembling them in order.
+///
+/// Each range takes its own slot from `slots`, and is held to a timeout scaled to its own size.
+fn read_ranges(
+    url: &Url,
+    config: &config::Config,
+    len: u64,
+    parts: u64,
+    slots: Option<&HostSlots>,
+) -> Result<Vec<u8>, Failure> {
+    let size = len.div_ceil(parts.max(1)).max(1);
+    let ranges = (0..len)
+        .step_by(usize::try_from(size).unwrap_or(usize::MAX))
+        .map(|start| (start, (start + size).min(len)))
+        .collect::<Vec<_>>();
+
+    let chunks = thread::scope(|s| {
+        let handles = ranges
+            .iter()
+            .map(|&(start, end)| {
+                s.spawn(move || -> Result<Vec<u8>, Failure> {
+                    let _slot = slots.map(|slots| slots.acquire(url));
+                    let mut response = send_with(url, config, |request| {
+                        request.header("Range", format!("bytes={start}-{}", end - 1))
+                    })?;
+
+                    if response.status() != StatusCode::PARTIAL_CONTENT {
+                        return Err(Failure::Request(anyhow!(
+                            "{url} didn't return bytes {start}-{} as requested, but HTTP {}",
+                            end - 1,
+                            response.status()
+                        )));
+                    }
+
+                    let timeout = config
+                        .timeout()
+                        .zip(config.timeout_rate)
+                        .map(|(base, rate)| scaled_timeout(base, rate, Some(end - start)));
+                    let chunk = read_body(url, response.body_mut(), None, None, timeout)?;
+
+                    if chunk.len() as u64 != end - start {
+                        return Err(Failure::Request(anyhow!(
+                            "{url} returned {} bytes for bytes {start}-{}",
+                            chunk.len(),
+                            end - 1
+                        )));
+                    }
+
+                    Ok(chunk)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("range download panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    Ok(chunks.concat())
+}
+
+/// Reads `body` to the end into `buf`, returning whether it was read before `deadline`.
+fn read_until(body: &mut impl Read, deadline: Instant, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut chunk = [0; 8192];
+
+    loop {
+        match body.read(&mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        if Instant::now() > deadline {
+            return Ok(false);
+        }
+    }
+}
+
+/// Returns how long reading a response body may take: `base`, plus the time it takes to transfer `content_length` bytes
+/// at `rate` bytes a second, so that large downloads aren't held to the same timeout as small ones.
+fn scaled_timeout(base: Duration, rate: u64, content_length: Option<u64>) -> Duration {
+    match content_length {
+        Some(len) if rate > 0 => base + Duration::from_secs(len / rate),
+        _ => base,
+    }
+}
+
+/// How many times each kind of transient failure may be retried, on top of the backoff's own limits.
+#[derive(Clone, Copy, Debug, Default)]
+struct RetryLimits {
+    connect: Option<usize>,
+    request: Option<usize>,
+}
+
+static RETRY_BUDGET: OnceLock<Option<RetryBudget>> = OnceLock::new();
+
+/// A number of retries shared by all requests in the run, so that a failing registry can't multiply per-request retries
+/// into an enormous total.
+#[derive(Debug)]
+struct RetryBudget(AtomicUsize);
+
+impl RetryBudget {
+    fn new(retries: usize) -> RetryBudget {
+        RetryBudget(AtomicUsize::new(retries))
+    }
+
+    /// Takes one retry from the budget, returning whether there was one left.
+    fn take(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// How an attempt to fetch something failed.
+#[derive(Debug)]
+enum Failure {
+    /// Couldn't connect to the server, which is usually transient.
+    Connect(anyhow::Error),
+    /// The request failed after connecting, e.g. by timing out.
+    Request(anyhow::Error),
+    /// Retrying won't help.
+    Permanent(anyhow::Error),
+}
+
+impl From<isahc::Error> for Failure {
+    fn from(err: isahc::Error) -> Failure {
+        match err.kind() {
+            ErrorKind::ConnectionFailed | ErrorKind::NameResolution => Failure::Connect(err.into()),
+            _ if err.is_network() || err.is_timeout() => Failure::Request(err.into()),
+            _ => Failure::Permanent(err.into()),
+        }
+    }
+}
+
+/// Runs `attempt` until it succeeds, retrying transient failures according to `backoff` and `limits`, as long as `budget`
+/// has retries left.
+fn retry_with_limits<T>(
+    backoff: ExponentialBackoff,
+    limits: RetryLimits,
+    budget: Option<&RetryBudget>,
+    mut attempt: impl FnMut() -> Result<T, Failure>,
+) -> anyhow::Result<T> {
+    let (mut connect_failures, mut request_failures) = (0, 0);
+
+    let transient = |err, failures: &mut usize, limit: Option<usize>| {
+        *failures += 1;
+
+        if limit.is_some_and(|limit| *failures > limit) || budget.is_some_and(|b| !b.take()) {
+            backoff::Error::permanent(err)
+        } else {
+            backoff::Error::transient(err)
+        }
+    };
+
+    retry(backoff, || match attempt() {
+        Ok(value) => Ok(value),
+        Err(Failure::Connect(err)) => Err(transient(err, &mut connect_failures, limits.connect)),
+        Err(Failure::Request(err)) => Err(transient(err, &mut request_failures, limits.request)),
+        Err(Failure::Permanent(err)) => Err(backoff::Error::permanent(err)),
+    })
+    .map_err(|backoff_err| match backoff_err {
+        backoff::Error::Permanent(err)
+        | backoff::Error::Transient {
+            err,
+            retry_after: _,
+        } => err,
+    })
+}
+
+static HOST_SLOTS: OnceLock<Option<HostSlots>> = OnceLock::new();
+
+/// Limits how many requests may be in flight to any one host at once. Overrides `FETCH_NPM_DEPS_PER_HOST_JOBS`.
+pub fn set_per_host_jobs(jobs: usize) {
+    let _ = HOST_SLOTS.set(Some(HostSlots::new(jobs)));
+}
+
+/// A counting semaphore per host (and port), so that the fetch workers can't all pile onto one registry.
+struct HostSlots {
+    jobs: usize,
+    in_use: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+impl HostSlots {
+    fn new(jobs: usize) -> HostSlots {
+        HostSlots {
+            jobs: jobs.max(1),
+            in_use: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes a slot for the host of `url`, waiting for one to be released if they're all in use.
+    fn acquire(&self, url: &Url) -> HostSlot<'_> {
+        let host = url.authority().to_string();
+        let mut in_use = self
+            .released
+            .wait_while(self.in_use.lock().unwrap(), |in_use| {
+                in_use.get(&host).is_some_and(|&n| n >= self.jobs)
+            })
+            .unwrap();
+
+        *in_use.entry(host.clone()).or_default() += 1;
+
+        HostSlot { slots: self, host }
+    }
+}
+
+/// A slot taken from [`HostSlots`], which is released when dropped.
+struct HostSlot<'a> {
+    slots: &'a HostSlots,
+    host: String,
+}
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        let mut in_use = self.slots.in_use.lock().unwrap();
+
+        if let Some(n) = in_use.get_mut(&self.host) {
+            *n -= 1;
+
+            if *n == 0 {
+                in_use.remove(&self.host);
+            }
+        }
+
+        // Waiters for different hosts share the condition variable, so all of them need waking.
+        self.slots.released.notify_all();
+    }
+}
+
+static CIRCUIT_BREAKER: OnceLock<HostCircuitBreaker> = OnceLock::new();
+
+/// Stops sending requests to a host after `max_failures` consecutive failed requests to it.
+pub fn set_max_failures_per_host(max_failures: usize) {
+    let _ = CIRCUIT_BREAKER.set(HostCircuitBreaker::new(max_failures));
+}
+
+/// Tracks consecutive request failures per host, so that once a host is clearly down, the remaining requests to it fail
+/// immediately instead of each being retried.
+struct HostCircuitBreaker {
+    max_failures: usize,
+    failures: Mutex<HashMap<String, usize>>,
+}
+
+impl HostCircuitBreaker {
+    fn new(max_failures: usize) -> HostCircuitBreaker {
+        HostCircuitBreaker {
+            max_failures,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, url: &Url) -> anyhow::Result<()> {
+        let host = url.host_str().unwrap_or_default();
+        let failures = self.failures.lock().unwrap().get(host).copied();
+
+        match failures {
+            Some(failures) if failures >= self.max_failures => Err(anyhow!(
+                "host circuit open: not fetching {url}, as {host} failed {failures} times in a row"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn record(&self, url: &Url, success: bool) {
+        let host = url.host_str().unwrap_or_default().to_string();
+        let mut failures = self.failures.lock().unwrap();
+
+        if success {
+            failures.remove(&host);
+        } else {
+            *failures.entry(host).or_default() += 1;
+        }
+    }
+}
+
+/// Hosts serving archives that aren't reproducible byte-for-byte (they're generated on the fly, and may be regenerated at any
+/// time), so their integrity can't be meaningfully verified.
+const UNVERIFIED_HOSTS: &[&str] = &[
+    "codeload.github.com",
+    "bitbucket.org",
+    "gitlab.com",
+    "git.sr.ht",
+];
+
+/// Returns whether the integrity of data fetched from `url` should be verified.
+///
+/// The default set of hosts that are exempt from verification can be replaced with `unverified-hosts` in the config.
+pub fn should_verify_integrity(url: &Url, config: &config::Config) -> bool {
+    match &config.unverified_hosts {
+        Some(hosts) => should_verify_integrity_with(url, hosts.iter().map(String::as_str)),
+        None => should_verify_integrity_with(url, UNVERIFIED_HOSTS.iter().copied()),
+    }
+}
+
+fn should_verify_integrity_with<'a>(
+    url: &Url,
+    mut unverified_hosts: impl Iterator<Item = &'a str>,
+) -> bool {
+    !url.host_str()
+        .is_some_and(|host| unverified_hosts.any(|h| h == host))
+}
+
+/// Checks whether `data` matches the SRI hash `integrity`.
+pub fn integrity_matches(integrity: &str, data: &[u8]) -> anyhow::Result<bool> {
+    let (algo, hash) = integrity
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected SRI hash, got {integrity:?}"))?;
+
+    let expected = BASE64_STANDARD.decode(hash)?;
+
+    Ok(match algo {
+        "sha1" => Sha1::new().chain(data).finalize().as_slice() == expected,
+        "sha512" => Sha512::new().chain(data).finalize().as_slice() == expected,
+        _ => bail!("unknown hash algorithm {algo:?}"),
+    })
+}
+
+/// Returns whether the integrity of tarballs fetched from `url` covers their decompressed tar stream rather than their
+/// compressed bytes, per `decompressed-hash-hosts` in the config.
+///
+/// This is for registries that recompress tarballs with different gzip settings, which changes their compressed bytes but
+/// not the archive itself.
+pub fn should_hash_decompressed(url: &Url, config: &config::Config) -> bool {
+    !should_verify_integrity_with(
+        url,
+        config.decompressed_hash_hosts.iter().map(String::as_str),
+    )
+}
+
+/// Checks whether the decompressed contents of gzipped `data` match the SRI hash `integrity`, hashing `gzip`'s output as
+/// it's streamed rather than holding it in memory.
+pub fn decompressed_integrity_matches(integrity: &str, data: &[u8]) -> anyhow::Result<bool> {
+    let (algo, hash) = integrity
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected SRI hash, got {integrity:?}"))?;
+
+    let expected = BASE64_STANDARD.decode(hash)?;
+
+    let mut cmd = Command::new("gzip")
+        .args(["--decompress", "--stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = cmd.stdin.take().unwrap();
+    let mut stdout = cmd.stdout.take().unwrap();
+
+    let actual = thread::scope(|s| {
+        s.spawn(move || stdin.write_all(data));
+
+        match algo {
+            "sha1" => hash_reader::<Sha1>(&mut stdout),
+            "sha512" => hash_reader::<Sha512>(&mut stdout),
+            _ => Err(anyhow!("unknown hash algorithm {algo:?}")),
+        }
+    })?;
+
+    let output = cmd.wait_with_output()?;
+
+    if !output.status.success() {
+        bail!(
+            "failed to decompress: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(actual == expected)
+}
+
+fn hash_reader<D: Digest + Update>(reader: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut hasher = D::new();
+    let mut buf = [0; 8 * 1024];
+
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(hasher.finalize().to_vec()),
+            n => Update::update(&mut hasher, &buf[..n]),
+        }
+    }
+}
+
+/// Returns whether `data` starts with the gzip magic bytes.
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+/// Creates a temporary directory under `NIX_BUILD_TOP` (in a Nix build) or `TMPDIR`, which is removed when dropped (and
+/// thus also when bailing out with an error).
+pub fn tempdir() -> io::Result<TempDir> {
+    tempdir_in(&temp_root())
+}
+
+/// Returns the directory that [`tempdir`] creates temporary directories in.
+pub fn temp_root() -> PathBuf {
+    env::var_os("NIX_BUILD_TOP")
+        .filter(|top| !top.is_empty())
+        .map_or_else(env::temp_dir, PathBuf::from)
+}
+
+/// Like [`tempdir`], but creates the temporary directory under `root`.
+pub fn tempdir_in(root: &Path) -> io::Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix("fetch-npm-deps-")
+        .tempdir_in(root)
+}
+
+/// Decompresses gzipped `data` with `gzip`.
+pub fn gunzip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut cmd = Command::new("gzip")
+        .args(["--decompress", "--stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = cmd.stdin.take().unwrap();
+
+    // Feed gzip from another thread, as it may fill up its output pipe before it has read all of its input.
+    let output = thread::scope(|s| {
+        s.spawn(move || stdin.write_all(data));
+
+        cmd.wait_with_output()
+    })?;
+
+    if !output.status.success() {
+        bail!(
+            "failed to decompress: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+/// Checks that `data` is a well-formed gzipped tarball by having `tar` list it, which catches truncated downloads that still
+/// happen to start with the gzip magic bytes.
+pub fn validate_archive(data: &[u8]) -> anyhow::Result<()> {
+    let mut cmd = Command::new("tar")
+        .args(["--list", "--gzip"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // tar can give up before consuming all of its input, in which case its exit status tells us what went wrong.
+    let _ = cmd.stdin.take().unwrap().write_all(data);
+
+    let output = cmd.wait_with_output()?;
+
+    if !output.status.success() {
+        bail!(
+            "invalid archive: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_server {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+    use url::Url;
+
+    /// A minimal HTTP server for tests, which answers each connection with the next of a fixed list of responses.
+    pub struct Server {
+        pub url: Url,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Server {
+        pub fn new(responses: Vec<Vec<u8>>) -> Server {
+            let count = responses.len();
+            let mut responses = responses.into_iter();
+
+            Server::with_handler(count, move |_| responses.next().unwrap())
+        }
+
+        /// Like [`Server::new`], but answers each of `count` connections with what `handler` returns for its request's
+        /// head.
+        pub fn with_handler(
+            count: usize,
+            mut handler: impl FnMut(&str) -> Vec<u8> + Send + 'static,
+        ) -> Server {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let seen = Arc::clone(&requests);
+
+            thread::spawn(move || {
+                for _ in 0..count {
+                    let Ok((mut stream, _)) = listener.accept() else {
+                        return;
+                    };
+
+                    let mut reader = BufReader::new(&mut stream);
+                    let mut request = String::new();
+
+                    while reader.read_line(&mut request).is_ok_and(|n| n > 2) {}
+
+                    let response = handler(&request);
+
+                    seen.lock().unwrap().push(request);
+
+                    let _ = stream.write_all(&response);
+                }
+            });
+
+            Server { url, requests }
+        }
+
+        /// Returns the heads of the requests received so far.
+        pub fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    pub fn response(status: u16, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        write!(
+            out,
+            "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        )
+        .unwrap();
+
+        for (name, value) in headers {
+            write!(out, "{name}: {value}\r\n").unwrap();
+        }
+
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(body);
+
+        out
+    }
+
+    /// Returns a URL that nothing is listening on.
+    pub fn unreachable_url() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_url_rewrites, authorization, bearer_token, combine_ca_bundles, content_length,
+        decompressed_integrity_matches, get_url_body_with_backoff, get_url_body_with_config,
+        get_url_body_with_mirrors, get_url_with, github_api_url, host_override, host_requests,
+        integrity_matches, read_body, read_ranges, retry_with_limits, rewrite_url, s3_url,
+        scaled_timeout, send_traced, should_verify_integrity_with,
+        test_server::{response, unreachable_url, Server},
+        validate_archive, Failure, HostCircuitBreaker, HostSlots, RetryBudget, RetryLimits,
+        UrlRewrite,
+    };
+    use backoff::ExponentialBackoff;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use isahc::ResponseExt;
+    use rayon::prelude::*;
+    use serde_json::json;
+    use sha1::{Digest, Sha1};
+    use std::{
+        fs,
+        process::{Command, Stdio},
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+    use tempfile::tempdir;
+    use url::Url;
+
+    fn no_retries() -> ExponentialBackoff {
+        ExponentialBackoff {
+            max_elapsed_time: Some(Duration::ZERO),
+            ..ExponentialBackoff::default()
+        }
+    }
+
+    #[test]
+    fn mirror_fallback() {
+        let server = Server::new(vec![response(200, &[], b"tarball")]);
+        let url = unreachable_url().join("foo/-/foo-1.0.0.tgz").unwrap();
+        let mirrors = json!({
+            "127.0.0.1": [
+                unreachable_url().authority(),
+                server.url.authority(),
+            ]
+        });
+
+        assert_eq!(
+            get_url_body_with_mirrors(&url, mirrors.as_object().unwrap(), no_retries).unwrap(),
+            b"tarball"
+        );
+        assert!(server.requests()[0].starts_with("GET /foo/-/foo-1.0.0.tgz"));
+    }
+
+    #[test]
+    fn registry_rewrites() {
+        let rewrites = json!({
+            "registry.npmjs.org": "registry.example.com",
+            "old.example.com": "new.example.com:8080",
+            "prefixed.example.com": "artifacts.example.com/api/v1/npm/",
+            "root.example.com": "new.example.com/",
+        });
+        let rewrites = rewrites.as_object().unwrap();
+
+        for (input, expected) in [
+            (
+                "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz",
+                "https://registry.example.com/foo/-/foo-1.0.0.tgz",
+            ),
+            (
+                "https://old.example.com/foo.tgz",
+                "https://new.example.com:8080/foo.tgz",
+            ),
+            (
+                "https://other.example.com/foo.tgz",
+                "https://other.example.com/foo.tgz",
+            ),
+            (
+                "https://prefixed.example.com/@scope/foo/-/foo-1.0.0.tgz",
+                "https://artifacts.example.com/api/v1/npm/@scope/foo/-/foo-1.0.0.tgz",
+            ),
+            (
+                "https://root.example.com/foo/-/foo-1.0.0.tgz",
+                "https://new.example.com/foo/-/foo-1.0.0.tgz",
+            ),
+        ] {
+            assert_eq!(
+                rewrite_url(&Url::parse(input).unwrap(), rewrites).as_str(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let breaker = HostCircuitBreaker::new(2);
+        let down = Url::parse("https://down.example.com/foo.tgz").unwrap();
+        let up = Url::parse("https://up.example.com/foo.tgz").unwrap();
+
+        breaker.record(&down, false);
+        assert!(breaker.check(&down).is_ok());
+
+        breaker.record(&down, false);
+        breaker.record(&up, false);
+
+        let err = breaker.check(&down).unwrap_err().to_string();
+
+        assert!(err.starts_with("host circuit open"), "{err}");
+        assert!(breaker.check(&up).is_ok());
+
+        breaker.record(&up, true);
+        breaker.record(&up, false);
+        assert!(
+            breaker.check(&up).is_ok(),
+            "successes should reset the count"
+        );
+    }
+
+    #[test]
+    fn circuit_breaker_error_statuses() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(503, &[], b"Service Unavailable"); 4]);
+        let url = server.url.join("foo.tgz")?;
+        let breaker = HostCircuitBreaker::new(2);
+        let backoff = ExponentialBackoff {
+            initial_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            ..ExponentialBackoff::default()
+        };
+
+        let err = get_url_body_with_config(
+            &url,
+            &crate::config::Config::default(),
+            Some(&breaker),
+            backoff,
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.starts_with("host circuit open"), "{err}");
+        assert_eq!(server.requests().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_verification_policy() {
+        let registry = Url::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz").unwrap();
+        let forge = Url::parse("https://codeload.github.com/foo/bar/tar.gz/main").unwrap();
+
+        assert!(should_verify_integrity_with(
+            &registry,
+            ["codeload.github.com"].into_iter()
+        ));
+        assert!(!should_verify_integrity_with(
+            &forge,
+            ["codeload.github.com"].into_iter()
+        ));
+        assert!(should_verify_integrity_with(&forge, std::iter::empty()));
+    }
+
+    #[test]
+    fn integrity_checks() -> anyhow::Result<()> {
+        assert!(integrity_matches(
+            "sha1-8dLS+STphqyG/fezbJS83zK+7BU=",
+            b"foo\n"
+        )?);
+        assert!(!integrity_matches(
+            "sha1-8dLS+STphqyG/fezbJS83zK+7BU=",
+            b"bar\n"
+        )?);
+        assert!(integrity_matches("md5-foo", b"foo\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn archive_validation() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+
+        fs::create_dir(dir.path().join("package"))?;
+        fs::write(dir.path().join("package/package.json"), "{}".repeat(4096))?;
+
+        let tarball = Command::new("tar")
+            .args(["--create", "--gzip", "-C"])
+            .arg(dir.path())
+            .arg("package")
+            .output()?
+            .stdout;
+
+        assert!(validate_archive(&tarball).is_ok());
+        assert!(validate_archive(&tarball[..tarball.len() / 2]).is_err());
+        assert!(validate_archive(b"<!DOCTYPE html>").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_encoding() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("archive.tar");
+
+        fs::write(&path, b"uncompressed archive")?;
+
+        let gzipped = Command::new("gzip")
+            .args(["-n", "-c"])
+            .arg(&path)
+            .output()?
+            .stdout;
+
+        let server = Server::new(vec![
+            response(200, &[("Content-Encoding", "gzip")], &gzipped),
+            response(200, &[], &gzipped),
+        ]);
+
+        let archive = server.url.join("foo/bar/tar/main")?;
+        let body = get_url_body_with_backoff(&archive, no_retries())?;
+
+        assert_eq!(body, b"uncompressed archive");
+        assert!(integrity_matches(
+            &format!(
+                "sha1-{}",
+                BASE64_STANDARD.encode(Sha1::digest(b"uncompressed archive"))
+            ),
+            &body
+        )?);
+
+        let tarball = server.url.join("foo/-/foo-1.0.0.tgz")?;
+
+        assert_eq!(
+            get_url_body_with_backoff(&tarball, no_retries())?,
+            gzipped,
+            "bodies without a content encoding should be left as is"
+        );
+
+        let requests = server
+            .requests()
+            .into_iter()
+            .map(|r| r.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+
+        assert!(requests[0].contains("accept-encoding: gzip\r\n"));
+        assert!(requests[1].contains("accept-encoding: identity\r\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn github_token() {
+        let config = crate::config::Config {
+            github_token: Some(String::from("gh-token")),
+            tokens: json!({ "registry.example.com": "registry-token" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..crate::config::Config::default()
+        };
+
+        for (url, token) in [
+            (
+                "https://api.github.com/repos/foo/bar/tarball/main",
+                Some("gh-token"),
+            ),
+            (
+                "https://codeload.github.com/foo/bar/tar.gz/main",
+                Some("gh-token"),
+            ),
+            (
+                "https://registry.example.com/foo.tgz",
+                Some("registry-token"),
+            ),
+            ("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz", None),
+            ("https://github.com.example.com/foo.tgz", None),
+            ("https://gitlab.com/foo/bar/-/archive/main.tar.gz", None),
+        ] {
+            assert_eq!(
+                bearer_token(&Url::parse(url).unwrap(), &config),
+                token,
+                "{url}"
+            );
+        }
+
+        assert_eq!(
+            github_api_url(
+                &Url::parse("https://codeload.github.com/foo/bar/tar.gz/abc123").unwrap()
+            )
+            .map(String::from),
+            Some(String::from(
+                "https://api.github.com/repos/foo/bar/tarball/abc123"
+            ))
+        );
+        assert_eq!(
+            github_api_url(&Url::parse("https://bitbucket.org/foo/bar/get/abc123.tar.gz").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_limits() {
+        let instant_retries = || ExponentialBackoff {
+            initial_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+            max_elapsed_time: None,
+            ..ExponentialBackoff::default()
+        };
+
+        // Fails with each of `failures` in turn, then succeeds, returning how many attempts it took.
+        let run = |limits, failures: &[fn(anyhow::Error) -> Failure]| {
+            let mut attempts = 0;
+
+            retry_with_limits(instant_retries(), limits, None, || {
+                attempts += 1;
+
+                match failures.get(attempts - 1) {
+                    Some(failure) => Err(failure(anyhow::anyhow!("attempt {attempts} failed"))),
+                    None => Ok(attempts),
+                }
+            })
+        };
+
+        let connect: fn(_) -> _ = Failure::Connect;
+        let request: fn(_) -> _ = Failure::Request;
+        let permanent: fn(_) -> _ = Failure::Permanent;
+
+        let limits = RetryLimits {
+            connect: Some(3),
+            request: Some(1),
+        };
+
+        assert_eq!(run(limits, &[connect; 3]).unwrap(), 4);
+        assert_eq!(
+            run(limits, &[connect; 4]).unwrap_err().to_string(),
+            "attempt 4 failed"
+        );
+        assert_eq!(
+            run(limits, &[connect, request, connect, connect]).unwrap(),
+            5,
+            "connection and request failures should be counted separately"
+        );
+        assert_eq!(
+            run(limits, &[request; 2]).unwrap_err().to_string(),
+            "attempt 2 failed"
+        );
+        assert_eq!(
+            run(limits, &[permanent]).unwrap_err().to_string(),
+            "attempt 1 failed"
+        );
+        assert_eq!(
+            run(RetryLimits::default(), &[request; 10]).unwrap(),
+            11,
+            "without limits, only the backoff should limit retries"
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        let server = Server::new(vec![
+            response(404, &[], b"Not Found"),
+            response(404, &[], b"Not Found"),
+        ]);
+        let url = server.url.join("foo/-/foo-1.0.0.tgz").unwrap();
+        let backoff = ExponentialBackoff {
+            initial_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            ..ExponentialBackoff::default()
+        };
+
+        let err = get_url_body_with_backoff(&url, backoff)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.starts_with(&format!("{url} was not found (HTTP 404)")),
+            "{err}"
+        );
+        assert!(err.contains("the lockfile may be stale"), "{err}");
+        assert_eq!(server.requests().len(), 1, "404s shouldn't be retried");
+    }
+
+    #[test]
+    fn extra_ca_certs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let cert =
+            |name| format!("-----BEGIN CERTIFICATE-----\n{name}\n-----END CERTIFICATE-----\n");
+        let (base, extra, invalid) = (
+            dir.path().join("base.pem"),
+            dir.path().join("extra.pem"),
+            dir.path().join("invalid.pem"),
+        );
+        fs::write(&base, cert("base").trim_end())?;
+        fs::write(&extra, cert("extra"))?;
+        fs::write(&invalid, "not a certificate")?;
+
+        assert_eq!(
+            String::from_utf8(combine_ca_bundles(Some(&base), &extra)?)?,
+            cert("base") + &cert("extra")
+        );
+        assert_eq!(
+            String::from_utf8(combine_ca_bundles(None, &extra)?)?,
+            cert("extra")
+        );
+        assert!(combine_ca_bundles(Some(&base), &invalid).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn status_retries() -> anyhow::Result<()> {
+        let config = crate::config::Config {
+            status_retries: json!({ "520": true, "503": false })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..crate::config::Config::default()
+        };
+        let backoff = || ExponentialBackoff {
+            initial_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            ..ExponentialBackoff::default()
+        };
+
+        let server = Server::new(vec![
+            response(520, &[], b"Web Server Returned an Unknown Error"),
+            response(200, &[], b"tarball"),
+        ]);
+        let body =
+            get_url_body_with_config(&server.url.join("foo.tgz")?, &config, None, backoff())?;
+
+        assert_eq!(body, b"tarball");
+        assert_eq!(server.requests().len(), 2, "520 should be retried");
+
+        let server = Server::new(vec![
+            response(503, &[], b"Service Unavailable"),
+            response(200, &[], b"tarball"),
+        ]);
+        let url = server.url.join("foo.tgz")?;
+
+        let err = get_url_body_with_config(&url, &config, None, backoff()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!("{url} returned HTTP 503 Service Unavailable")
+        );
+        assert_eq!(server.requests().len(), 1, "503 shouldn't be retried");
+
+        Ok(())
+    }
+
+    #[test]
+    fn caching_proxy_not_found() -> anyhow::Result<()> {
+        let server = Server::new(vec![
+            response(404, &[], b"Not Found"),
+            response(200, &[], b"tarball"),
+        ]);
+        let url = server.url.join("foo/-/foo-1.0.0.tgz")?;
+        let config = crate::config::Config {
+            caching_proxy_hosts: vec![url.host_str().unwrap().to_string()],
+            ..crate::config::Config::default()
+        };
+        let backoff = ExponentialBackoff {
+            initial_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            ..ExponentialBackoff::default()
+        };
+        let body = get_url_body_with_config(&url, &config, None, backoff)?;
+
+        assert_eq!(body, b"tarball");
+        assert_eq!(
+            server.requests().len(),
+            2,
+            "404s from caching proxies should be retried"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn decompressed_hash() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("package.tar");
+
+        fs::write(&path, b"the same archive, compressed twice".repeat(64))?;
+
+        let gzip = |level: &str| -> anyhow::Result<Vec<u8>> {
+            Ok(Command::new("gzip")
+                .args(["-n", "-c", level])
+                .arg(&path)
+                .output()?
+                .stdout)
+        };
+
+        let fast = gzip("-1")?;
+        let best = gzip("-9")?;
+
+        assert_ne!(fast, best, "the two encodings should differ");
+
+        let integrity = format!(
+            "sha1-{}",
+            BASE64_STANDARD.encode(Sha1::digest(fs::read(&path)?))
+        );
+
+        assert!(decompressed_integrity_matches(&integrity, &fast)?);
+        assert!(decompressed_integrity_matches(&integrity, &best)?);
+        assert!(!integrity_matches(&integrity, &fast)?);
+        assert!(!decompressed_integrity_matches(
+            &format!("sha1-{}", BASE64_STANDARD.encode(Sha1::digest(&fast))),
+            &fast
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn host_request_counts() -> anyhow::Result<()> {
+        let c = Server::new(vec![response(200, &[], b"qux")]);
+        let redirect = c.url.join("qux/-/qux-1.0.0.tgz")?;
+        let a = Server::new(vec![
+            response(200, &[], b"foo"),
+            response(200, &[], b"bar"),
+            response(302, &[("Location", redirect.as_str())], b""),
+        ]);
+        let b = Server::new(vec![response(200, &[], b"baz")]);
+
+        for url in [
+            a.url.join("foo/-/foo-1.0.0.tgz")?,
+            b.url.join("baz/-/baz-1.0.0.tgz")?,
+            a.url.join("bar/-/bar-1.0.0.tgz")?,
+            a.url.join("qux/-/qux-1.0.0.tgz")?,
+        ] {
+            get_url_body_with_backoff(&url, no_retries())?;
+        }
+
+        // Other tests send requests concurrently, but each test server has its own port.
+        let counts = host_requests();
+
+        assert_eq!(counts.get(a.url.authority()), Some(&3));
+        assert_eq!(counts.get(b.url.authority()), Some(&1));
+        assert_eq!(
+            counts.get(c.url.authority()),
+            Some(&1),
+            "hosts that requests are redirected to should be listed too"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn head_content_length() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], &[0; 1234])]);
+
+        assert_eq!(
+            content_length(&server.url.join("foo/-/foo-1.0.0.tgz")?)?,
+            Some(1234)
+        );
+        assert!(server.requests()[0].starts_with("HEAD /foo/-/foo-1.0.0.tgz "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn html_response() -> anyhow::Result<()> {
+        let server = Server::new(vec![
+            response(
+                200,
+                &[("Content-Type", "Text/HTML; charset=utf-8")],
+                b"<!DOCTYPE html><html></html>",
+            ),
+            response(
+                200,
+                &[("Content-Type", "application/octet-stream")],
+                b"tarball",
+            ),
+        ]);
+        let url = server.url.join("foo/foo")?;
+
+        let err = get_url_body_with_backoff(&url, no_retries())
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.starts_with(&format!("{url} returned an HTML page instead of a tarball")),
+            "{err}"
+        );
+
+        let body = get_url_body_with_backoff(&url, no_retries())?;
+
+        assert_eq!(body, b"tarball");
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_retry_budget() {
+        let budget = RetryBudget::new(5);
+        let attempts = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let backoff = ExponentialBackoff {
+                        initial_interval: Duration::ZERO,
+                        max_interval: Duration::ZERO,
+                        max_elapsed_time: None,
+                        ..ExponentialBackoff::default()
+                    };
+
+                    retry_with_limits(backoff, RetryLimits::default(), Some(&budget), || {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+
+                        Err::<(), _>(Failure::Request(anyhow::anyhow!("failed")))
+                    })
+                    .unwrap_err();
+                });
+            }
+        });
+
+        // Each request is attempted once, and only 5 retries are shared between them.
+        assert_eq!(attempts.load(Ordering::SeqCst), 4 + 5);
+        assert!(!budget.take());
+    }
+
+    #[test]
+    fn netrc_credentials() {
+        let config = crate::config::Config {
+            tokens: json!({ "registry.example.com": "registry-token" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..crate::config::Config::default()
+        };
+        let netrc = crate::netrc::Netrc::parse(
+            "machine mirror.example.com login alice password s3cret
+machine registry.example.com login bob password hunter2",
+        );
+
+        for (url, expected) in [
+            (
+                "https://mirror.example.com/foo/-/foo-1.0.0.tgz",
+                Some(String::from("Basic YWxpY2U6czNjcmV0")),
+            ),
+            (
+                "https://registry.example.com/foo/-/foo-1.0.0.tgz",
+                Some(String::from("Bearer registry-token")),
+            ),
+            ("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz", None),
+        ] {
+            assert_eq!(
+                authorization(&Url::parse(url).unwrap(), &config, Some(&netrc)),
+                expected,
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "needs nghttpd and a libcurl with HTTP/2, as in the Nix build"]
+    fn http2_multiplexing() -> anyhow::Result<()> {
+        /// Kills and reaps `nghttpd` when dropped, so that a failing test doesn't leave it running.
+        struct Nghttpd(std::process::Child);
+
+        impl Drop for Nghttpd {
+            fn drop(&mut self) {
+                let _ = self.0.kill();
+                let _ = self.0.wait();
+            }
+        }
+
+        assert!(
+            isahc::is_http_version_supported(isahc::http::Version::HTTP_2),
+            "libcurl should support HTTP/2"
+        );
+
+        let dir = tempdir()?;
+
+        for i in 0..50 {
+            fs::write(dir.path().join(format!("{i}.tgz")), format!("tarball {i}"))?;
+        }
+
+        // The port is only free when it's picked, so another process may take it before `nghttpd` binds it, in which case
+        // `nghttpd` exits and is started again on another one.
+        let (_server, port) = (0..10)
+            .find_map(|_| -> Option<anyhow::Result<_>> {
+                let start = || -> anyhow::Result<_> {
+                    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+                        .local_addr()?
+                        .port();
+                    let mut server = Nghttpd(
+                        Command::new("nghttpd")
+                            .args(["--no-tls", "--htdocs"])
+                            .arg(dir.path())
+                            .arg(port.to_string())
+                            .stdout(Stdio::null())
+                            .spawn()?,
+                    );
+
+                    while server.0.try_wait()?.is_none() {
+                        if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                            return Ok(Some((server, port)));
+                        }
+
+                        thread::sleep(Duration::from_millis(20));
+                    }
+
+                    Ok(None)
+                };
+
+                start().transpose()
+            })
+            .ok_or_else(|| anyhow::anyhow!("nghttpd couldn't listen on any port"))??;
+
+        let base = Url::parse(&format!("http://127.0.0.1:{port}/"))?;
+        let config = crate::config::Config {
+            http2_hosts: vec![base.authority().to_string()],
+            ..crate::config::Config::default()
+        };
+
+        let results = (0..50)
+            .into_par_iter()
+            .map(|i| -> anyhow::Result<_> {
+                let mut response = get_url_with(&base.join(&format!("{i}.tgz"))?, &config)?;
+                let mut body = String::new();
+
+                std::io::Read::read_to_string(response.body_mut(), &mut body)?;
+
+                Ok((i, response.version(), response.local_addr(), body))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        for (i, version, _, body) in &results {
+            assert_eq!(*version, isahc::http::Version::HTTP_2);
+            assert_eq!(*body, format!("tarball {i}"));
+        }
+
+        assert_eq!(
+            results
+                .iter()
+                .map(|(_, _, local_addr, _)| *local_addr)
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            1,
+            "the requests should be multiplexed over a single connection"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_size() -> anyhow::Result<()> {
+        let server = Server::new(vec![
+            response(200, &[], &[0; 1000]),
+            // Without a `Content-Length`, the body runs until the connection is closed.
+            [
+                &b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n"[..],
+                &[0; 100],
+            ]
+            .concat(),
+            response(200, &[], &[0; 100]),
+        ]);
+        let url = server.url.join("huge.tgz")?;
+        let read = |max_size| -> Result<Vec<u8>, Failure> {
+            let mut response = get_url_with(&url, crate::config::get())?;
+            let content_length = response
+                .headers()
+                .get("Content-Length")
+                .and_then(|len| len.to_str().ok()?.parse().ok());
+
+            read_body(&url, response.body_mut(), content_length, max_size, None)
+        };
+
+        assert!(matches!(
+            read(Some(10)),
+            Err(Failure::Permanent(err))
+                if err.to_string() == format!("{url} is 1000 bytes, which is over the maximum size of 10 bytes")
+        ));
+        assert!(matches!(
+            read(Some(10)),
+            Err(Failure::Permanent(err))
+                if err.to_string() == format!("{url} is over the maximum size of 10 bytes")
+        ));
+        assert!(matches!(read(Some(100)), Ok(body) if body.len() == 100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn per_host_jobs() -> anyhow::Result<()> {
+        let slots = HostSlots::new(2);
+        let (a, b) = (
+            Url::parse("https://a.example.com/foo.tgz")?,
+            Url::parse("https://b.example.com/foo.tgz")?,
+        );
+        let (in_use, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+
+        thread::scope(|s| {
+            for _ in 0..16 {
+                let (slots, a, in_use, peak) = (&slots, &a, &in_use, &peak);
+
+                s.spawn(move || {
+                    let _slot = slots.acquire(a);
+
+                    peak.fetch_max(in_use.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    in_use.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+
+            // Other hosts aren't held up by a busy one.
+            let _slots = (slots.acquire(&b), slots.acquire(&b));
+        });
+
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert!(slots.in_use.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn s3_urls() -> anyhow::Result<()> {
+        let url = Url::parse("s3://internal-packages/tarballs/@scope/foo-1.0.0.tgz")?;
+
+        assert_eq!(
+            s3_url(&url, "https://{bucket}.s3.eu-west-1.amazonaws.com/{key}")?.as_str(),
+            "https://internal-packages.s3.eu-west-1.amazonaws.com/tarballs/@scope/foo-1.0.0.tgz"
+        );
+        assert_eq!(
+            s3_url(&url, "https://minio.example.com:9000/{bucket}/{key}")?.as_str(),
+            "https://minio.example.com:9000/internal-packages/tarballs/@scope/foo-1.0.0.tgz"
+        );
+        assert!(s3_url(
+            &Url::parse("s3://internal-packages/")?,
+            "https://{bucket}/{key}"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_scaled_timeout() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], &[0; 3000])]);
+        let url = server.url.join("big.tgz")?;
+        let mut response = get_url_with(&url, crate::config::get())?;
+        let content_length = response
+            .headers()
+            .get("Content-Length")
+            .and_then(|len| len.to_str().ok()?.parse().ok());
+        let base = Duration::from_secs(10);
+
+        assert_eq!(
+            scaled_timeout(base, 1000, content_length),
+            Duration::from_secs(13)
+        );
+        assert_eq!(
+            scaled_timeout(base, 100, content_length),
+            Duration::from_secs(40)
+        );
+        assert_eq!(scaled_timeout(base, 1000, None), base);
+
+        assert!(matches!(
+            read_body(
+                &url,
+                response.body_mut(),
+                content_length,
+                None,
+                Some(Duration::ZERO),
+            ),
+            Err(Failure::Request(err)) if err.to_string() == format!("timed out reading {url} after 0s")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_downloads() -> anyhow::Result<()> {
+        let tarball = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let body = tarball.clone();
+        let server = Server::with_handler(10, move |request| {
+            let range = request.lines().find_map(|line| {
+                line.to_ascii_lowercase()
+                    .strip_prefix("range: bytes=")
+                    .map(String::from)
+            });
+
+            match range {
+                Some(range) => {
+                    let (start, end) = range.trim().split_once('-').unwrap();
+                    let (start, end) = (
+                        start.parse::<usize>().unwrap(),
+                        end.parse::<usize>().unwrap(),
+                    );
+
+                    response(
+                        206,
+                        &[(
+                            "Content-Range",
+                            &format!("bytes {start}-{end}/{}", body.len()),
+                        )],
+                        &body[start..=end],
+                    )
+                }
+                None => response(200, &[("Accept-Ranges", "bytes")], &body),
+            }
+        });
+        let url = server.url.join("big.tgz")?;
+        let config = crate::config::Config {
+            range_threshold: Some(1000),
+            ..crate::config::Config::default()
+        };
+        // A single slot, which each range has to take in turn.
+        let slots = HostSlots::new(1);
+
+        let single = get_url_body_with_backoff(&url, no_retries())?;
+        let ranged = read_ranges(&url, &config, tarball.len() as u64, 4, Some(&slots))
+            .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+        let probed = get_url_body_with_config(&url, &config, None, no_retries())?;
+
+        assert_eq!(single, tarball);
+        assert_eq!(ranged, single);
+        assert_eq!(probed, single);
+        assert!(slots.in_use.lock().unwrap().is_empty());
+
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 10);
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|r| r.to_ascii_lowercase().contains("range: bytes="))
+                .count(),
+            8
+        );
+        assert!(
+            requests[5].starts_with("HEAD "),
+            "the length should be probed without downloading the body"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn host_overrides() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(200, &[], b"ok")]);
+        let port = server.url.port().unwrap();
+        let config = crate::config::Config {
+            host_overrides: json!({ format!("127.0.0.1:{port}"): "mirror.internal" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..crate::config::Config::default()
+        };
+
+        let response = get_url_with(&server.url.join("foo.tgz")?, &config)?;
+
+        assert_eq!(response.status(), 200);
+        assert!(server.requests()[0]
+            .to_ascii_lowercase()
+            .contains(&format!("\r\nhost: mirror.internal:{port}\r\n")));
+        assert!(
+            host_override(
+                &Url::parse("https://registry.npmjs.org/foo")?,
+                &config.host_overrides
+            )
+            .is_none(),
+            "hostnames shouldn't be overridden"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn url_rewrites() -> anyhow::Result<()> {
+        let rewrites = [
+            r"^https://codeload\.github\.com/([^/]+)/([^/]+)/tar\.gz/(.+)$=>https://git.example.com/$1/$2/archive/$3.tar.gz",
+            "^https://registry.npmjs.org/=>https://npm.example.com/",
+        ]
+        .into_iter()
+        .map(str::parse)
+        .collect::<anyhow::Result<Vec<UrlRewrite>>>()?;
+
+        for (url, expected) in [
+            (
+                "https://codeload.github.com/foo/bar/tar.gz/abc123",
+                "https://git.example.com/foo/bar/archive/abc123.tar.gz",
+            ),
+            (
+                "https://registry.npmjs.org/lodash/-/lodash-4.17.21.tgz",
+                "https://npm.example.com/lodash/-/lodash-4.17.21.tgz",
+            ),
+            ("https://example.com/foo.tgz", "https://example.com/foo.tgz"),
+        ] {
+            assert_eq!(
+                apply_url_rewrites(&url.parse()?, &rewrites)?.as_str(),
+                expected
+            );
+        }
+
+        assert!("no-arrow".parse::<UrlRewrite>().is_err());
+        assert!("(=>x".parse::<UrlRewrite>().is_err());
+        assert!(apply_url_rewrites(
+            &"https://example.com/".parse()?,
+            &["https.*=>nope".parse()?]
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace_http() -> anyhow::Result<()> {
+        let server = Server::new(vec![response(
+            200,
+            &[("Set-Cookie", "session=secret")],
+            b"ok",
+        )]);
+        let config = crate::config::Config {
+            tokens: json!({ "127.0.0.1": "secret-token" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ..crate::config::Config::default()
+        };
+        let mut trace = Vec::new();
+
+        let mut url = server.url.join("foo.tgz?token=secret")?;
+
+        url.set_username("secret-user").unwrap();
+
+        let response = send_traced(&url, &config, |request| request, Some(&mut trace))?;
+        let trace = String::from_utf8(trace)?;
+
+        assert_eq!(response.status(), 200);
+        assert!(
+            trace.starts_with(&format!(
+                "> GET http://<redacted>@{}/foo.tgz?<redacted>\n",
+                server.url.authority()
+            )),
+            "{trace}"
+        );
+        assert!(trace.contains("> authorization: <redacted>\n"), "{trace}");
+        assert!(trace.contains("< HTTP/1.1 200 OK\n"), "{trace}");
+        assert!(trace.contains("< set-cookie: <redacted>\n"), "{trace}");
+        assert!(trace.contains("< content-length: 2\n"), "{trace}");
+        assert!(!trace.contains("secret"), "{trace}");
+        assert!(
+            server.requests()[0].contains("Bearer secret-token"),
+            "only the trace should be redacted"
+        );
+
+        Ok(())
+    }
+}